@@ -1,50 +1,314 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::chunk;
 use crate::db;
+use crate::hash::Hasher;
+use crate::ignores;
+use crate::models::file::{metadata, FileMeta};
 use crate::models::staged_file;
+use crate::models::stat_cache;
 use crate::models::status::Status;
-use crate::store;
+
+// A file being added, and its hash if the stat cache already had it
+struct Pending {
+    path: PathBuf,
+    meta: FileMeta,
+    file_hash: Option<String>,
+}
+
+/* Hashes every pending file that has no hash yet, spread across jobs
+ * threads. Results are handed back through on_hashed in the order they
+ * finish, on the calling thread, so it can keep using the connection.
+ */
+fn hash_all(
+    hasher: &Hasher,
+    root_path: &Path,
+    pending: &[Pending],
+    jobs: usize,
+    mut on_hashed: impl FnMut(&Pending, String) -> Result<()>,
+) -> Result<()> {
+    let to_hash: Vec<&Pending> =
+        pending.iter().filter(|p| p.file_hash.is_none()).collect();
+    let next = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..jobs.clamp(1, to_hash.len().max(1)) {
+            let sender = sender.clone();
+            let (to_hash, next) = (&to_hash, &next);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(p) = to_hash.get(i) else {
+                    return;
+                };
+                let result = hasher.hash_file(&root_path.join(&p.path));
+                if sender.send((i, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+
+        for (i, result) in receiver {
+            if let Err(e) = result.and_then(|hash| on_hashed(to_hash[i], hash))
+            {
+                // Stop the workers picking up anything new
+                next.store(to_hash.len(), Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    })
+}
 
 pub fn add(
     connection: &Connection,
     root_path: &Path,
     rel_path: &Path,
+    jobs: usize,
 ) -> Result<()> {
+    if let Some(rule) = ignores::check(root_path, rel_path)? {
+        if !rule.negated {
+            return Err(anyhow!(
+                "{} is ignored by {}, see `sssync check-ignore`",
+                rel_path.display(),
+                rule.pattern
+            ));
+        }
+    }
+
     let status = Status::new(connection, root_path)?;
+    let hasher = Hasher::for_repository(connection)?;
 
-    for ua in status.unstaged_additions {
-        if ua.starts_with(rel_path) {
-            let full_file_path = root_path.join(&ua);
-            println!("staging addition: {}", full_file_path.display());
+    let racy_after = stat_cache::racy_after()?;
 
-            let staged_file =
-                staged_file::StagedFile::new(&full_file_path, &ua)?;
+    /* New files are staged as additions and files tracked at HEAD as
+     * modifications, along with anything staged that changed again since.
+     */
+    let mut pending = vec![];
+    let changed = status
+        .unstaged_additions
+        .iter()
+        .chain(&status.unstaged_modifications)
+        .chain(&status.staged_but_changed);
+    for path in changed {
+        if path.starts_with(rel_path) {
+            let meta = metadata(&root_path.join(path))?;
+            let file_hash = stat_cache::get(connection, path, &meta)?;
+            pending.push(Pending {
+                path: path.clone(),
+                meta,
+                file_hash,
+            });
+        }
+    }
 
-            store::insert_from(
-                root_path,
-                &staged_file.file_hash,
-                &full_file_path,
-            )?;
+    let total = pending.len();
+    let mut done = 0;
+    let mut stage = |p: &Pending, file_hash: String| -> Result<()> {
+        done += 1;
+        let full_file_path = root_path.join(&p.path);
+        let is_tracked = status.tracked_files.contains_key(&p.path);
+        println!(
+            "[{}/{}] staging {}: {}",
+            done,
+            total,
+            if is_tracked {
+                "modification"
+            } else {
+                "addition"
+            },
+            full_file_path.display()
+        );
 
-            db::staging::insert(
-                connection,
-                &staged_file::Change::Addition(staged_file),
-            )?;
+        stat_cache::remember(
+            connection, &p.path, &p.meta, &file_hash, racy_after,
+        )?;
+
+        let staged_file =
+            staged_file::StagedFile::new(&p.path, &p.meta, file_hash)?;
+        chunk::insert(
+            connection,
+            root_path,
+            &staged_file.file_hash,
+            &full_file_path,
+        )?;
+        db::staging::insert(
+            connection,
+            &if is_tracked {
+                staged_file::Change::Modification(staged_file)
+            } else {
+                staged_file::Change::Addition(staged_file)
+            },
+        )
+    };
+
+    for p in pending.iter() {
+        if let Some(file_hash) = &p.file_hash {
+            stage(p, file_hash.clone())?;
         }
     }
+    hash_all(&hasher, root_path, &pending, jobs, &mut stage)?;
 
-    for ua in status.unstaged_deletions {
+    for ua in &status.unstaged_deletions {
         if ua.starts_with(rel_path) {
-            let full_file_path = root_path.join(&ua);
+            let full_file_path = root_path.join(ua);
             println!("staging deletion: {}", full_file_path.display());
             db::staging::insert(
                 connection,
-                &staged_file::Change::Deletion(ua),
+                &staged_file::Change::Deletion(ua.clone()),
             )?;
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::{self, File};
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::models::staged_file::Change;
+    use crate::store;
+    use crate::testing::TempDir;
+    use crate::types::hash_algorithm::HashAlgorithm;
+
+    fn repo() -> Result<(TempDir, Connection)> {
+        let dir = TempDir::new()?;
+        store::init(dir.path())?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+        Ok((dir, connection))
+    }
+
+    fn staged_hash(connection: &Connection, path: &str) -> Result<String> {
+        for change in db::staging::get_all(connection)? {
+            if let Change::Addition(sf) | Change::Modification(sf) = change {
+                if sf.path == path {
+                    return Ok(sf.file_hash);
+                }
+            }
+        }
+        Err(anyhow!("{} isn't staged", path))
+    }
+
+    #[test]
+    fn test_racy_modification_is_rehashed() -> Result<()> {
+        let (dir, connection) = repo()?;
+        let root_path = dir.path();
+        let hasher = Hasher::for_repository(&connection)?;
+        let path = root_path.join("a.txt");
+
+        fs::write(&path, "before")?;
+        let modified = fs::metadata(&path)?.modified()?;
+        add(&connection, root_path, Path::new("a.txt"), 1)?;
+        // Written just now, so it isn't cached
+        assert!(db::stat_cache::get(&connection, "a.txt")?.is_none());
+
+        // Rewritten within the same tick, its stat looks unchanged
+        fs::write(&path, "after!")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)?;
+        add(&connection, root_path, Path::new("a.txt"), 1)?;
+
+        assert_eq!(
+            staged_hash(&connection, "a.txt")?,
+            hasher.hash_bytes(b"after!")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_changed_stat_invalidates_cache() -> Result<()> {
+        let (dir, connection) = repo()?;
+        let root_path = dir.path();
+        let path = root_path.join("a.txt");
+        let rel_path = Path::new("a.txt");
+
+        fs::write(&path, "contents")?;
+        let aged = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(aged)?;
+        let meta = metadata(&path)?;
+        stat_cache::remember(
+            &connection,
+            rel_path,
+            &meta,
+            "cached",
+            stat_cache::racy_after()?,
+        )?;
+        assert_eq!(
+            stat_cache::get(&connection, rel_path, &meta)?,
+            Some(String::from("cached"))
+        );
+
+        // A different modified time, same size
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(aged - Duration::from_secs(1))?;
+        let meta = metadata(&path)?;
+        assert_eq!(stat_cache::get(&connection, rel_path, &meta)?, None);
+
+        // A different size, same modified time
+        fs::write(&path, "longer contents")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(aged)?;
+        let meta = metadata(&path)?;
+        assert_eq!(stat_cache::get(&connection, rel_path, &meta)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_hashing_matches_sequential() -> Result<()> {
+        let (dir, connection) = repo()?;
+        let root_path = dir.path();
+        let hasher = Hasher::for_repository(&connection)?;
+
+        let mut pending = vec![];
+        for i in 0..32 {
+            let path = PathBuf::from(format!("{}.txt", i));
+            fs::write(root_path.join(&path), "x".repeat(i * 100))?;
+            pending.push(Pending {
+                meta: metadata(&root_path.join(&path))?,
+                path,
+                file_hash: None,
+            });
+        }
+        // Already hashed, it's left alone
+        pending[0].file_hash = Some(String::from("cached"));
+
+        let hash_with = |jobs: usize| -> Result<HashMap<PathBuf, String>> {
+            let mut hashes = HashMap::new();
+            hash_all(&hasher, root_path, &pending, jobs, |p, file_hash| {
+                hashes.insert(p.path.clone(), file_hash);
+                Ok(())
+            })?;
+            Ok(hashes)
+        };
+        let sequential = hash_with(1)?;
+        let parallel = hash_with(8)?;
+
+        assert_eq!(sequential.len(), 31);
+        assert!(!sequential.contains_key(Path::new("0.txt")));
+        for (path, file_hash) in &sequential {
+            assert_eq!(file_hash, &hasher.hash_file(&root_path.join(path))?);
+        }
+        assert_eq!(sequential, parallel);
+        Ok(())
+    }
+}
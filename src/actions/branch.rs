@@ -3,6 +3,7 @@ use std::path::Path;
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::config;
 use crate::db;
 use crate::models;
 use crate::models::meta::Meta;
@@ -96,8 +97,19 @@ pub fn switch(
     let diff = TreeDiff::new(&current_tree, &future_tree);
 
     println!("applying diff: {}", root_path.display());
-    store::apply_diff(root_path, &diff)?;
-    db::meta::update(connection, &Meta::new(&reference.name))
+    store::apply_diff(
+        connection,
+        root_path,
+        &diff,
+        config::checkout_strategy(connection)?,
+    )?;
+    db::meta::update(
+        connection,
+        &Meta {
+            head: reference.name,
+            ..meta
+        },
+    )
 }
 
 /* Lists all branches in the local repository
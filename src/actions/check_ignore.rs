@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::ignores;
+
+// Prints whether each path is ignored, and the rule that decides it
+pub fn check_ignore(root_path: &Path, rel_paths: &[&Path]) -> Result<()> {
+    for rel_path in rel_paths {
+        let path = rel_path.display();
+        match ignores::check(root_path, rel_path)? {
+            None => println!("{}: not ignored", path),
+            Some(rule) => match &rule.source {
+                None => println!("{}: ignored, it's in {}", path, rule.pattern),
+                Some(source) => println!(
+                    "{}: {} by {} in {}",
+                    path,
+                    if rule.negated {
+                        "not ignored"
+                    } else {
+                        "ignored"
+                    },
+                    rule.pattern,
+                    source.display()
+                ),
+            },
+        }
+    }
+    Ok(())
+}
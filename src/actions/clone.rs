@@ -4,24 +4,33 @@ use std::path::Path;
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::backend;
+use crate::config;
 use crate::db;
-use crate::models;
-use crate::remote::{fetch_remote_db, fetch_remote_objects, RemoteInfo};
-use crate::s3::make_client;
+use crate::encryption;
+use crate::models::migration::MigrationAction;
+use crate::models::remote::{Remote, S3Settings};
+use crate::remote::{checkout, fetch_remote_objects, RemoteInfo};
 use crate::store;
+use crate::store::FORMAT_VERSION;
 
-pub async fn clone(url_str: &str, destination: &Path) -> Result<()> {
-    let remote_info = RemoteInfo::from_url(&url_str)?;
+pub async fn clone(
+    url_str: &str,
+    destination: &Path,
+    s3: &S3Settings,
+    encryption_key: Option<String>,
+    jobs: usize,
+) -> Result<()> {
+    let remote_info = RemoteInfo::from_url(url_str)?;
 
     if destination.exists() {
         return Err(anyhow!(
             "desintation {} already exists",
             destination.display()
-        )
-        .into());
+        ));
     }
 
-    let Some(root_path) = store::get_root_path(destination) else {
+    if store::get_root_path(destination).is_some() {
         return Err(anyhow!(
             "desintation {} is already sssync'd",
             destination.display()
@@ -29,28 +38,57 @@ pub async fn clone(url_str: &str, destination: &Path) -> Result<()> {
     };
 
     println!("creating: {}", destination.display());
-    fs::create_dir(&destination)?;
+    fs::create_dir(destination)?;
     let local_path = fs::canonicalize(destination)?;
     let local_db_path = store::db_path(&local_path);
 
     println!("initializing sssync in: {}", local_path.display());
     store::init(&local_path)?;
     let remote_name = "origin";
+
+    let mut remote = Remote::new(remote_name, remote_info.kind, url_str)?;
+    remote.s3 = s3.clone();
+    remote.encryption_key = encryption_key;
+    let backend = backend::for_remote(&remote).await?;
+
+    println!("Fetching remote db");
+    backend.get_database(&local_db_path).await?;
+    if remote.encryption_key.is_none()
+        && encryption::is_encrypted(&local_db_path)?
+    {
+        return Err(anyhow!(
+            "the remote is encrypted, clone it with --encryption-key"
+        ));
+    }
+
     let connection = Connection::open(&local_db_path)?;
-    let client = make_client().await;
+    db::upgrade(&connection)?;
+    store::check_format(&connection)?;
+    // The store is new, every object will be written in the current layout
+    // whatever the remote uses.
+    db::meta::set_format_version(&connection, FORMAT_VERSION)?;
+
+    // The remote database is a copy of the database of whoever last pushed,
+    // drop the state that only made sense in their repository.
+    db::delete_local_state(&connection)?;
+    db::remote::insert(&connection, &remote)?;
 
-    db::remote::insert(
+    let files = fetch_remote_objects(
+        &connection,
+        backend.as_ref(),
         &connection,
-        &models::remote::Remote {
-            name: remote_name.to_string(),
-            kind: remote_info.kind,
-            location: url_str.to_string(),
-        },
+        &local_path,
+        remote_name,
+        MigrationAction::Clone,
+        jobs,
+    )
+    .await?;
+    checkout(
+        &connection,
+        &local_path,
+        &files,
+        config::checkout_strategy(&connection)?,
     )?;
 
-    println!("Fetching remote db");
-    fetch_remote_db(&client, &remote_info, &destination).await?;
-    fetch_remote_objects(&connection, &root_path, remote_name).await?;
-
     Ok(())
 }
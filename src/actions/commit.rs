@@ -4,20 +4,26 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 
+use crate::chunk;
+use crate::config;
 use crate::db;
+use crate::hash::Hasher;
 use crate::models::commit::Commit;
 use crate::models::reference::Kind;
 use crate::models::staged_file::Change;
 use crate::models::status::{
-    hash_all, intermediate_to_tree_files, Hashable, IntermediateTree, Status,
+    intermediate_to_tree_files, IntermediateTree, Status,
 };
-use crate::store;
 
 pub fn commit(
     connection: &Connection,
     root_path: &Path,
     message: &str,
+    author: Option<&str>,
 ) -> Result<()> {
+    let committer = config::identity(connection)?;
+    let author = author.unwrap_or(&committer);
+
     let staged_files = db::staging::get_all(connection)?;
     if staged_files.is_empty() {
         return Err(anyhow!("Staging is empty: Nothing to commit"));
@@ -47,8 +53,9 @@ pub fn commit(
 
     for a in staged_changes {
         match a {
-            Change::Addition(sf) => {
-                store::insert_from(
+            Change::Addition(sf) | Change::Modification(sf) => {
+                chunk::insert(
+                    connection,
                     root_path,
                     &sf.file_hash,
                     &root_path.join(&sf.path),
@@ -67,16 +74,26 @@ pub fn commit(
     let tree_files: Vec<IntermediateTree> =
         new_tree.clone().into_values().collect();
 
-    let hashable_tree_files: Vec<Box<dyn Hashable>> = new_tree
-        .into_iter()
-        .map(|(_, t)| {
-            let i: Box<dyn Hashable> = Box::new(t);
-            i
+    let files: Vec<(String, String)> = tree_files
+        .iter()
+        .map(|t| match t {
+            IntermediateTree::Staged(sf) => {
+                (sf.path.clone(), sf.file_hash.clone())
+            }
+            IntermediateTree::Committed(tf) => {
+                (tf.path.clone(), tf.file_hash.clone())
+            }
         })
         .collect();
 
-    let hash = hash_all(&hashable_tree_files);
-    let commit = Commit::new(&hash, message, "", parent_hash)?;
+    let commit = Commit::create(
+        &Hasher::for_repository(connection)?,
+        &files,
+        message,
+        author,
+        &committer,
+        parent_hash,
+    )?;
 
     db::commit::insert(connection, &commit)?;
     db::reference::update(
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::config;
+use crate::db;
+
+/* connection is either the repository's database or, with global set, the
+ * global config, which only takes the keys marked global.
+ */
+fn validate_key(key: &str, global: bool) -> Result<()> {
+    config::validate_key(key)?;
+    if global {
+        config::validate_global_key(key)?;
+    }
+    Ok(())
+}
+
+pub fn get(connection: &Connection, key: &str, global: bool) -> Result<()> {
+    validate_key(key, global)?;
+    match db::config::get(connection, key)? {
+        Some(value) => println!("{}", value),
+        None => println!("{} is not set", key),
+    }
+    Ok(())
+}
+
+pub fn set(
+    connection: &Connection,
+    key: &str,
+    value: &str,
+    global: bool,
+) -> Result<()> {
+    validate_key(key, global)?;
+    config::validate(key, value)?;
+    db::config::set(connection, key, value)
+}
+
+pub fn unset(connection: &Connection, key: &str, global: bool) -> Result<()> {
+    validate_key(key, global)?;
+    db::config::delete(connection, key)
+}
+
+// List every known setting along with its value if it has been set
+pub fn list(connection: &Connection, global: bool) -> Result<()> {
+    for key in config::KEYS.iter().filter(|k| k.global || !global) {
+        match db::config::get(connection, key.name)? {
+            Some(value) => println!("{} = {}", key.name, value),
+            None => println!("{} (unset): {}", key.name, key.description),
+        }
+    }
+    Ok(())
+}
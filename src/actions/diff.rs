@@ -2,7 +2,7 @@ use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
 use crate::db;
-use crate::tree;
+use crate::tree::{self, TreeDiff};
 
 pub fn diff(connection: &Connection, hash: &str) -> Result<()> {
     let staged_files = db::staging::get_all(connection)?;
@@ -15,19 +15,31 @@ pub fn diff(connection: &Connection, hash: &str) -> Result<()> {
     let head = db::commit::get_by_ref_name(connection, &meta.head)?
         .ok_or(anyhow!("Head is bad - no matching ref name"))?;
 
-    let diff = tree::diff(&connection, &head.hash, hash)?;
+    let diff = tree::diff(connection, &head.hash, hash)?;
+    print_diff(&diff, "");
 
-    for f in diff.additions {
-        println!("Added: {}", f.path)
+    Ok(())
+}
+
+// Prints every file the diff touches, one to a line after indent
+pub fn print_diff(diff: &TreeDiff, indent: &str) {
+    for f in diff.new_files() {
+        println!("{}Added: {}", indent, f.path)
     }
 
-    for f in diff.changes {
-        println!("changed: {}", f.path)
+    for p in &diff.renames {
+        println!("{}Renamed: {} -> {}", indent, p.from.path, p.to.path)
     }
 
-    for f in diff.deletions {
-        println!("Removed: {}", f.path)
+    for p in &diff.copies {
+        println!("{}Copied: {} -> {}", indent, p.from.path, p.to.path)
     }
 
-    Ok(())
+    for f in &diff.changes {
+        println!("{}changed: {}", indent, f.path)
+    }
+
+    for f in diff.removed_files() {
+        println!("{}Removed: {}", indent, f.path)
+    }
 }
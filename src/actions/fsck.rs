@@ -0,0 +1,287 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+
+use crate::actions::gc;
+use crate::backend;
+use crate::db;
+use crate::hash::Hasher;
+use crate::migration;
+use crate::models::commit::Commit;
+use crate::models::migration::MigrationAction;
+use crate::models::reference::{self, Reference};
+use crate::models::transfer::TransferKind;
+use crate::store;
+
+/* Everything wrong with a repository that fsck knows how to look for */
+#[derive(Default)]
+pub struct Report {
+    // Objects reachable from a branch or the staging area that aren't in
+    // the store, along with one of the paths referencing them
+    pub missing: BTreeMap<String, String>,
+    // Objects whose contents no longer hash to their name
+    pub corrupt: Vec<String>,
+    // Objects in the store that gc would remove
+    pub orphaned: Vec<String>,
+    // Commits whose parent isn't in the database
+    pub dangling_parents: Vec<Commit>,
+    // References pointing at a commit that isn't in the database
+    pub dangling_refs: Vec<Reference>,
+}
+
+impl Report {
+    /* Orphaned objects take up space but don't break anything, everything
+     * else is a problem.
+     */
+    pub fn problem_count(&self) -> usize {
+        self.missing.len()
+            + self.corrupt.len()
+            + self.dangling_parents.len()
+            + self.dangling_refs.len()
+    }
+}
+
+pub fn check(connection: &Connection, root_path: &Path) -> Result<Report> {
+    let mut report = Report::default();
+
+    // Every object the database expects to find in the store, and the
+    // wider set of objects that are worth keeping around
+    let referenced = gc::referenced_objects(connection)?;
+    let live = gc::live_objects(connection, root_path)?;
+
+    let hasher = Hasher::for_repository(connection)?;
+    let objects = store::list_objects(root_path)?;
+    let stored: HashSet<&String> = objects.iter().collect();
+
+    for (hash, path) in &referenced {
+        if !stored.contains(hash) {
+            report.missing.insert(hash.clone(), path.clone());
+        }
+    }
+
+    for hash in &objects {
+        if !live.contains_key(hash) {
+            report.orphaned.push(hash.clone());
+        }
+        let path = store::find_object(root_path, hash)
+            .ok_or(anyhow!("object {} vanished from the store", hash))?;
+        let compression = store::object_compression(&path);
+        if &hasher.hash_reader(store::read_object_file(&path, compression)?)?
+            != hash
+        {
+            report.corrupt.push(hash.clone());
+        }
+    }
+
+    let commits = db::commit::get_all(connection)?;
+    let commit_hashes: HashSet<&String> =
+        commits.iter().map(|c| &c.hash).collect();
+
+    for commit in &commits {
+        if let Some(parent_hash) = &commit.parent_hash {
+            if !commit_hashes.contains(parent_hash) {
+                report.dangling_parents.push(commit.clone());
+            }
+        }
+    }
+
+    for r in
+        db::reference::get_all_by_kind(connection, reference::Kind::Branch)?
+    {
+        if !commit_hashes.contains(&r.hash) {
+            report.dangling_refs.push(r);
+        }
+    }
+
+    Ok(report)
+}
+
+/* Check the repository for missing, corrupt or orphaned objects and for
+ * commits and references pointing nowhere.
+ *
+ * If a remote is given, corrupt objects are removed from the store and
+ * they, along with the missing ones, are downloaded again from the remote.
+ */
+pub async fn fsck(
+    connection: &Connection,
+    root_path: &Path,
+    refetch_from: Option<&str>,
+    jobs: usize,
+) -> Result<()> {
+    let report = check(connection, root_path)?;
+    print_report(&report);
+
+    if let Some(remote_name) = refetch_from {
+        // Corrupt objects nothing references aren't worth fetching again
+        let to_fetch: Vec<String> = report
+            .missing
+            .keys()
+            .chain(
+                report
+                    .corrupt
+                    .iter()
+                    .filter(|h| !report.orphaned.contains(h)),
+            )
+            .cloned()
+            .collect();
+
+        if !to_fetch.is_empty() {
+            refetch(connection, root_path, remote_name, &to_fetch, jobs)
+                .await?;
+            return fsck_after_refetch(connection, root_path);
+        }
+    }
+
+    match report.problem_count() {
+        0 => Ok(()),
+        n => Err(anyhow!("fsck found {} problems", n)),
+    }
+}
+
+async fn refetch(
+    connection: &Connection,
+    root_path: &Path,
+    remote_name: &str,
+    hashes: &[String],
+    jobs: usize,
+) -> Result<()> {
+    let remote = db::remote::get(connection, remote_name)?;
+    let backend = backend::for_remote(&remote).await?;
+
+    for hash in hashes {
+        if let Some(path) = store::find_object(root_path, hash) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    println!("Fetching {} objects from {}", hashes.len(), remote_name);
+    let migration = migration::create(
+        connection,
+        TransferKind::Download,
+        MigrationAction::Fsck,
+        remote_name,
+        hashes,
+        None,
+    )?;
+    migration::run(
+        connection,
+        backend.as_ref(),
+        root_path,
+        &migration,
+        false,
+        true,
+        jobs,
+    )
+    .await
+}
+
+fn fsck_after_refetch(connection: &Connection, root_path: &Path) -> Result<()> {
+    println!("Checking again");
+    let report = check(connection, root_path)?;
+    print_report(&report);
+
+    match report.problem_count() {
+        0 => Ok(()),
+        n => Err(anyhow!("fsck found {} problems after refetching", n)),
+    }
+}
+
+fn print_report(report: &Report) {
+    for (hash, path) in &report.missing {
+        println!("missing object: {} ({})", hash, path);
+    }
+    for hash in &report.corrupt {
+        println!("corrupt object: {}", hash);
+    }
+    for hash in &report.orphaned {
+        println!("orphaned object: {}", hash);
+    }
+    for commit in &report.dangling_parents {
+        println!(
+            "dangling parent: commit {} has missing parent {}",
+            commit.hash,
+            commit.parent_hash.as_deref().unwrap_or_default()
+        );
+    }
+    for r in &report.dangling_refs {
+        println!(
+            "dangling ref: {} points at missing commit {}",
+            r.name, r.hash
+        );
+    }
+
+    if report.problem_count() == 0 && report.orphaned.is_empty() {
+        println!("ok");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tree_file::TreeFile;
+    use crate::testing::TempDir;
+    use crate::types::compression::Compression;
+    use crate::types::hash_algorithm::HashAlgorithm;
+
+    #[test]
+    fn test_check_finds_problems() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path().to_path_buf();
+        store::init(&root_path)?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+
+        let commit = Commit::new("c1", "first", "", Some(String::from("c0")))?;
+        db::commit::insert(&connection, &commit)?;
+        db::tree::insert(
+            &connection,
+            &TreeFile {
+                path: String::from("a.txt"),
+                file_hash: String::from("missing"),
+                size_bytes: 1,
+                commit_hash: commit.hash.clone(),
+            },
+        )?;
+        db::reference::insert(
+            &connection,
+            "main",
+            reference::Kind::Branch,
+            &commit.hash,
+        )?;
+        db::reference::insert(
+            &connection,
+            "old",
+            reference::Kind::Branch,
+            "nowhere",
+        )?;
+
+        let orphan_path = root_path.join("orphan.txt");
+        fs::write(&orphan_path, "orphan")?;
+        let orphan_hash = Hasher::default().hash_file(&orphan_path)?;
+        store::insert_from(
+            &root_path,
+            &orphan_hash,
+            &orphan_path,
+            Compression::None,
+        )?;
+        let corrupt_path = store::object_path(&root_path, "corrupt");
+        fs::create_dir_all(corrupt_path.parent().unwrap())?;
+        fs::write(corrupt_path, "garbage")?;
+
+        let report = check(&connection, &root_path)?;
+        assert_eq!(report.missing.keys().collect::<Vec<_>>(), vec!["missing"]);
+        assert_eq!(report.corrupt, vec![String::from("corrupt")]);
+        let mut orphaned = report.orphaned.clone();
+        orphaned.sort();
+        let mut expected = vec![String::from("corrupt"), orphan_hash];
+        expected.sort();
+        assert_eq!(orphaned, expected);
+        assert_eq!(report.dangling_parents.len(), 1);
+        assert_eq!(report.dangling_refs.len(), 1);
+        assert_eq!(report.problem_count(), 4);
+        Ok(())
+    }
+}
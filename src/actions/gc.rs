@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::chunk;
+use crate::db;
+use crate::models::staged_file::Change;
+use crate::store;
+use crate::tree;
+
+pub const DEFAULT_GRACE_PERIOD_DAYS: u64 = 14;
+
+/* Objects the repository still needs, mapped to one of the paths they're
+ * found at. Those reachable from a local branch or the staging area are
+ * what the working directory can be built from.
+ */
+pub fn referenced_objects(
+    connection: &Connection,
+) -> Result<BTreeMap<String, String>> {
+    let mut objects = tree::reachable_objects(connection)?;
+    for change in db::staging::get_all(connection)? {
+        if let Change::Addition(staged) | Change::Modification(staged) = change
+        {
+            for hash in chunk::file_objects(connection, &staged.file_hash)? {
+                objects.entry(hash).or_insert(staged.path.clone());
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/* Along with the referenced objects, keep the ones reachable from the
+ * fetched copy of each remote's database. Fetch downloads those before
+ * they've been merged into a local branch. Objects a file in the working
+ * directory is symlinked to are kept as well.
+ */
+pub fn live_objects(
+    connection: &Connection,
+    root_path: &Path,
+) -> Result<BTreeMap<String, String>> {
+    let mut objects = referenced_objects(connection)?;
+    for remote in db::remote::get_all(connection)? {
+        let remote_db_path = store::remote_db_file(root_path, &remote.name);
+        if !remote_db_path.exists() {
+            continue;
+        }
+        let remote_connection = Connection::open(&remote_db_path)?;
+        for (hash, path) in tree::reachable_objects(&remote_connection)? {
+            objects.entry(hash).or_insert(path);
+        }
+    }
+    for (hash, path) in store::linked_objects(root_path)? {
+        objects
+            .entry(hash)
+            .or_insert(path.to_string_lossy().to_string());
+    }
+    Ok(objects)
+}
+
+/* Remove objects from the store that nothing refers to anymore
+ *
+ * Objects written to the store within the grace period are kept even when
+ * unreachable, they may belong to a command that is still running. With
+ * dry_run set the objects are only listed.
+ */
+pub fn gc(
+    connection: &Connection,
+    root_path: &Path,
+    grace_period_days: u64,
+    dry_run: bool,
+) -> Result<()> {
+    let live = live_objects(connection, root_path)?;
+    let grace_period = Duration::from_secs(
+        grace_period_days * crate::migration::SECONDS_PER_DAY,
+    );
+    let cutoff = SystemTime::now()
+        .checked_sub(grace_period)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut removed = 0;
+    let mut removed_bytes = 0;
+    let mut kept_for_grace = 0;
+
+    for hash in store::list_objects(root_path)? {
+        if live.contains_key(&hash) {
+            continue;
+        }
+
+        let Some(path) = store::find_object(root_path, &hash) else {
+            continue;
+        };
+        let metadata = fs::metadata(&path)?;
+        if metadata.modified()? > cutoff {
+            kept_for_grace += 1;
+            continue;
+        }
+
+        if dry_run {
+            println!("would remove: {} ({} bytes)", hash, metadata.len());
+        } else {
+            println!("removing: {} ({} bytes)", hash, metadata.len());
+            fs::remove_file(&path)?;
+        }
+        removed += 1;
+        removed_bytes += metadata.len();
+    }
+
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    println!("{} {} objects, {} bytes", verb, removed, removed_bytes);
+    if kept_for_grace > 0 {
+        println!(
+            "Kept {} unreachable objects newer than {} days",
+            kept_for_grace, grace_period_days
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::models::chunk::Chunk;
+    use crate::models::commit::Commit;
+    use crate::models::reference::Kind;
+    use crate::models::remote::Remote;
+    use crate::models::staged_file::StagedFile;
+    use crate::models::tree_file::TreeFile;
+    use crate::testing::TempDir;
+    use crate::types::checkout_strategy::CheckoutStrategy;
+    use crate::types::compression::Compression;
+    use crate::types::hash_algorithm::HashAlgorithm;
+    use crate::types::remote_kind::RemoteKind;
+
+    const AGED: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    // Objects referred to by the chunks of a file on a branch, the staging
+    // area, the fetched remote database and a symlink checkout. gc_repo
+    // ages them past the grace period, along with one nothing refers to.
+    const LIVE: [&str; 5] =
+        ["chunk-1", "chunk-2", "staged", "remote-only", "linked"];
+
+    fn commit_file(
+        connection: &Connection,
+        commit_hash: &str,
+        path: &str,
+        file_hash: &str,
+    ) -> Result<()> {
+        db::commit::insert(
+            connection,
+            &Commit::new(commit_hash, "", "", None)?,
+        )?;
+        db::tree::insert(
+            connection,
+            &TreeFile {
+                path: String::from(path),
+                file_hash: String::from(file_hash),
+                size_bytes: 1,
+                commit_hash: String::from(commit_hash),
+            },
+        )?;
+        db::reference::insert(connection, "main", Kind::Branch, commit_hash)
+    }
+
+    fn gc_repo() -> Result<(TempDir, Connection)> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path();
+        store::init(root_path)?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+
+        for hash in LIVE.iter().chain(&["unreferenced", "fresh"]) {
+            store::insert_bytes(
+                root_path,
+                hash,
+                hash.as_bytes(),
+                Compression::None,
+            )?;
+        }
+
+        // A file stored in chunks, only the chunks are objects
+        commit_file(&connection, "c1", "big.bin", "big")?;
+        let chunks: Vec<Chunk> = ["chunk-1", "chunk-2"]
+            .iter()
+            .enumerate()
+            .map(|(position, chunk_hash)| Chunk {
+                file_hash: String::from("big"),
+                position: position as i64,
+                chunk_hash: String::from(*chunk_hash),
+                size_bytes: 1,
+            })
+            .collect();
+        db::chunk::insert(&connection, &chunks)?;
+
+        db::staging::insert(
+            &connection,
+            &Change::Addition(StagedFile {
+                path: String::from("staged.txt"),
+                file_hash: String::from("staged"),
+                size_bytes: 1,
+                modified_time_seconds: 0,
+            }),
+        )?;
+
+        db::remote::insert(
+            &connection,
+            &Remote::new("origin", RemoteKind::Local, "local:///unused")?,
+        )?;
+        let remote_connection =
+            Connection::open(store::remote_db_file(root_path, "origin"))?;
+        db::init(&remote_connection, HashAlgorithm::default())?;
+        commit_file(&remote_connection, "r1", "remote.txt", "remote-only")?;
+
+        store::export_to(
+            &connection,
+            root_path,
+            "linked",
+            &root_path.join("linked.txt"),
+            CheckoutStrategy::Symlink,
+        )?;
+
+        let aged = SystemTime::now() - AGED;
+        for hash in store::list_objects(root_path)? {
+            if hash != "fresh" {
+                let path = store::find_object(root_path, &hash).unwrap();
+                File::open(path)?.set_modified(aged)?;
+            }
+        }
+        Ok((dir, connection))
+    }
+
+    #[test]
+    fn test_gc_removes_only_expired_unreferenced_objects() -> Result<()> {
+        let (dir, connection) = gc_repo()?;
+        let root_path = dir.path();
+
+        gc(&connection, root_path, DEFAULT_GRACE_PERIOD_DAYS, false)?;
+
+        for hash in LIVE {
+            assert!(store::exists(root_path, hash), "{} was removed", hash);
+        }
+        // Unreachable, but written within the grace period
+        assert!(store::exists(root_path, "fresh"));
+        assert!(!store::exists(root_path, "unreferenced"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_dry_run_removes_nothing() -> Result<()> {
+        let (dir, connection) = gc_repo()?;
+        let root_path = dir.path();
+
+        gc(&connection, root_path, 0, true)?;
+
+        let mut objects = store::list_objects(root_path)?;
+        objects.sort();
+        let mut expected: Vec<String> = LIVE
+            .iter()
+            .chain(&["unreferenced", "fresh"])
+            .map(|h| h.to_string())
+            .collect();
+        expected.sort();
+        assert_eq!(objects, expected);
+        Ok(())
+    }
+}
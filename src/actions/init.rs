@@ -6,8 +6,9 @@ use rusqlite::Connection;
 use crate::db;
 use crate::db::repo_db_path;
 use crate::store;
+use crate::types::hash_algorithm::HashAlgorithm;
 
-pub fn init(path: &Path) -> Result<()> {
+pub fn init(path: &Path, hash_algorithm: HashAlgorithm) -> Result<()> {
     if !path.is_dir() {
         return Err(anyhow!(
             "desintation {} must be a directory",
@@ -25,10 +26,14 @@ pub fn init(path: &Path) -> Result<()> {
         )
         .into());
     }
-    println!("initializing sssync in: {}", path.display());
+    println!(
+        "initializing sssync in: {}, hashing with {}",
+        path.display(),
+        hash_algorithm
+    );
     store::init(path)?;
 
     let connection = Connection::open(repo_db_path(path))?;
-    db::init(&connection)?;
+    db::init(&connection, hash_algorithm)?;
     Ok(())
 }
@@ -3,8 +3,10 @@ use std::path::Path;
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::actions::diff::print_diff;
 use crate::db;
 use crate::store;
+use crate::tree::TreeDiff;
 
 pub fn log(
     connection: &Connection,
@@ -12,6 +14,7 @@ pub fn log(
     maybe_hash: Option<String>,
     maybe_branch_name: Option<String>,
     maybe_remote_name: Option<String>,
+    files: bool,
 ) -> Result<()> {
     let meta = db::meta::get(connection)?;
 
@@ -47,16 +50,30 @@ pub fn log(
 
     let commits = db::commit::get_children(target_connection, &starting_hash)?;
 
-    commits.into_iter().for_each(|commit| {
+    for commit in commits {
         println!("commit {}", commit.hash);
         println!("Author: {}", commit.author);
+        if !commit.committer.is_empty() && commit.committer != commit.author {
+            println!("Committer: {}", commit.committer);
+        }
         println!("Date: {}", commit.created_unix_timestamp);
         println!(
             "Parent: {}",
-            commit.parent_hash.unwrap_or_else(|| String::from("None"))
+            commit.parent_hash.as_deref().unwrap_or("None")
         );
         println!("\t{}", commit.message);
+        if files {
+            let tree = db::tree::get(target_connection, &commit.hash)?;
+            let parent_tree = match &commit.parent_hash {
+                Some(parent_hash) => {
+                    db::tree::get(target_connection, parent_hash)?
+                }
+                None => vec![],
+            };
+            println!();
+            print_diff(&TreeDiff::new(&parent_tree, &tree), "\t");
+        }
         println!("");
-    });
+    }
     Ok(())
 }
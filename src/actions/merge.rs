@@ -3,10 +3,12 @@ use std::path::Path;
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::config;
 use crate::db;
+use crate::hash::Hasher;
 use crate::models::commit::{diff_commit_list, Commit, CompareResult};
 use crate::models::reference;
-use crate::models::status::{hash_all, Hashable, Status};
+use crate::models::status::Status;
 use crate::store;
 use crate::tree;
 
@@ -37,9 +39,12 @@ pub fn rebase(
             "Aborted: You have uncomitted changes in your working directory."
         );
         println!("{}", status);
+        return Ok(());
     }
 
     let meta = db::meta::get(resolver.destination())?;
+    let hasher = Hasher::for_repository(resolver.destination())?;
+    let committer = config::identity(resolver.destination())?;
     let head = db::commit::get_by_ref_name(resolver.destination(), &meta.head)?
         .ok_or(anyhow!("No commit"))?;
 
@@ -67,64 +72,66 @@ pub fn rebase(
     //
     // 1. get the commit
     // 2. diff the commit and its parent
-    // 3. apply the diff to the new parent
+    // 3. apply the diff to the tree of the new parent
     // 4. create a new commit copying the contents of the old commit with that
     //    tree
-    let mut combined_diff = tree::TreeDiff::empty();
+    if commits_diff.is_empty() {
+        return Ok(());
+    }
     let mut parent_hash = source_head.hash;
+    let mut tree_files = db::tree::get(resolver.source(), &parent_hash)?;
 
-    let mut commits_to_rebase = commits_diff.iter().rev().peekable();
-
-    while let Some(commit) = commits_to_rebase.next() {
+    for commit in commits_diff.iter().rev() {
         println!("rebasing {} onto {}", commit.hash, parent_hash);
-        // get the changes in the current commit
-        let diff = tree::diff_parent(connection, &commit)?;
-
-        // sum them into a bigger diff that will be applied to
-        // the filesytem at the end
-        combined_diff = combined_diff.add(&diff)?;
-
-        // find all the addition and changes
-        let updates = combined_diff.updates();
-
-        // make a new hash with the new file state from the changes
-        let hashable_files = updates
-            .into_iter()
-            .map(|f| {
-                let i: Box<dyn Hashable> = Box::new(f);
-                i
-            })
+        // get the changes in the current commit and make the new parent's
+        // files into the files of the rebased commit
+        let diff = tree::diff_parent(connection, commit)?;
+        tree_files = diff.apply(&tree_files);
+
+        // make a new hash with the full file state of the new tree
+        let files: Vec<(String, String)> = tree_files
+            .iter()
+            .map(|f| (f.path.clone(), f.file_hash.clone()))
             .collect();
 
-        let hash = hash_all(&hashable_files);
-
-        let new_commit = Commit::new(
-            &hash,
+        let new_commit = Commit::create(
+            &hasher,
+            &files,
             &commit.message,
             &commit.author,
+            &committer,
             Some(parent_hash.clone()),
         )?;
 
-        // create a new commit with that hash
-        db::commit::insert(&resolver.destination(), &new_commit)?;
+        // create a new commit with that hash and tree
+        db::commit::insert(resolver.destination(), &new_commit)?;
+        tree_files = tree_files
+            .iter()
+            .map(|f| f.update_commit_hash(&new_commit.hash))
+            .collect();
+        db::tree::insert_batch(resolver.destination(), tree_files.clone())?;
 
         // update the parent hash, it should now be
         // the hash of the commit we just added
-        parent_hash = new_commit.hash.clone();
-
-        // we are at the last commit, update the ref
-        if commits_to_rebase.peek().is_none() {
-            println!("updating head to {}", new_commit.hash);
-            db::reference::update(
-                resolver.destination(),
-                &meta.head,
-                reference::Kind::Branch,
-                &new_commit.hash,
-            )?;
-        }
+        parent_hash = new_commit.hash;
     }
 
-    store::apply_diff(root_path, &combined_diff)?;
+    println!("updating head to {}", parent_hash);
+    db::reference::update(
+        resolver.destination(),
+        &meta.head,
+        reference::Kind::Branch,
+        &parent_hash,
+    )?;
+
+    // Update the current filesystem to match the rebased tree
+    let current_tree = db::tree::get(resolver.destination(), &head.hash)?;
+    store::apply_diff(
+        resolver.destination(),
+        root_path,
+        &tree::TreeDiff::new(&current_tree, &tree_files),
+        config::checkout_strategy(resolver.destination())?,
+    )?;
 
     Ok(())
 }
@@ -162,6 +169,7 @@ pub fn merge(
             "Aborted: You have uncomitted changes in your working directory."
         );
         println!("{}", status);
+        return Ok(());
     }
     let meta = db::meta::get(resolver.destination())?;
     let head = db::commit::get_by_ref_name(resolver.destination(), &meta.head)?
@@ -221,7 +229,12 @@ pub fn merge(
     let current_tree = db::tree::get(resolver.destination(), &head.hash)?;
     let future_tree = db::tree::get(resolver.source(), &branch_head.hash)?;
     let diff = tree::TreeDiff::new(&current_tree, &future_tree);
-    store::apply_diff(root_path, &diff)?;
+    store::apply_diff(
+        resolver.destination(),
+        root_path,
+        &diff,
+        config::checkout_strategy(resolver.destination())?,
+    )?;
 
     db::reference::update(
         resolver.destination(),
@@ -286,3 +299,90 @@ impl<'a> ConnectionResolver<'a> {
         self.maybe_remote_connection.is_some()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::actions::{add, branch, commit};
+    use crate::testing::TempDir;
+    use crate::types::hash_algorithm::HashAlgorithm;
+
+    fn commit_file(
+        connection: &Connection,
+        root_path: &Path,
+        path: &str,
+        contents: &str,
+    ) -> Result<()> {
+        fs::write(root_path.join(path), contents)?;
+        add::add(connection, root_path, Path::new(path), 1)?;
+        commit::commit(connection, root_path, path, None)
+    }
+
+    #[test]
+    fn test_rebase_builds_full_trees() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path();
+        store::init(root_path)?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+        db::config::set(&connection, config::USER_NAME, "Ada")?;
+        let hasher = Hasher::for_repository(&connection)?;
+
+        commit_file(&connection, root_path, "a.txt", "a")?;
+        commit_file(&connection, root_path, "z.txt", "z")?;
+        branch::add(&connection, "feature", None)?;
+        commit_file(&connection, root_path, "z.txt", "z on main")?;
+
+        branch::switch(&connection, root_path, "feature")?;
+        commit_file(&connection, root_path, "a.txt", "a on feature")?;
+        commit_file(&connection, root_path, "b.txt", "b on feature")?;
+
+        rebase(&connection, root_path, "main", &None)?;
+
+        let main = db::reference::get(&connection, "main")?;
+        let head = db::commit::get_by_ref_name(&connection, "feature")?
+            .ok_or(anyhow!("No commit"))?;
+        let parent = db::commit::get(
+            &connection,
+            head.parent_hash.as_ref().ok_or(anyhow!("No parent"))?,
+        )?;
+        assert_eq!(parent.parent_hash, Some(main.hash));
+
+        // Both rebased commits hold every file, not just what they changed
+        let tree_of = |commit: &Commit| -> Result<Vec<(String, String)>> {
+            let mut files: Vec<(String, String)> =
+                db::tree::get(&connection, &commit.hash)?
+                    .into_iter()
+                    .map(|f| (f.path, f.file_hash))
+                    .collect();
+            files.sort();
+            Ok(files)
+        };
+        let file = |path: &str, contents: &str| {
+            (String::from(path), hasher.hash_bytes(contents.as_bytes()))
+        };
+        assert_eq!(
+            tree_of(&parent)?,
+            vec![file("a.txt", "a on feature"), file("z.txt", "z on main")]
+        );
+        let files = tree_of(&head)?;
+        assert_eq!(
+            files,
+            vec![
+                file("a.txt", "a on feature"),
+                file("b.txt", "b on feature"),
+                file("z.txt", "z on main"),
+            ]
+        );
+        assert_eq!(head.hash, head.canonical_hash(&hasher, &files));
+
+        assert_eq!(fs::read_to_string(root_path.join("z.txt"))?, "z on main");
+        assert_eq!(
+            fs::read_to_string(root_path.join("a.txt"))?,
+            "a on feature"
+        );
+        Ok(())
+    }
+}
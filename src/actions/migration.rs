@@ -1,9 +1,17 @@
-use anyhow::Result;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::actions::remote::{publish_database, upload_database};
+use crate::backend;
+use crate::config;
 use crate::db;
 use crate::models::migration;
+use crate::models::migration::{MigrationAction, MigrationState};
 use crate::models::transfer;
+use crate::remote::checkout;
+use crate::store;
 
 pub fn list(connection: &Connection) -> Result<()> {
     let migrations = db::migration::get_all(connection)?;
@@ -16,3 +24,271 @@ pub fn show(connection: &Connection, migration_id: &str) -> Result<()> {
     transfer::print_table(uploads);
     Ok(())
 }
+
+/* Pick a migration back up where it stopped
+ *
+ * Every transfer that didn't complete is retried, then the step the command
+ * that created the migration would have run after its transfers is run.
+ * Transfers that already completed are left alone so resuming a finished
+ * migration only repeats that last step.
+ */
+pub async fn resume(
+    connection: &Connection,
+    root_path: &Path,
+    migration_id: &str,
+    force: bool,
+    jobs: usize,
+) -> Result<()> {
+    let migration = db::migration::get(connection, migration_id)?;
+    if let MigrationState::Canceled = migration.state {
+        return Err(anyhow!("Migration {} was canceled", migration.id));
+    }
+
+    // A push publishes the commit it uploaded objects for, not whatever
+    // the head is now.
+    let pushed = match (&migration.ref_name, &migration.commit_hash) {
+        (Some(ref_name), Some(commit_hash)) => Some((ref_name, commit_hash)),
+        _ => None,
+    };
+    if let (Some(MigrationAction::Push), None) = (&migration.action, pushed) {
+        return Err(anyhow!(
+            "Migration {} doesn't record the commit it pushed, push again \
+             with `sssync remote push {}`",
+            migration.id,
+            migration.remote_name
+        ));
+    }
+
+    let remote = db::remote::get(connection, &migration.remote_name)?;
+    let backend = backend::for_remote(&remote).await?;
+
+    db::transfer::reset_unfinished(connection, &migration.id)?;
+    crate::migration::run(
+        connection,
+        backend.as_ref(),
+        root_path,
+        &migration,
+        force,
+        true,
+        jobs,
+    )
+    .await?;
+
+    match migration.action {
+        Some(MigrationAction::Init) => {
+            let local_db_path = store::db_path(root_path);
+            upload_database(backend.as_ref(), &local_db_path, force).await
+        }
+        Some(MigrationAction::Push) => {
+            let Some((ref_name, commit_hash)) = pushed else {
+                return Err(anyhow!("No pushed commit"));
+            };
+            // The remote may have moved on since the push started, work
+            // from a fresh copy of its database.
+            let remote_db_path = store::remote_db_file(root_path, &remote.name);
+            backend.get_database(&remote_db_path).await?;
+            let remote_connection = Connection::open(&remote_db_path)?;
+            db::upgrade(&remote_connection)?;
+            store::check_format(&remote_connection)?;
+            store::check_hashes(connection, &remote_connection, &remote.name)?;
+            publish_database(
+                connection,
+                backend.as_ref(),
+                &remote_db_path,
+                ref_name,
+                commit_hash,
+            )
+            .await
+        }
+        Some(MigrationAction::Clone) => {
+            let meta = db::meta::get(connection)?;
+            let head = db::commit::get_by_ref_name(connection, &meta.head)?
+                .ok_or(anyhow!("No commit"))?;
+            let files = db::tree::get(connection, &head.hash)?;
+            checkout(
+                connection,
+                root_path,
+                &files,
+                config::checkout_strategy(connection)?,
+            )
+        }
+        // Fetch and fsck only download objects, there is nothing left to do
+        Some(MigrationAction::Fetch) | Some(MigrationAction::Fsck) => Ok(()),
+        None => {
+            println!(
+                "Migration {} doesn't record the command that created it, \
+                 only its transfers were resumed",
+                migration.id
+            );
+            Ok(())
+        }
+    }
+}
+
+/* Delete migrations and their transfers from the database
+ *
+ * Only migrations older than older_than_days in one of states are removed,
+ * with no states given finished (Complete and Canceled) migrations are.
+ */
+pub fn prune(
+    connection: &Connection,
+    older_than_days: u64,
+    states: &[MigrationState],
+) -> Result<()> {
+    let states = if states.is_empty() {
+        vec![MigrationState::Complete, MigrationState::Canceled]
+    } else {
+        states.to_vec()
+    };
+
+    let pruned = crate::migration::prune(
+        connection,
+        older_than_days * crate::migration::SECONDS_PER_DAY,
+        &states,
+    )?;
+    println!("Pruned {} migrations", pruned);
+
+    // Deleted rows leave free pages behind, give them back so the database
+    // pushed to remotes actually shrinks.
+    if pruned > 0 {
+        connection.execute("VACUUM", [])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::actions::{add, commit, remote};
+    use crate::models::remote::Remote;
+    use crate::models::transfer::TransferKind;
+    use crate::testing::TempDir;
+    use crate::types::hash_algorithm::HashAlgorithm;
+    use crate::types::remote_kind::RemoteKind;
+
+    // A repository with a local remote in remote_dir, and a first commit
+    // of a.txt published to it
+    async fn published_repo(
+        remote_dir: &TempDir,
+    ) -> Result<(TempDir, Connection)> {
+        let dir = TempDir::new()?;
+        store::init(dir.path())?;
+        let connection = Connection::open(store::db_path(dir.path()))?;
+        db::init(&connection, HashAlgorithm::default())?;
+        db::config::set(&connection, config::USER_NAME, "Ada Lovelace")?;
+        let location = format!("local://{}", remote_dir.path().display());
+        remote::add(
+            &connection,
+            "origin",
+            &RemoteKind::Local,
+            &location,
+            Default::default(),
+            None,
+        )?;
+
+        commit_file(&connection, dir.path(), "a.txt")?;
+        remote::init(&connection, dir.path(), "origin", false, 1).await?;
+        Ok((dir, connection))
+    }
+
+    // Commits a new file, returning the hash of its object
+    fn commit_file(
+        connection: &Connection,
+        root_path: &Path,
+        name: &str,
+    ) -> Result<String> {
+        fs::write(root_path.join(name), format!("contents of {}", name))?;
+        add::add(connection, root_path, Path::new(name), 1)?;
+        commit::commit(connection, root_path, name, None)?;
+        Ok(db::tree::get_by_path(connection, Path::new(name))?.file_hash)
+    }
+
+    #[tokio::test]
+    async fn test_resume_push_publishes_the_pushed_commit() -> Result<()> {
+        let remote_dir = TempDir::new()?;
+        let (dir, connection) = published_repo(&remote_dir).await?;
+        let root_path = dir.path();
+
+        // A push of b.txt stopped before its object was uploaded
+        let b_hash = commit_file(&connection, root_path, "b.txt")?;
+        let pushed = db::reference::get(&connection, "main")?;
+        let migration = crate::migration::create(
+            &connection,
+            TransferKind::Upload,
+            MigrationAction::Push,
+            "origin",
+            std::slice::from_ref(&b_hash),
+            Some(&pushed),
+        )?;
+        db::migration::set_state(
+            &connection,
+            &migration,
+            MigrationState::Failed,
+        )?;
+        // and another commit was made before it was resumed
+        let c_hash = commit_file(&connection, root_path, "c.txt")?;
+        let latest = db::reference::get(&connection, "main")?;
+
+        resume(&connection, root_path, &migration.id, false, 1).await?;
+
+        let remote = db::remote::get(&connection, "origin")?;
+        let backend = backend::for_remote(&remote).await?;
+        assert!(backend.head_object(&b_hash).await?);
+        assert!(!backend.head_object(&c_hash).await?);
+
+        let remote_connection =
+            Connection::open(store::db_path(remote_dir.path()))?;
+        assert_eq!(
+            db::reference::get(&remote_connection, "main")?.hash,
+            pushed.hash
+        );
+        assert!(db::commit::get(&remote_connection, &latest.hash).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_clone_checks_out_files() -> Result<()> {
+        let remote_dir = TempDir::new()?;
+        let (_source_dir, _source) = published_repo(&remote_dir).await?;
+
+        // A clone that fetched the database but none of the objects
+        let dir = TempDir::new()?;
+        let root_path = dir.path();
+        store::init(root_path)?;
+        let location = format!("local://{}", remote_dir.path().display());
+        let remote = Remote::new("origin", RemoteKind::Local, &location)?;
+        let backend = backend::for_remote(&remote).await?;
+        backend.get_database(&store::db_path(root_path)).await?;
+        let connection = Connection::open(store::db_path(root_path))?;
+        db::upgrade(&connection)?;
+        db::delete_local_state(&connection)?;
+        db::remote::insert(&connection, &remote)?;
+
+        let a_hash =
+            db::tree::get_by_path(&connection, Path::new("a.txt"))?.file_hash;
+        let migration = crate::migration::create(
+            &connection,
+            TransferKind::Download,
+            MigrationAction::Clone,
+            "origin",
+            std::slice::from_ref(&a_hash),
+            None,
+        )?;
+        db::migration::set_state(
+            &connection,
+            &migration,
+            MigrationState::Failed,
+        )?;
+
+        resume(&connection, root_path, &migration.id, false, 1).await?;
+
+        assert!(store::exists(root_path, &a_hash));
+        assert_eq!(
+            fs::read_to_string(root_path.join("a.txt"))?,
+            "contents of a.txt"
+        );
+        Ok(())
+    }
+}
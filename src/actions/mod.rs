@@ -1,13 +1,20 @@
 pub mod add;
 pub mod branch;
+pub mod check_ignore;
 pub mod clone;
 pub mod commit;
+pub mod config;
 pub mod diff;
+pub mod fsck;
+pub mod gc;
 pub mod init;
 pub mod log;
 pub mod merge;
 pub mod migration;
+pub mod mv;
 pub mod remote;
 pub mod reset;
 pub mod status;
 pub mod tree;
+pub mod upgrade;
+pub mod watch;
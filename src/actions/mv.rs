@@ -0,0 +1,133 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+
+use crate::chunk;
+use crate::db;
+use crate::hash::Hasher;
+use crate::ignores;
+use crate::models::file::metadata;
+use crate::models::staged_file::{Change, StagedFile};
+use crate::models::stat_cache;
+use crate::models::status::Status;
+
+/* Moves the file or directory at from to to, or into to when it's a
+ * directory, and stages the move. Objects are content addressed so the
+ * files keep their hashes, nothing is read again unless it changed since it
+ * was last hashed. Untracked files in a directory move along with it but
+ * stay untracked.
+ */
+pub fn mv(
+    connection: &Connection,
+    root_path: &Path,
+    from: &Path,
+    to: &Path,
+) -> Result<()> {
+    let mut to = to.to_path_buf();
+    if root_path.join(&to).is_dir() {
+        let name = from
+            .file_name()
+            .ok_or(anyhow!("can't move {}", from.display()))?;
+        to.push(name);
+    }
+    let full_from = root_path.join(from);
+    let full_to = root_path.join(&to);
+
+    if full_from.symlink_metadata().is_err() {
+        return Err(anyhow!("{} doesn't exist", from.display()));
+    }
+    if full_to.symlink_metadata().is_ok() {
+        return Err(anyhow!("{} already exists", to.display()));
+    }
+    if to.starts_with(from) {
+        return Err(anyhow!("can't move {} inside itself", from.display()));
+    }
+    if let Some(rule) = ignores::check(root_path, &to)? {
+        if !rule.negated {
+            return Err(anyhow!(
+                "{} is ignored by {}, see `sssync check-ignore`",
+                to.display(),
+                rule.pattern
+            ));
+        }
+    }
+
+    // Every tracked or staged file being moved, and where it's going
+    let status = Status::new(connection, root_path)?;
+    let known: BTreeSet<&PathBuf> = status
+        .tracked_files
+        .keys()
+        .chain(&status.staged_additions)
+        .chain(&status.staged_modifications)
+        .collect();
+    let mut moves: Vec<(PathBuf, PathBuf)> = vec![];
+    for path in known {
+        if !path.starts_with(from) || !root_path.join(path).exists() {
+            continue;
+        }
+        let below = path.strip_prefix(from)?;
+        let new_path = if below.as_os_str().is_empty() {
+            to.clone()
+        } else {
+            to.join(below)
+        };
+        moves.push((path.clone(), new_path));
+    }
+    if moves.is_empty() {
+        return Err(anyhow!(
+            "{} isn't tracked, add it instead",
+            from.display()
+        ));
+    }
+
+    // The stat cache knows the files by the paths they're moving from
+    let hasher = Hasher::for_repository(connection)?;
+    let racy_after = stat_cache::racy_after()?;
+    let mut hashes = vec![];
+    for (path, _) in &moves {
+        let meta = metadata(&root_path.join(path))?;
+        hashes.push(stat_cache::hash(
+            connection, &hasher, root_path, path, &meta,
+        )?);
+    }
+
+    println!("moving: {} -> {}", full_from.display(), full_to.display());
+    if let Some(parent) = full_to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&full_from, &full_to)?;
+
+    let transaction = connection.unchecked_transaction()?;
+    for ((path, new_path), file_hash) in moves.iter().zip(hashes) {
+        if status.tracked_files.contains_key(path) {
+            db::staging::insert(&transaction, &Change::Deletion(path.clone()))?;
+        } else {
+            db::staging::delete_path(&transaction, &path.to_string_lossy())?;
+        }
+
+        let full_path = root_path.join(new_path);
+        let meta = metadata(&full_path)?;
+        stat_cache::remember(
+            &transaction,
+            new_path,
+            &meta,
+            &file_hash,
+            racy_after,
+        )?;
+        // Only stores anything if the file changed since it was hashed
+        chunk::insert(&transaction, root_path, &file_hash, &full_path)?;
+
+        let staged_file = StagedFile::new(new_path, &meta, file_hash)?;
+        let change = if status.tracked_files.contains_key(new_path) {
+            Change::Modification(staged_file)
+        } else {
+            Change::Addition(staged_file)
+        };
+        db::staging::insert(&transaction, &change)?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
@@ -1,34 +1,63 @@
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 use url::Url;
 
+use crate::backend;
+use crate::backend::ObjectBackend;
+use crate::chunk;
 use crate::db;
+use crate::encryption;
 use crate::models::commit;
+use crate::models::migration::MigrationAction;
 use crate::models::reference;
 use crate::models::remote;
-use crate::models::remote::Remote;
+use crate::models::remote::{Remote, S3Settings};
 use crate::models::transfer::TransferKind;
-use crate::remote::{fetch_remote_db, fetch_remote_objects, RemoteInfo};
-use crate::s3;
-use crate::s3::make_client;
-use crate::s3::upload_multipart::upload_multipart;
+use crate::remote::{fetch_remote_objects, RemoteInfo};
 use crate::store;
+use crate::store::{COMPRESSED_FORMAT_VERSION, FORMAT_VERSION};
 use crate::tree;
+use crate::types::compression::Compression;
 use crate::types::remote_kind::RemoteKind;
 
+// Number of objects remote gc hands to the backend to delete at once
+const GC_BATCH_SIZE: usize = 1000;
+
 // Add a remote to the repository
 pub fn add(
     connection: &Connection,
     name: &str,
     kind: &RemoteKind,
     location: &str,
+    s3: S3Settings,
+    encryption_key: Option<String>,
 ) -> Result<()> {
-    let remote = Remote::new(name, *kind, location)?;
+    if let Some(source) = &encryption_key {
+        // Fail now rather than on the first push
+        encryption::load_key(source)?;
+    }
+    RemoteInfo::from_url(location)?;
+
+    let mut remote = Remote::new(name, *kind, location)?;
+    remote.s3 = s3;
+    remote.encryption_key = encryption_key;
     db::remote::insert(connection, &remote)
 }
 
+// Write a new key for encrypting remotes to the file at path
+pub fn generate_key(path: &Path) -> Result<()> {
+    encryption::generate_key(path)?;
+    println!("Wrote a new encryption key to: {}", path.display());
+    println!(
+        "Keep a copy of it somewhere safe, without it the remote can't be read"
+    );
+    Ok(())
+}
+
 // Remote a remote from the repository
 pub fn remove(connection: &Connection, name: &str) -> Result<()> {
     db::remote::delete(connection, name).map_err(|e| e.into())
@@ -37,13 +66,28 @@ pub fn remove(connection: &Connection, name: &str) -> Result<()> {
 // Locate a named file in the remote. Returns the URL that references the file.
 pub fn locate(
     connection: &Connection,
+    root_path: &Path,
     remote_name: &str,
     path: &Path,
 ) -> Result<Url> {
     let remote = db::remote::get(connection, remote_name)?;
     let tree_file = db::tree::get_by_path(connection, path)?;
-    let url =
-        remote::remote_object_path(&remote.location, &tree_file.file_hash)?;
+
+    // The remote's layout is in its database, objects are uploaded
+    // compressed or not the way they're stored here.
+    let remote_db_path = store::remote_db_path(root_path, remote_name)?;
+    let remote_connection = Connection::open(&remote_db_path)?;
+    db::upgrade(&remote_connection)?;
+    let remote_meta = db::meta::get(&remote_connection)?;
+    let compression = store::find_object(root_path, &tree_file.file_hash)
+        .map(|p| store::object_compression(&p))
+        .unwrap_or(Compression::None);
+    let url = remote::remote_object_path(
+        &remote.location,
+        &tree_file.file_hash,
+        remote_meta.format_version,
+        compression,
+    )?;
     println!("Url: {}", url);
     Ok(url)
 }
@@ -54,6 +98,12 @@ pub fn list(connection: &Connection) -> Result<()> {
 
     for remote in remotes {
         println!("Remote: {} {}", remote.name, remote.location);
+        if let Some(endpoint_url) = &remote.s3.endpoint_url {
+            println!("\tendpoint: {}", endpoint_url);
+        }
+        if let Some(encryption_key) = &remote.encryption_key {
+            println!("\tencryption key: {}", encryption_key);
+        }
     }
 
     Ok(())
@@ -75,150 +125,181 @@ pub async fn init(
     root_path: &Path,
     remote_name: &str,
     force: bool,
+    jobs: usize,
 ) -> Result<()> {
     let remote = db::remote::get(connection, remote_name)?;
     let meta = db::meta::get(connection)?;
     let head = db::commit::get_by_ref_name(connection, &meta.head)?
         .ok_or(anyhow!("No commit"))?;
+    let backend = backend::for_remote(&remote).await?;
+    let local_db_path = store::db_path(root_path);
+
+    // check if the remote database exists before running init
+    if backend.head_database().await? && !force {
+        println!("WARNING: remote already exists.");
+        println!("\trun with --force to init anyway.");
+        return Ok(());
+    }
 
-    match remote.kind {
-        RemoteKind::S3 => {
-            let client = s3::make_client().await;
-            let remote_info = RemoteInfo::from_url(&remote.location)?;
-
-            // check if the remote file exists before running init
-            let head_object_res = client
-                .head_object()
-                .bucket(&remote_info.bucket)
-                .key(&remote_info.database_key())
-                .send()
-                .await;
-
-            if head_object_res.is_ok() && !force {
-                println!("WARNING: remote already exists.");
-                println!("\trun with --force to init anyway.");
-                return Ok(());
-            }
-
-            let local_db_path = store::db_path(root_path);
-
-            let tree = db::tree::get(connection, &head.hash)?;
-            let hashes: Vec<String> =
-                tree.iter().map(|t| t.file_hash.to_string()).collect();
-
-            let migration = crate::migration::create(
-                connection,
-                TransferKind::Upload,
-                &remote.name,
-                &hashes,
-            )?;
-
-            println!("Running Migration");
-            crate::migration::run(
-                connection, root_path, &migration, force, true,
-            )
-            .await?;
+    let tree = db::tree::get(connection, &head.hash)?;
+    let hashes = chunk::object_hashes(
+        connection,
+        tree.iter().map(|t| t.file_hash.as_str()),
+    )?;
 
-            upload_multipart(
-                &client,
-                &remote_info.bucket,
-                &remote_info.database_key(),
-                &local_db_path,
-                force,
-            )
-            .await?;
+    let migration = crate::migration::create(
+        connection,
+        TransferKind::Upload,
+        MigrationAction::Init,
+        &remote.name,
+        &hashes,
+        None,
+    )?;
 
-            Ok(())
-        }
-        RemoteKind::Local => Ok(()),
-    }
+    println!("Running Migration");
+    crate::migration::run(
+        connection,
+        backend.as_ref(),
+        root_path,
+        &migration,
+        force,
+        true,
+        jobs,
+    )
+    .await?;
+
+    upload_database(backend.as_ref(), &local_db_path, force).await
+}
+
+/* Uploads the database at db_path, leaving behind the state that has to
+ * stay in this repository, remotes and their encryption keys among it.
+ */
+pub async fn upload_database(
+    backend: &dyn ObjectBackend,
+    db_path: &Path,
+    force: bool,
+) -> Result<()> {
+    let mut shared = db_path.as_os_str().to_owned();
+    shared.push(".shared.partial");
+    let shared = PathBuf::from(shared);
+
+    db::write_shareable_copy(db_path, &shared)?;
+    let result = backend.put_database(&shared, force).await;
+    _ = fs::remove_file(&shared);
+    result
 }
 
 pub async fn push(
     connection: &Connection,
     root_path: &Path,
     remote_name: &str,
+    jobs: usize,
 ) -> Result<()> {
     let remote = db::remote::get(connection, remote_name)?;
     let meta = db::meta::get(connection)?;
     let head = db::commit::get_by_ref_name(connection, &meta.head)?
         .ok_or(anyhow!("No commit"))?;
+    let head_ref = db::reference::get(connection, &meta.head)?;
 
-    match remote.kind {
-        RemoteKind::S3 => {
-            let s3_client = s3::make_client().await;
-            let remote_info = RemoteInfo::from_url(&remote.location)?;
-
-            let remote_db_path = store::remote_db_path(root_path, remote_name)?;
-            println!("Fetching remote db: {}", remote_db_path.display());
-            fetch_remote_db(&s3_client, &remote_info, &remote_db_path).await?;
-
-            let remote_connection = Connection::open(&remote_db_path)?;
-            let remote_head =
-                db::commit::get_by_ref_name(&remote_connection, &meta.head)?
-                    .ok_or(anyhow!("No remote commit: {}", meta.head))?;
-
-            println!(
-                "Updating {} from {} to {}...",
-                remote_name, remote_head.hash, head.hash
-            );
-
-            if remote_head.hash == head.hash {
-                return Err(anyhow!("Remote is already at: {}", head.hash));
-            }
-
-            // figure out what commits are different
-            let local_commits =
-                db::commit::get_children(connection, &head.hash)?;
-
-            let remote_commits = db::commit::get_children(
-                &remote_connection,
-                &remote_head.hash,
-            )?;
-
-            let ff_commits =
-                commit::diff_commit_list_left(&local_commits, &remote_commits)?;
-
-            // Figure out what files changed between the commits
-            let file_diff = tree::diff_list(&connection, &ff_commits)?;
-            let updated_files = file_diff.updates();
-
-            let to_upload_hashes: Vec<String> =
-                updated_files.iter().map(|f| f.file_hash.clone()).collect();
-
-            let migration = crate::migration::create(
-                connection,
-                TransferKind::Upload,
-                &remote.name,
-                &to_upload_hashes,
-            )?;
-
-            println!("Running Migration");
-            crate::migration::run(
-                connection, root_path, &migration, false, true,
-            )
-            .await?;
+    let backend = backend::for_remote(&remote).await?;
 
-            // Update the local remote db before uploading it
-            // to the remote server
+    let remote_db_path = store::remote_db_file(root_path, remote_name);
+    println!("Fetching remote db: {}", remote_db_path.display());
+    backend.get_database(&remote_db_path).await?;
 
-            db::update_remote(&connection, &remote_connection)?;
+    let remote_connection = Connection::open(&remote_db_path)?;
+    db::upgrade(&remote_connection)?;
+    store::check_format(&remote_connection)?;
+    store::check_hashes(connection, &remote_connection, remote_name)?;
+    let remote_head =
+        db::commit::get_by_ref_name(&remote_connection, &meta.head)?
+            .ok_or(anyhow!("No remote commit: {}", meta.head))?;
+
+    println!(
+        "Updating {} from {} to {}...",
+        remote_name, remote_head.hash, head.hash
+    );
+
+    if remote_head.hash == head.hash {
+        return Err(anyhow!("Remote is already at: {}", head.hash));
+    }
 
-            upload_multipart(
-                &s3_client,
-                &remote_info.bucket,
-                &remote_info.database_key(),
-                &store::remote_db_path(root_path, remote_name)?,
-                true,
-            )
-            .await?;
+    // figure out what commits are different
+    let local_commits = db::commit::get_children(connection, &head.hash)?;
 
-            println!("done uploading");
+    let remote_commits =
+        db::commit::get_children(&remote_connection, &remote_head.hash)?;
 
-            Ok(())
-        }
-        RemoteKind::Local => Ok(()),
+    let ff_commits =
+        commit::diff_commit_list_left(&local_commits, &remote_commits)?;
+
+    // Figure out what files changed between the commits
+    let file_diff = tree::diff_list(connection, &ff_commits)?;
+    let updated_files = file_diff.updates();
+
+    // Files stored in chunks upload their chunks, the ones the remote
+    // already has from earlier versions of the file are skipped.
+    let to_upload_hashes = chunk::object_hashes(
+        connection,
+        updated_files.iter().map(|f| f.file_hash.as_str()),
+    )?;
+
+    let migration = crate::migration::create(
+        connection,
+        TransferKind::Upload,
+        MigrationAction::Push,
+        &remote.name,
+        &to_upload_hashes,
+        Some(&head_ref),
+    )?;
+
+    println!("Running Migration");
+    crate::migration::run(
+        connection,
+        backend.as_ref(),
+        root_path,
+        &migration,
+        false,
+        true,
+        jobs,
+    )
+    .await?;
+
+    publish_database(
+        connection,
+        backend.as_ref(),
+        &remote_db_path,
+        &head_ref.name,
+        &head_ref.hash,
+    )
+    .await?;
+
+    println!("done uploading");
+
+    Ok(())
+}
+
+/* The last step of a push, once every object is in the remote: record the
+ * pushed commits in the downloaded copy of the remote database, point
+ * ref_name at commit_hash and upload it.
+ */
+pub async fn publish_database(
+    connection: &Connection,
+    backend: &dyn ObjectBackend,
+    remote_db_path: &Path,
+    ref_name: &str,
+    commit_hash: &str,
+) -> Result<()> {
+    let remote_connection = Connection::open(remote_db_path)?;
+    db::update_remote(connection, &remote_connection, ref_name, commit_hash)?;
+
+    // The remote may now hold objects compressed by this repository
+    let local_meta = db::meta::get(connection)?;
+    if local_meta.format_version >= COMPRESSED_FORMAT_VERSION {
+        store::record_compression(&remote_connection, Compression::Zstd)?;
     }
+
+    upload_database(backend, remote_db_path, true).await
 }
 
 /* Pull down the remote database
@@ -234,19 +315,12 @@ pub async fn fetch_remote_database(
     remote_name: &str,
 ) -> Result<PathBuf> {
     let remote = db::remote::get(connection, remote_name)?;
-    let remote_db_path = store::remote_db_path(root_path, remote_name)?;
-    let remote_info = RemoteInfo::from_url(&remote.location)?;
+    let remote_db_path = store::remote_db_file(root_path, remote_name);
+    let backend = backend::for_remote(&remote).await?;
 
-    match remote.kind {
-        RemoteKind::S3 => {
-            let client = s3::make_client().await;
+    backend.get_database(&remote_db_path).await?;
 
-            fetch_remote_db(&client, &remote_info, &remote_db_path).await?;
-
-            Ok(remote_db_path.clone())
-        }
-        RemoteKind::Local => Ok(remote_db_path.clone()),
-    }
+    Ok(remote_db_path)
 }
 
 /* Push up a copy of the remote database. This probably shouldn't be used,
@@ -259,55 +333,180 @@ pub async fn push_remote_database(
     force: bool,
 ) -> Result<()> {
     let remote = db::remote::get(connection, remote_name)?;
+    let backend = backend::for_remote(&remote).await?;
 
-    match remote.kind {
-        RemoteKind::S3 => {
-            let client = s3::make_client().await;
-
-            let local_db_path = store::remote_db_path(root_path, remote_name)?;
-            let remote_info = RemoteInfo::from_url(&remote.location)?;
-
-            println!(
-                "Uploading: {} to {}",
-                local_db_path.display(),
-                remote_info.database_key()
-            );
-
-            upload_multipart(
-                &client,
-                &remote_info.bucket,
-                &remote_info.database_key(),
-                &local_db_path,
-                force,
-            )
-            .await?;
+    let local_db_path = store::remote_db_path(root_path, remote_name)?;
 
-            Ok(())
-        }
-        RemoteKind::Local => Ok(()),
-    }
+    println!(
+        "Uploading: {} to {}",
+        local_db_path.display(),
+        remote.location
+    );
+
+    upload_database(backend.as_ref(), &local_db_path, force).await
 }
 
 /* Retreive all remote objects
+ *
+ * Downloads the remote database along with the objects referenced by the
+ * remote head. The working directory is left untouched, use merge to bring
+ * the changes in.
  */
 pub async fn fetch(
     connection: &Connection,
     root_path: &Path,
     remote_name: &str,
+    jobs: usize,
 ) -> Result<()> {
     let remote = db::remote::get(connection, remote_name)?;
-    let remote_info = RemoteInfo::from_url(&remote.location)?;
-
-    match remote.kind {
-        RemoteKind::S3 => {
-            let client = make_client().await;
-            let remote_db_path = store::remote_db_path(root_path, remote_name)?;
-            fetch_remote_db(&client, &remote_info, &remote_db_path).await?;
-            fetch_remote_objects(&connection, &root_path, remote_name).await?;
-            Ok(())
+    let backend = backend::for_remote(&remote).await?;
+
+    let remote_db_path = store::remote_db_file(root_path, remote_name);
+    backend.get_database(&remote_db_path).await?;
+    let remote_connection = Connection::open(&remote_db_path)?;
+    db::upgrade(&remote_connection)?;
+    store::check_format(&remote_connection)?;
+    store::check_hashes(connection, &remote_connection, remote_name)?;
+
+    fetch_remote_objects(
+        connection,
+        backend.as_ref(),
+        &remote_connection,
+        root_path,
+        remote_name,
+        MigrationAction::Fetch,
+        jobs,
+    )
+    .await?;
+    Ok(())
+}
+
+/* Delete objects from the remote that no branch of the remote reaches
+ *
+ * The remote database is fetched first so the reachable set is as fresh as
+ * possible. A push uploads its objects before the database that refers to
+ * them, so objects written within the grace period are kept. With dry_run
+ * set the objects, and the bytes that would be reclaimed, are only listed.
+ */
+pub async fn gc(
+    connection: &Connection,
+    root_path: &Path,
+    remote_name: &str,
+    grace_period_days: u64,
+    dry_run: bool,
+) -> Result<()> {
+    let remote = db::remote::get(connection, remote_name)?;
+    let backend = backend::for_remote(&remote).await?;
+    let remote_db_path = store::remote_db_file(root_path, remote_name);
+    collect_garbage(
+        connection,
+        backend.as_ref(),
+        &remote_db_path,
+        remote_name,
+        grace_period_days,
+        dry_run,
+    )
+    .await
+}
+
+/* Remote gc against backend, with the remote database downloaded to
+ * remote_db_path.
+ */
+async fn collect_garbage(
+    connection: &Connection,
+    backend: &dyn ObjectBackend,
+    remote_db_path: &Path,
+    remote_name: &str,
+    grace_period_days: u64,
+    dry_run: bool,
+) -> Result<()> {
+    backend.get_database(remote_db_path).await?;
+    let remote_connection = Connection::open(remote_db_path)?;
+    // Objects are only deleted once it's certain the database naming them
+    // is one this sssync understands.
+    db::upgrade(&remote_connection)?;
+    store::check_format(&remote_connection)?;
+    store::check_hashes(connection, &remote_connection, remote_name)?;
+    let reachable = tree::reachable_objects(&remote_connection)?;
+
+    let grace_period = Duration::from_secs(
+        grace_period_days * crate::migration::SECONDS_PER_DAY,
+    );
+    let cutoff = SystemTime::now()
+        .checked_sub(grace_period)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut unreachable: Vec<String> = vec![];
+    let mut unreachable_bytes = 0;
+    let mut kept_for_grace = 0;
+
+    for object in backend.list_objects().await? {
+        if reachable.contains_key(&object.hash) {
+            continue;
+        }
+        if object.last_modified.is_some_and(|t| t > cutoff) {
+            kept_for_grace += 1;
+            continue;
         }
-        RemoteKind::Local => Ok(()),
+
+        println!("unreachable: {} ({} bytes)", object.hash, object.size_bytes);
+        unreachable_bytes += object.size_bytes;
+        unreachable.push(object.hash);
+    }
+
+    println!(
+        "{} unreachable objects, {} bytes can be reclaimed from {}",
+        unreachable.len(),
+        unreachable_bytes,
+        remote_name
+    );
+    if kept_for_grace > 0 {
+        println!(
+            "Kept {} unreachable objects newer than {} days",
+            kept_for_grace, grace_period_days
+        );
+    }
+
+    if dry_run || unreachable.is_empty() {
+        return Ok(());
     }
+
+    for batch in unreachable.chunks(GC_BATCH_SIZE) {
+        backend.delete_objects(batch).await?;
+        println!("Deleted {} objects", batch.len());
+    }
+    Ok(())
+}
+
+/* Bring the remote up to the current format
+ *
+ * Objects still stored at their flat keys are moved to their fanned out
+ * keys, then the new format version is recorded in the remote database.
+ */
+pub async fn upgrade(
+    connection: &Connection,
+    root_path: &Path,
+    remote_name: &str,
+) -> Result<()> {
+    let remote = db::remote::get(connection, remote_name)?;
+    let backend = backend::for_remote(&remote).await?;
+
+    let remote_db_path = store::remote_db_file(root_path, remote_name);
+    backend.get_database(&remote_db_path).await?;
+    let remote_connection = Connection::open(&remote_db_path)?;
+    db::upgrade(&remote_connection)?;
+    store::check_format(&remote_connection)?;
+
+    let meta = db::meta::get(&remote_connection)?;
+    if meta.format_version < FORMAT_VERSION {
+        let moved = backend.shard_objects().await?;
+        println!("Moved {} objects to the fanned out layout", moved);
+        db::meta::set_format_version(&remote_connection, FORMAT_VERSION)?;
+        upload_database(backend.as_ref(), &remote_db_path, true).await?;
+    }
+
+    println!("{} is at format version {}", remote_name, FORMAT_VERSION);
+    Ok(())
 }
 
 pub fn branch_list(
@@ -330,3 +529,136 @@ pub fn branch_list(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::backend::memory::MemoryBackend;
+    use crate::hash::LEGACY_HASH_VERSION;
+    use crate::models::commit::Commit;
+    use crate::models::tree_file::TreeFile;
+    use crate::testing::TempDir;
+    use crate::types::hash_algorithm::HashAlgorithm;
+
+    const AGED: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    /* A remote whose main branch reaches the object "kept", holding that,
+     * an unreachable object written just now and more unreachable objects
+     * past the grace period than fit in one delete.
+     */
+    fn gc_remote(dir: &TempDir) -> Result<(MemoryBackend, Connection)> {
+        let remote_db_path = dir.path().join("remote.db");
+        let remote_connection = Connection::open(&remote_db_path)?;
+        db::init(&remote_connection, HashAlgorithm::default())?;
+        db::commit::insert(
+            &remote_connection,
+            &Commit::new("c1", "", "", None)?,
+        )?;
+        db::tree::insert(
+            &remote_connection,
+            &TreeFile {
+                path: String::from("kept.txt"),
+                file_hash: String::from("kept"),
+                size_bytes: 1,
+                commit_hash: String::from("c1"),
+            },
+        )?;
+        db::reference::insert(
+            &remote_connection,
+            "main",
+            reference::Kind::Branch,
+            "c1",
+        )?;
+
+        let backend = MemoryBackend::new();
+        let aged = SystemTime::now() - AGED;
+        let mut objects = backend.objects.lock().unwrap();
+        let mut last_modified = backend.last_modified.lock().unwrap();
+        for i in 0..GC_BATCH_SIZE + 1 {
+            let hash = format!("expired-{}", i);
+            objects.insert(hash.clone(), (Compression::None, vec![]));
+            last_modified.insert(hash, aged);
+        }
+        for hash in ["kept", "recent"] {
+            objects.insert(hash.to_string(), (Compression::None, vec![]));
+        }
+        last_modified.insert(String::from("kept"), aged);
+        last_modified.insert(String::from("recent"), SystemTime::now());
+        drop((objects, last_modified));
+
+        Ok((backend, remote_connection))
+    }
+
+    fn publish(backend: &MemoryBackend, dir: &TempDir) -> Result<()> {
+        *backend.database.lock().unwrap() =
+            Some(fs::read(dir.path().join("remote.db"))?);
+        Ok(())
+    }
+
+    fn remote_objects(backend: &MemoryBackend) -> HashSet<String> {
+        backend.objects.lock().unwrap().keys().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn test_gc_deletes_expired_unreachable_objects() -> Result<()> {
+        let dir = TempDir::new()?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+        let (backend, _) = gc_remote(&dir)?;
+        publish(&backend, &dir)?;
+
+        let fetched = dir.path().join("fetched.db");
+        collect_garbage(&connection, &backend, &fetched, "origin", 14, true)
+            .await?;
+        assert_eq!(remote_objects(&backend).len(), GC_BATCH_SIZE + 3);
+
+        collect_garbage(&connection, &backend, &fetched, "origin", 14, false)
+            .await?;
+        assert_eq!(
+            remote_objects(&backend),
+            HashSet::from([String::from("kept"), String::from("recent")])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_refuses_unknown_remote_databases() -> Result<()> {
+        let dir = TempDir::new()?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+        let (backend, remote_connection) = gc_remote(&dir)?;
+        let fetched = dir.path().join("fetched.db");
+
+        db::meta::set_format_version(&remote_connection, FORMAT_VERSION + 1)?;
+        publish(&backend, &dir)?;
+        let result = collect_garbage(
+            &connection,
+            &backend,
+            &fetched,
+            "origin",
+            14,
+            false,
+        )
+        .await;
+        assert!(result.is_err());
+
+        db::meta::set_format_version(&remote_connection, FORMAT_VERSION)?;
+        db::meta::set_hash_version(&remote_connection, LEGACY_HASH_VERSION)?;
+        publish(&backend, &dir)?;
+        let result = collect_garbage(
+            &connection,
+            &backend,
+            &fetched,
+            "origin",
+            14,
+            false,
+        )
+        .await;
+        assert!(result.is_err());
+
+        assert_eq!(remote_objects(&backend).len(), GC_BATCH_SIZE + 3);
+        Ok(())
+    }
+}
@@ -3,6 +3,7 @@ use std::path::Path;
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::config;
 use crate::db::staging;
 use crate::models::status::Status;
 use crate::store;
@@ -17,15 +18,22 @@ pub fn reset(
 ) -> Result<()> {
     staging::delete(connection)?;
     if hard {
+        let strategy = config::checkout_strategy(connection)?;
         let status = Status::new(connection, root_path)?;
-        for ua in status.unstaged_additions {
+        for um in status.unstaged_modifications {
             let tf = status
                 .tracked_files
-                .get(&ua)
+                .get(&um)
                 .ok_or(anyhow!("weird, missing tracked file"))?;
 
-            let full_file_path = root_path.join(&ua);
-            store::export_to(root_path, &tf.file_hash, &full_file_path)?;
+            let full_file_path = root_path.join(&um);
+            store::export_to(
+                connection,
+                root_path,
+                &tf.file_hash,
+                &full_file_path,
+                strategy,
+            )?;
         }
 
         for ud in status.unstaged_deletions {
@@ -35,7 +43,13 @@ pub fn reset(
                 .ok_or(anyhow!("weird, missing tracked file"))?;
 
             let full_file_path = root_path.join(&ud);
-            store::export_to(root_path, &tf.file_hash, &full_file_path)?;
+            store::export_to(
+                connection,
+                root_path,
+                &tf.file_hash,
+                &full_file_path,
+                strategy,
+            )?;
         }
     }
     Ok(())
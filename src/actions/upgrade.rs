@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+
+use crate::db;
+use crate::hash::{Hasher, HASH_VERSION};
+use crate::models::commit::Commit;
+use crate::models::staged_file::Change;
+use crate::models::tree_file::TreeFile;
+use crate::store;
+use crate::store::FORMAT_VERSION;
+use crate::types::checkout_strategy::CheckoutStrategy;
+
+/* Bring the repository up to the current format
+ *
+ * Objects still stored flat are moved to their fanned out location and the
+ * new format version is recorded. Repositories hashed with an older hash
+ * version are rehashed. Running it on an up to date repository does
+ * nothing.
+ */
+pub fn upgrade(connection: &Connection, root_path: &Path) -> Result<()> {
+    // There's no upgrading a repository from a newer sssync
+    store::check_format(connection)?;
+    let meta = db::meta::get(connection)?;
+
+    if meta.format_version < FORMAT_VERSION {
+        let moved = store::shard_objects(root_path)?;
+        println!("Moved {} objects to the fanned out layout", moved);
+        db::meta::set_format_version(connection, FORMAT_VERSION)?;
+    }
+
+    if meta.hash_version < HASH_VERSION {
+        rehash(connection, root_path)?;
+    }
+
+    println!(
+        "Repository is at format version {}, hash version {}",
+        FORMAT_VERSION, HASH_VERSION
+    );
+    Ok(())
+}
+
+/* Move the repository to the current hash version
+ *
+ * When the encoding of object hashes changed every object in the store is
+ * read once, which checks it still matches its old hash and gives its new
+ * one, and files stored in chunks are hashed from their chunks. Nothing is
+ * changed unless every file the repository refers to can be rehashed,
+ * objects only in a remote have to be fetched first. Repositories whose
+ * objects keep their names only have their commits rehashed.
+ *
+ * Objects are linked to their new names, then the database is rewritten in
+ * one transaction: manifests, staging, trees and transfers get the new file
+ * hashes and every commit is hashed again from its canonical serialization,
+ * parents before children, with references following along. Only then are
+ * the old names removed.
+ */
+fn rehash(connection: &Connection, root_path: &Path) -> Result<()> {
+    let from = Hasher::for_repository(connection)?;
+    let to = from.upgraded();
+
+    let trees = db::tree::get_all(connection)?;
+    let renamed = if from.names_objects_like(&to) {
+        HashMap::new()
+    } else {
+        rehash_objects(connection, root_path, &from, &to, &trees)?
+    };
+    let new_file_hash =
+        |hash: &String| renamed.get(hash).unwrap_or(hash).clone();
+
+    let mut commit_files: HashMap<&str, Vec<(String, String)>> = HashMap::new();
+    for t in &trees {
+        commit_files
+            .entry(&t.commit_hash)
+            .or_default()
+            .push((t.path.clone(), new_file_hash(&t.file_hash)));
+    }
+
+    // A commit's hash covers its parent's, so parents are rehashed first
+    let commits: HashMap<String, Commit> = db::commit::get_all(connection)?
+        .into_iter()
+        .map(|c| (c.hash.clone(), c))
+        .collect();
+    let mut rehashed_commits: HashMap<String, String> = HashMap::new();
+    let mut new_commit_hashes: HashMap<String, String> = HashMap::new();
+    for commit in commits.values() {
+        let mut chain = vec![];
+        let mut next = Some(commit);
+        while let Some(c) = next {
+            if rehashed_commits.contains_key(&c.hash) {
+                break;
+            }
+            chain.push(c);
+            next = c.parent_hash.as_ref().and_then(|p| commits.get(p));
+        }
+
+        for c in chain.into_iter().rev() {
+            let parent_hash = c
+                .parent_hash
+                .as_ref()
+                .map(|p| rehashed_commits.get(p).unwrap_or(p).clone());
+            let files = commit_files
+                .get(c.hash.as_str())
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let new = Commit {
+                parent_hash,
+                ..c.clone()
+            }
+            .canonical_hash(&to, files);
+            if let Some(other) =
+                new_commit_hashes.insert(new.clone(), c.hash.clone())
+            {
+                return Err(anyhow!(
+                    "commits {} and {} would share a hash, can't upgrade",
+                    other,
+                    c.hash
+                ));
+            }
+            rehashed_commits.insert(c.hash.clone(), new);
+        }
+    }
+
+    // Link every object to its new name, the old names are kept until the
+    // database refers to the new ones
+    for (old, new) in &renamed {
+        // Files stored in chunks have no object of their own
+        let Some(source) = store::find_object(root_path, old) else {
+            continue;
+        };
+        let compression = store::object_compression(&source);
+        let destination =
+            store::encoded_object_path(root_path, new, compression);
+        if destination.exists() {
+            continue;
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::hard_link(&source, &destination).is_err() {
+            fs::copy(&source, &destination)?;
+        }
+    }
+
+    let transaction = connection.unchecked_transaction()?;
+    for (old, new) in &renamed {
+        db::chunk::replace_hash(&transaction, old, new)?;
+        db::staging::replace_file_hash(&transaction, old, new)?;
+        db::tree::replace_file_hash(&transaction, old, new)?;
+        db::transfer::replace_object_hash(&transaction, old, new)?;
+    }
+    for (old, new) in &rehashed_commits {
+        db::commit::replace_hash(&transaction, old, new)?;
+        db::tree::replace_commit_hash(&transaction, old, new)?;
+        db::reference::replace_hash(&transaction, old, new)?;
+    }
+    db::stat_cache::delete_all(&transaction)?;
+    db::meta::set_hash_version(&transaction, HASH_VERSION)?;
+    transaction.commit()?;
+    println!("Rewrote {} commits", rehashed_commits.len());
+
+    // Symlinks in the working directory still point at the old names
+    for (old, path) in store::linked_objects(root_path)? {
+        if let Some(new) = renamed.get(&old) {
+            store::export_to(
+                connection,
+                root_path,
+                new,
+                &root_path.join(path),
+                CheckoutStrategy::Symlink,
+            )?;
+        }
+    }
+
+    let new_names: HashSet<&String> = renamed.values().collect();
+    for old in renamed.keys() {
+        if new_names.contains(old) {
+            continue;
+        }
+        if let Some(path) = store::find_object(root_path, old) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/* Reads every object in the store and every file stored in chunks, giving
+ * the new name of each. Errors if any file the trees or the staging area
+ * refer to can't be read.
+ */
+fn rehash_objects(
+    connection: &Connection,
+    root_path: &Path,
+    from: &Hasher,
+    to: &Hasher,
+    trees: &[TreeFile],
+) -> Result<HashMap<String, String>> {
+    let mut renamed: HashMap<String, String> = HashMap::new();
+    for hash in store::list_objects(root_path)? {
+        let (old, new) =
+            from.rehash(to, [store::open_object(root_path, &hash)])?;
+        if old != hash {
+            return Err(anyhow!(
+                "object {} is corrupt, run sssync fsck before upgrading",
+                hash
+            ));
+        }
+        renamed.insert(hash, new);
+    }
+    println!("Rehashed {} objects", renamed.len());
+
+    let mut manifests: BTreeMap<String, Vec<(i64, String)>> = BTreeMap::new();
+    for chunk in db::chunk::get_all(connection)? {
+        manifests
+            .entry(chunk.file_hash)
+            .or_default()
+            .push((chunk.position, chunk.chunk_hash));
+    }
+    for (file_hash, mut chunks) in manifests {
+        if renamed.contains_key(&file_hash)
+            || !chunks.iter().all(|(_, c)| renamed.contains_key(c))
+        {
+            continue;
+        }
+        chunks.sort();
+        let (old, new) = from.rehash(
+            to,
+            chunks.iter().map(|(_, c)| store::open_object(root_path, c)),
+        )?;
+        if old != file_hash {
+            return Err(anyhow!(
+                "chunks of {} are corrupt, run sssync fsck before upgrading",
+                file_hash
+            ));
+        }
+        renamed.insert(file_hash, new);
+    }
+
+    let mut file_hashes: Vec<&String> =
+        trees.iter().map(|t| &t.file_hash).collect();
+    let staged: Vec<Change> = db::staging::get_all(connection)?;
+    for change in &staged {
+        if let Change::Addition(sf) | Change::Modification(sf) = change {
+            file_hashes.push(&sf.file_hash);
+        }
+    }
+    let missing = file_hashes
+        .iter()
+        .filter(|h| !renamed.contains_key(h.as_str()))
+        .count();
+    if missing > 0 {
+        return Err(anyhow!(
+            "{} files are missing from the store, fetch them with \
+             sssync fsck --refetch <remote> before upgrading",
+            missing
+        ));
+    }
+    Ok(renamed)
+}
@@ -0,0 +1,179 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use rusqlite::Connection;
+
+use crate::db;
+use crate::ignores::{self, IGNORE_FILE_NAME};
+use crate::models::file;
+use crate::store::STORE_DIR;
+use crate::watch;
+
+fn watch_mask() -> WatchMask {
+    // Only the existence of files matters to status, a file being written
+    // to is only of interest when it's an ignore file.
+    WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::ONLYDIR
+}
+
+fn is_ignored(root_path: &Path, rel_path: &Path) -> Result<bool> {
+    Ok(ignores::check(root_path, rel_path)?.is_some_and(|r| !r.negated))
+}
+
+/* Watches rel_dir and every directory below it that isn't ignored. Returns
+ * false when part of it went away before it could be watched, in which case
+ * whatever replaces it may not be watched either.
+ */
+fn add_watches(
+    handle: &mut Watches,
+    watched: &mut HashMap<WatchDescriptor, PathBuf>,
+    root_path: &Path,
+    rel_dir: &Path,
+) -> Result<bool> {
+    let dirs = match file::get_dirs_in(root_path, rel_dir) {
+        Ok(dirs) => dirs,
+        Err(e) if is_not_found(&e) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    for dir in dirs {
+        match handle.add(root_path.join(&dir), watch_mask()) {
+            Ok(wd) => {
+                watched.insert(wd, dir);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                return Err(anyhow!(
+                    "ran out of inotify watches at {} directories, raise \
+                    fs.inotify.max_user_watches with sysctl",
+                    watched.len()
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+}
+
+// Stops watching rel_dir and everything below it
+fn remove_watches(
+    handle: &mut Watches,
+    watched: &mut HashMap<WatchDescriptor, PathBuf>,
+    rel_dir: &Path,
+) {
+    let below: Vec<WatchDescriptor> = watched
+        .iter()
+        .filter(|(_, dir)| dir.starts_with(rel_dir))
+        .map(|(wd, _)| wd.clone())
+        .collect();
+    for wd in below {
+        watched.remove(&wd);
+        // Fails if the directory is already gone, which is just as good
+        _ = handle.remove(wd);
+    }
+}
+
+/* Runs until killed, recording every path created, deleted or moved in the
+ * working directory so status only has to look at those.
+ *
+ * Changes are recorded after any new directory is watched, so by the time
+ * status sees a directory it's guaranteed to catch what happens inside it.
+ * When changes can't be trusted to have all been seen, the kernel queue
+ * overflowed or an ignore file changed, the snapshot status keeps is
+ * thrown away and the next status walks the working directory again.
+ */
+pub fn watch(connection: &Connection, root_path: &Path) -> Result<()> {
+    let _lock = watch::lock(root_path)?;
+    // Left behind by a watcher that didn't get to clean up
+    db::watcher::delete_all(connection)?;
+
+    let mut inotify = Inotify::init()?;
+    let mut handle = inotify.watches();
+    let mut watched = HashMap::new();
+    while !add_watches(&mut handle, &mut watched, root_path, Path::new(""))? {}
+
+    let pid = i64::from(std::process::id());
+    db::watcher::register(connection, pid)?;
+    db::dirty_path::take_all(connection)?;
+    println!(
+        "watching {} directories in {}",
+        watched.len(),
+        root_path.display()
+    );
+
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let mut dirty = BTreeSet::new();
+        let mut lost_track = false;
+
+        for event in inotify.read_events_blocking(&mut buffer)? {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                lost_track = true;
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                watched.remove(&event.wd);
+                continue;
+            }
+            let (Some(dir), Some(name)) = (watched.get(&event.wd), event.name)
+            else {
+                continue;
+            };
+            let path = dir.join(name);
+            if path.starts_with(STORE_DIR) {
+                continue;
+            }
+
+            if name == IGNORE_FILE_NAME {
+                lost_track = true;
+            }
+            if event.mask.contains(EventMask::CLOSE_WRITE) {
+                continue;
+            }
+
+            if event.mask.contains(EventMask::ISDIR) {
+                if event.mask.contains(EventMask::MOVED_FROM) {
+                    remove_watches(&mut handle, &mut watched, &path);
+                }
+                if event
+                    .mask
+                    .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                    && !is_ignored(root_path, &path)?
+                    && !add_watches(
+                        &mut handle,
+                        &mut watched,
+                        root_path,
+                        &path,
+                    )?
+                {
+                    lost_track = true;
+                }
+            }
+            dirty.insert(path.to_string_lossy().to_string());
+        }
+
+        if lost_track {
+            // Directories an ignore file no longer ignores need watching
+            add_watches(&mut handle, &mut watched, root_path, Path::new(""))?;
+        }
+
+        let dirty: Vec<String> = dirty.into_iter().collect();
+        let transaction = connection.unchecked_transaction()?;
+        db::dirty_path::insert_batch(&transaction, &dirty)?;
+        if lost_track {
+            db::watcher::invalidate(&transaction, pid)?;
+        }
+        transaction.commit()?;
+    }
+}
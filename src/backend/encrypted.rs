@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::fs;
+
+use crate::backend::{ObjectBackend, ObjectInfo};
+use crate::encryption;
+use crate::encryption::Key;
+use crate::types::compression::Compression;
+
+/* Wraps the backend of a remote that has an encryption key. Objects and the
+ * database are encrypted into a file next to them before they're handed to
+ * the inner backend, and downloaded into one and decrypted on the way back.
+ * Everything else is passed through, objects keep the names of their
+ * plaintext.
+ */
+pub struct EncryptedBackend {
+    inner: Box<dyn ObjectBackend>,
+    key: Key,
+}
+
+impl EncryptedBackend {
+    pub fn new(inner: Box<dyn ObjectBackend>, key: Key) -> Self {
+        Self { inner, key }
+    }
+
+    // Encrypts source into a temporary file and returns its path
+    async fn encrypt(&self, source: &Path) -> Result<PathBuf> {
+        let key = self.key;
+        let source = source.to_path_buf();
+        let encrypted = encryption::encrypted_path(&source);
+        let destination = encrypted.clone();
+        // Encrypting large objects takes a while, keep it off of the threads
+        // driving the other transfers.
+        let result = tokio::task::spawn_blocking(move || {
+            encryption::encrypt_file(&key, &source, &destination)
+        })
+        .await?;
+        if result.is_err() {
+            _ = fs::remove_file(&encrypted).await;
+        }
+        result?;
+        Ok(encrypted)
+    }
+
+    async fn decrypt(&self, source: &Path, destination: &Path) -> Result<()> {
+        let key = self.key;
+        let source = source.to_path_buf();
+        let destination = destination.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            encryption::decrypt_file(&key, &source, &destination)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl ObjectBackend for EncryptedBackend {
+    async fn put_object(
+        &self,
+        hash: &str,
+        compression: Compression,
+        source: &Path,
+        force: bool,
+    ) -> Result<()> {
+        let encrypted = self.encrypt(source).await?;
+        let result = self
+            .inner
+            .put_object(hash, compression, &encrypted, force)
+            .await;
+        _ = fs::remove_file(&encrypted).await;
+        result
+    }
+
+    async fn get_object(
+        &self,
+        hash: &str,
+        destination: &Path,
+    ) -> Result<Compression> {
+        let encrypted = encryption::encrypted_path(destination);
+        let result = async {
+            let compression = self.inner.get_object(hash, &encrypted).await?;
+            self.decrypt(&encrypted, destination).await?;
+            Ok(compression)
+        }
+        .await;
+        _ = fs::remove_file(&encrypted).await;
+        result
+    }
+
+    async fn head_object(&self, hash: &str) -> Result<bool> {
+        self.inner.head_object(hash).await
+    }
+
+    async fn list_objects(&self) -> Result<Vec<ObjectInfo>> {
+        self.inner.list_objects().await
+    }
+
+    async fn delete_objects(&self, hashes: &[String]) -> Result<()> {
+        self.inner.delete_objects(hashes).await
+    }
+
+    async fn shard_objects(&self) -> Result<usize> {
+        self.inner.shard_objects().await
+    }
+
+    async fn put_database(&self, source: &Path, force: bool) -> Result<()> {
+        let encrypted = self.encrypt(source).await?;
+        let result = self.inner.put_database(&encrypted, force).await;
+        _ = fs::remove_file(&encrypted).await;
+        result
+    }
+
+    async fn get_database(&self, destination: &Path) -> Result<()> {
+        let encrypted = encryption::encrypted_path(destination);
+        let result = async {
+            self.inner.get_database(&encrypted).await?;
+            self.decrypt(&encrypted, destination).await
+        }
+        .await;
+        _ = fs::remove_file(&encrypted).await;
+        result
+    }
+
+    async fn head_database(&self) -> Result<bool> {
+        self.inner.head_database().await
+    }
+}
@@ -0,0 +1,244 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::fs;
+
+use crate::backend::{ObjectBackend, ObjectInfo};
+use crate::remote::RemoteInfo;
+use crate::types::compression::Compression;
+
+/* A backend for remotes that live on a locally mounted filesystem, an
+ * external drive or an NFS mount for example. Keys are laid out exactly as
+ * they are in S3, resolved against the root of the filesystem.
+ */
+pub struct LocalBackend {
+    remote_info: RemoteInfo,
+}
+
+impl LocalBackend {
+    pub fn new(remote_info: RemoteInfo) -> Self {
+        Self { remote_info }
+    }
+
+    fn object_path(&self, hash: &str, compression: Compression) -> PathBuf {
+        self.remote_info
+            .local_path(&self.remote_info.encoded_object_key(hash, compression))
+    }
+
+    fn flat_object_path(&self, hash: &str) -> PathBuf {
+        self.remote_info
+            .local_path(&self.remote_info.flat_object_key(hash))
+    }
+
+    fn objects_path(&self) -> PathBuf {
+        self.remote_info
+            .local_path(&self.remote_info.objects_prefix())
+    }
+
+    fn database_path(&self) -> PathBuf {
+        self.remote_info
+            .local_path(&self.remote_info.database_key())
+    }
+
+    // Every path the object may be stored at, with its compression
+    fn object_paths(&self, hash: &str) -> [(PathBuf, Compression); 3] {
+        [
+            (self.object_path(hash, Compression::None), Compression::None),
+            (self.object_path(hash, Compression::Zstd), Compression::Zstd),
+            (self.flat_object_path(hash), Compression::None),
+        ]
+    }
+
+    // Where the object can be read from, in either layout
+    async fn find_object(
+        &self,
+        hash: &str,
+    ) -> Result<Option<(PathBuf, Compression)>> {
+        for (path, compression) in self.object_paths(hash) {
+            if fs::try_exists(&path).await? {
+                return Ok(Some((path, compression)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl ObjectBackend for LocalBackend {
+    async fn put_object(
+        &self,
+        hash: &str,
+        compression: Compression,
+        source: &Path,
+        force: bool,
+    ) -> Result<()> {
+        copy_into(source, &self.object_path(hash, compression), force).await
+    }
+
+    async fn get_object(
+        &self,
+        hash: &str,
+        destination: &Path,
+    ) -> Result<Compression> {
+        let (source, compression) = self
+            .find_object(hash)
+            .await?
+            .ok_or(anyhow!("object not found: {}", hash))?;
+        println!("copying: {}", source.display());
+        fs::copy(source, destination).await?;
+        Ok(compression)
+    }
+
+    async fn head_object(&self, hash: &str) -> Result<bool> {
+        Ok(self.find_object(hash).await?.is_some())
+    }
+
+    async fn list_objects(&self) -> Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+
+        let mut entries = match fs::read_dir(self.objects_path()).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(objects)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(name) = object_file_name(&entry) else {
+                continue;
+            };
+            let metadata = entry.metadata().await?;
+
+            if metadata.is_file() {
+                let (hash, _) = Compression::from_name(&name);
+                objects.push(object_info(hash.to_string(), &metadata));
+                continue;
+            }
+
+            let mut shard = fs::read_dir(entry.path()).await?;
+            while let Some(shard_entry) = shard.next_entry().await? {
+                let Some(rest) = object_file_name(&shard_entry) else {
+                    continue;
+                };
+                let metadata = shard_entry.metadata().await?;
+                if metadata.is_file() {
+                    let (rest, _) = Compression::from_name(&rest);
+                    objects.push(object_info(
+                        format!("{}{}", name, rest),
+                        &metadata,
+                    ));
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn delete_objects(&self, hashes: &[String]) -> Result<()> {
+        for hash in hashes {
+            for (path, _) in self.object_paths(hash) {
+                match fs::remove_file(path).await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e.into())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn shard_objects(&self) -> Result<usize> {
+        let mut moved = 0;
+        let mut entries = match fs::read_dir(self.objects_path()).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(hash) = object_file_name(&entry) else {
+                continue;
+            };
+            if !entry.metadata().await?.is_file() {
+                continue;
+            }
+
+            let destination = self.object_path(&hash, Compression::None);
+            if destination == entry.path() {
+                continue;
+            }
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::rename(entry.path(), destination).await?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    async fn put_database(&self, source: &Path, force: bool) -> Result<()> {
+        copy_into(source, &self.database_path(), force).await
+    }
+
+    async fn get_database(&self, destination: &Path) -> Result<()> {
+        let source = self.database_path();
+        println!("copying: {}", source.display());
+        fs::copy(source, destination).await?;
+        Ok(())
+    }
+
+    async fn head_database(&self) -> Result<bool> {
+        Ok(fs::try_exists(self.database_path()).await?)
+    }
+}
+
+// Copies the file at source to destination. Like upload_multipart this
+// refuses to overwrite an existing file unless force is set.
+async fn copy_into(
+    source: &Path,
+    destination: &Path,
+    force: bool,
+) -> Result<()> {
+    if !force && fs::try_exists(destination).await? {
+        return Err(anyhow!("Skipping upload: File already exists."));
+    }
+
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    // Copy into a partial file and rename it into place so that an
+    // interrupted copy never leaves a truncated object behind.
+    let partial = partial_path(destination);
+    fs::copy(source, &partial).await?;
+    fs::rename(&partial, destination).await?;
+    Ok(())
+}
+
+// Name of a directory entry below the objects directory, skipping copies
+// that haven't finished
+fn object_file_name(entry: &fs::DirEntry) -> Option<String> {
+    let name = entry.file_name().to_str()?.to_string();
+    if name.ends_with(".partial") {
+        return None;
+    }
+    Some(name)
+}
+
+fn object_info(hash: String, metadata: &std::fs::Metadata) -> ObjectInfo {
+    ObjectInfo {
+        hash,
+        size_bytes: metadata.len() as i64,
+        last_modified: metadata.modified().ok(),
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".partial");
+    PathBuf::from(p)
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+use crate::backend::{ObjectBackend, ObjectInfo};
+use crate::types::compression::Compression;
+
+// Like S3, deleting more objects than this at once is refused
+const MAX_DELETE_BATCH: usize = 1000;
+
+/* A backend that keeps everything in memory, used to exercise remote code
+ * paths in tests.
+ */
+#[derive(Default)]
+pub struct MemoryBackend {
+    pub objects: Mutex<HashMap<String, (Compression, Vec<u8>)>>,
+    pub database: Mutex<Option<Vec<u8>>>,
+    // When objects were put, objects inserted directly have no time
+    pub last_modified: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ObjectBackend for MemoryBackend {
+    async fn put_object(
+        &self,
+        hash: &str,
+        compression: Compression,
+        source: &Path,
+        force: bool,
+    ) -> Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        if !force && objects.contains_key(hash) {
+            return Err(anyhow!("Skipping upload: File already exists."));
+        }
+        objects.insert(hash.to_string(), (compression, fs::read(source)?));
+        self.last_modified
+            .lock()
+            .unwrap()
+            .insert(hash.to_string(), SystemTime::now());
+        Ok(())
+    }
+
+    async fn get_object(
+        &self,
+        hash: &str,
+        destination: &Path,
+    ) -> Result<Compression> {
+        let objects = self.objects.lock().unwrap();
+        let (compression, contents) = objects
+            .get(hash)
+            .ok_or(anyhow!("object not found: {}", hash))?;
+        fs::write(destination, contents)?;
+        Ok(*compression)
+    }
+
+    async fn head_object(&self, hash: &str) -> Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(hash))
+    }
+
+    async fn list_objects(&self) -> Result<Vec<ObjectInfo>> {
+        let last_modified = self.last_modified.lock().unwrap();
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(hash, (_, contents))| ObjectInfo {
+                hash: hash.clone(),
+                size_bytes: contents.len() as i64,
+                last_modified: last_modified.get(hash).copied(),
+            })
+            .collect())
+    }
+
+    async fn delete_objects(&self, hashes: &[String]) -> Result<()> {
+        if hashes.len() > MAX_DELETE_BATCH {
+            return Err(anyhow!(
+                "can't delete {} objects at once, the most is {}",
+                hashes.len(),
+                MAX_DELETE_BATCH
+            ));
+        }
+        let mut objects = self.objects.lock().unwrap();
+        let mut last_modified = self.last_modified.lock().unwrap();
+        for hash in hashes {
+            objects.remove(hash);
+            last_modified.remove(hash);
+        }
+        Ok(())
+    }
+
+    // Objects in memory have no layout
+    async fn shard_objects(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    async fn put_database(&self, source: &Path, force: bool) -> Result<()> {
+        let mut database = self.database.lock().unwrap();
+        if !force && database.is_some() {
+            return Err(anyhow!("Skipping upload: File already exists."));
+        }
+        *database = Some(fs::read(source)?);
+        Ok(())
+    }
+
+    async fn get_database(&self, destination: &Path) -> Result<()> {
+        let database = self.database.lock().unwrap();
+        let contents = database
+            .as_ref()
+            .ok_or(anyhow!("remote database not found"))?;
+        fs::write(destination, contents)?;
+        Ok(())
+    }
+
+    async fn head_database(&self) -> Result<bool> {
+        Ok(self.database.lock().unwrap().is_some())
+    }
+}
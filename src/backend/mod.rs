@@ -0,0 +1,104 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::encryption;
+use crate::models::remote::Remote;
+use crate::remote::RemoteInfo;
+use crate::types::compression::Compression;
+use crate::types::remote_kind::RemoteKind;
+
+pub mod encrypted;
+pub mod local;
+#[cfg(test)]
+pub mod memory;
+pub mod s3;
+
+/* An object as seen by a backend: its hash along with the number of bytes
+ * it takes up in the backend, compressed or not, and when it was last
+ * written, if the backend knows.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub hash: String,
+    pub size_bytes: i64,
+    pub last_modified: Option<SystemTime>,
+}
+
+/* An ObjectBackend is the storage behind a remote.
+ *
+ * Remotes hold two kinds of things: objects, stored by the hash of their
+ * contents, and a single copy of the remote database. Everything sssync does
+ * with a remote is built on top of these operations so that adding a new kind
+ * of storage means implementing this trait and adding it to `for_remote`.
+ */
+#[async_trait]
+pub trait ObjectBackend: Send + Sync {
+    /* Store the file at source as the object hash, source holds the
+     * object's contents compressed with compression. If the object already
+     * exists this returns an error unless force is set.
+     */
+    async fn put_object(
+        &self,
+        hash: &str,
+        compression: Compression,
+        source: &Path,
+        force: bool,
+    ) -> Result<()>;
+
+    /* Write the object hash to the file at destination as it's stored,
+     * returning the compression it was stored with.
+     */
+    async fn get_object(
+        &self,
+        hash: &str,
+        destination: &Path,
+    ) -> Result<Compression>;
+
+    // Returns whether the object hash exists in the backend, in any
+    // compression
+    async fn head_object(&self, hash: &str) -> Result<bool>;
+
+    // Lists every object stored in the backend
+    async fn list_objects(&self) -> Result<Vec<ObjectInfo>>;
+
+    async fn delete_objects(&self, hashes: &[String]) -> Result<()>;
+
+    /* Move every object still stored in the flat layout to its fanned out
+     * key. Returns the number of objects moved.
+     */
+    async fn shard_objects(&self) -> Result<usize>;
+
+    /* Store the database found at source as the remote database. If a remote
+     * database already exists this returns an error unless force is set.
+     */
+    async fn put_database(&self, source: &Path, force: bool) -> Result<()>;
+
+    async fn get_database(&self, destination: &Path) -> Result<()>;
+
+    async fn head_database(&self) -> Result<bool>;
+}
+
+/* Build the backend matching the kind of the remote, encrypting everything
+ * that goes through it when the remote has an encryption key.
+ */
+pub async fn for_remote(remote: &Remote) -> Result<Box<dyn ObjectBackend>> {
+    let remote_info = RemoteInfo::from_url(&remote.location)?;
+
+    let backend: Box<dyn ObjectBackend> = match remote.kind {
+        RemoteKind::S3 => {
+            Box::new(s3::S3Backend::new(remote_info, &remote.s3).await)
+        }
+        RemoteKind::Local => Box::new(local::LocalBackend::new(remote_info)),
+    };
+
+    match &remote.encryption_key {
+        Some(source) => {
+            let key = encryption::load_key(source)?;
+            Ok(Box::new(encrypted::EncryptedBackend::new(backend, key)))
+        }
+        None => Ok(backend),
+    }
+}
@@ -0,0 +1,216 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::Client;
+
+use crate::backend::{ObjectBackend, ObjectInfo};
+use crate::models::remote::S3Settings;
+use crate::remote::RemoteInfo;
+use crate::s3;
+use crate::s3::upload_multipart::upload_multipart;
+use crate::types::compression::Compression;
+
+/* A backend storing objects and the remote database in an S3 bucket under
+ * the prefix of the remote url.
+ */
+pub struct S3Backend {
+    client: Client,
+    remote_info: RemoteInfo,
+}
+
+impl S3Backend {
+    pub async fn new(remote_info: RemoteInfo, settings: &S3Settings) -> Self {
+        Self {
+            client: s3::make_client(settings).await,
+            remote_info,
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<bool> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.remote_info.bucket)
+            .key(key)
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error().is_some_and(|se| se.is_not_found()) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Every key the object may be stored at, with its compression
+    fn object_keys(&self, hash: &str) -> [(String, Compression); 3] {
+        [
+            (self.remote_info.object_key(hash), Compression::None),
+            (
+                self.remote_info.encoded_object_key(hash, Compression::Zstd),
+                Compression::Zstd,
+            ),
+            (self.remote_info.flat_object_key(hash), Compression::None),
+        ]
+    }
+
+    async fn download(&self, key: &str, destination: &Path) -> Result<()> {
+        let mut file = File::create(destination)?;
+        s3::download_object(
+            &self.client,
+            &self.remote_info.bucket,
+            key,
+            &mut file,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectBackend for S3Backend {
+    async fn put_object(
+        &self,
+        hash: &str,
+        compression: Compression,
+        source: &Path,
+        force: bool,
+    ) -> Result<()> {
+        upload_multipart(
+            &self.client,
+            &self.remote_info.bucket,
+            &self.remote_info.encoded_object_key(hash, compression),
+            source,
+            force,
+        )
+        .await
+    }
+
+    async fn get_object(
+        &self,
+        hash: &str,
+        destination: &Path,
+    ) -> Result<Compression> {
+        // Try each key the object may be stored at in turn. Remotes that
+        // haven't been upgraded may still hold the object at its flat key.
+        let keys = self.object_keys(hash);
+        for (i, (key, compression)) in keys.iter().enumerate() {
+            match self.download(key, destination).await {
+                Ok(()) => return Ok(*compression),
+                Err(e) if is_no_such_key(&e) && i + 1 < keys.len() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(anyhow!("object not found: {}", hash))
+    }
+
+    async fn head_object(&self, hash: &str) -> Result<bool> {
+        for (key, _) in self.object_keys(hash) {
+            if self.head(&key).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn list_objects(&self) -> Result<Vec<ObjectInfo>> {
+        let prefix = self.remote_info.objects_prefix();
+        let objects =
+            s3::list_objects(&self.client, &self.remote_info.bucket, &prefix)
+                .await?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|o| {
+                // Keys in both layouts and any compression map back to
+                // the hash
+                let name = o.key()?.strip_prefix(&prefix)?;
+                let hash = Compression::from_name(name).0.replace('/', "");
+                Some(ObjectInfo {
+                    hash,
+                    size_bytes: o.size().unwrap_or_default(),
+                    last_modified: o
+                        .last_modified()
+                        .and_then(|t| SystemTime::try_from(*t).ok()),
+                })
+            })
+            .collect())
+    }
+
+    async fn delete_objects(&self, hashes: &[String]) -> Result<()> {
+        let keys: Vec<String> = hashes
+            .iter()
+            .flat_map(|h| self.object_keys(h).map(|(key, _)| key))
+            .collect();
+        s3::delete_objects(&self.client, &self.remote_info.bucket, &keys).await
+    }
+
+    async fn shard_objects(&self) -> Result<usize> {
+        let bucket = &self.remote_info.bucket;
+        let prefix = self.remote_info.objects_prefix();
+        let objects = s3::list_objects(&self.client, bucket, &prefix).await?;
+
+        let mut moved: Vec<String> = vec![];
+        for o in objects {
+            let Some(key) = o.key() else {
+                continue;
+            };
+            let Some(hash) = key.strip_prefix(&prefix) else {
+                continue;
+            };
+            let destination = self.remote_info.object_key(hash);
+            if hash.contains('/') || destination == key {
+                continue;
+            }
+
+            println!("moving: {} -> {}", key, destination);
+            s3::copy_object(
+                &self.client,
+                bucket,
+                key,
+                &destination,
+                o.size().unwrap_or_default(),
+            )
+            .await?;
+            moved.push(key.to_string());
+        }
+
+        s3::delete_objects(&self.client, bucket, &moved).await?;
+        Ok(moved.len())
+    }
+
+    async fn put_database(&self, source: &Path, force: bool) -> Result<()> {
+        upload_multipart(
+            &self.client,
+            &self.remote_info.bucket,
+            &self.remote_info.database_key(),
+            source,
+            force,
+        )
+        .await
+    }
+
+    async fn get_database(&self, destination: &Path) -> Result<()> {
+        self.download(&self.remote_info.database_key(), destination)
+            .await
+    }
+
+    async fn head_database(&self) -> Result<bool> {
+        self.head(&self.remote_info.database_key()).await
+    }
+}
+
+fn is_no_such_key(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<SdkError<GetObjectError>>()
+        .and_then(|e| e.as_service_error())
+        .is_some_and(|e| e.is_no_such_key())
+}
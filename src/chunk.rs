@@ -0,0 +1,318 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::config;
+use crate::db;
+use crate::hash::Hasher;
+use crate::models::chunk::Chunk;
+use crate::store;
+use crate::types::compression::Compression;
+
+/* Content defined chunking
+ *
+ * Large files can be stored as a list of chunks, each an object of its own,
+ * instead of as one object. The list, the file's manifest, is kept in the
+ * chunks table and travels to remotes with the database.
+ *
+ * Chunk boundaries are picked by the contents of the file rather than by
+ * offset. A rolling gear hash is kept over the bytes of the chunk and the
+ * chunk ends wherever its top bits are all zero, which depends only on the
+ * last 64 bytes read. Inserting or removing bytes moves the boundaries near
+ * the edit and no others, so the rest of the file maps to the same chunks as
+ * before and only the chunks around the change are stored and transferred
+ * again.
+ *
+ * Chunks are between MIN_CHUNK_SIZE and MAX_CHUNK_SIZE bytes long, around
+ * 2MB on average. Changing these or the gear table changes where boundaries
+ * fall: files already stored keep their manifests and still rebuild, but
+ * stop sharing chunks with files chunked afterward.
+ */
+pub const MIN_CHUNK_SIZE: usize = 512 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+// Past the minimum, a boundary is found on average every 2^21 bytes
+const BOUNDARY_BITS: u32 = 21;
+
+const GEAR: [u64; 256] = gear_table();
+
+// A table of random values, generated with splitmix64 from a fixed seed
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5353_5359_4e43;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/* Returns the length of the chunk at the start of data. data must hold at
+ * least MAX_CHUNK_SIZE bytes unless it's the end of the file.
+ */
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let mut fingerprint: u64 = 0;
+    for (i, byte) in data[..end].iter().enumerate().skip(MIN_CHUNK_SIZE) {
+        fingerprint = (fingerprint << 1).wrapping_add(GEAR[*byte as usize]);
+        if fingerprint >> (64 - BOUNDARY_BITS) == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+// Splits everything read from reader into chunks
+pub struct Chunker<R: Read> {
+    reader: R,
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            pending: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.pending.len() < MAX_CHUNK_SIZE {
+            let start = self.pending.len();
+            self.pending.resize(MAX_CHUNK_SIZE, 0);
+            match self.reader.read(&mut self.pending[start..]) {
+                Ok(read) => {
+                    self.pending.truncate(start + read);
+                    self.eof = read == 0;
+                }
+                Err(e) => {
+                    self.pending.truncate(start);
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let rest = self.pending.split_off(cut_point(&self.pending));
+        Some(Ok(std::mem::replace(&mut self.pending, rest)))
+    }
+}
+
+/* Puts the file at source into the store as hash, in chunks when it's at
+ * least chunking.min_size_bytes long and whole otherwise.
+ */
+pub fn insert(
+    connection: &Connection,
+    root_path: &Path,
+    hash: &str,
+    source: &Path,
+) -> Result<()> {
+    if is_stored(connection, root_path, hash)? {
+        return Ok(());
+    }
+
+    let compression = config::compression(connection)?;
+    store::record_compression(connection, compression)?;
+
+    let size_bytes = fs::metadata(source)?.len();
+    match config::chunking_min_size_bytes(connection)? {
+        // An empty file has no chunks, it's always stored whole
+        Some(min) if size_bytes >= min && size_bytes > 0 => {
+            insert_chunked(
+                connection,
+                &Hasher::for_repository(connection)?,
+                root_path,
+                hash,
+                source,
+                compression,
+            )?;
+            Ok(())
+        }
+        _ => store::insert_from(root_path, hash, source, compression),
+    }
+}
+
+// Stores each chunk of the file at source and records its manifest
+pub fn insert_chunked(
+    connection: &Connection,
+    hasher: &Hasher,
+    root_path: &Path,
+    hash: &str,
+    source: &Path,
+    compression: Compression,
+) -> Result<Vec<Chunk>> {
+    let mut manifest = vec![];
+    for (position, bytes) in Chunker::new(File::open(source)?).enumerate() {
+        let bytes = bytes?;
+        let chunk_hash = hasher.hash_bytes(&bytes);
+        store::insert_bytes(root_path, &chunk_hash, &bytes, compression)?;
+        manifest.push(Chunk {
+            file_hash: hash.to_string(),
+            position: position as i64,
+            chunk_hash,
+            size_bytes: bytes.len() as i64,
+        });
+    }
+    db::chunk::insert(connection, &manifest)?;
+    Ok(manifest)
+}
+
+// Whether the file hash can be rebuilt from the store, whole or in chunks
+pub fn is_stored(
+    connection: &Connection,
+    root_path: &Path,
+    hash: &str,
+) -> Result<bool> {
+    if store::exists(root_path, hash) {
+        return Ok(true);
+    }
+    let manifest = db::chunk::get(connection, hash)?;
+    Ok(!manifest.is_empty()
+        && manifest
+            .iter()
+            .all(|c| store::exists(root_path, &c.chunk_hash)))
+}
+
+/* The objects the file hash is stored as: its chunks when the database
+ * behind connection has a manifest for it, the file itself otherwise.
+ */
+pub fn file_objects(
+    connection: &Connection,
+    hash: &str,
+) -> Result<Vec<String>> {
+    let manifest = db::chunk::get(connection, hash)?;
+    if manifest.is_empty() {
+        return Ok(vec![hash.to_string()]);
+    }
+    Ok(manifest.into_iter().map(|c| c.chunk_hash).collect())
+}
+
+// The objects making up every one of the files, each listed once
+pub fn object_hashes<'a>(
+    connection: &Connection,
+    file_hashes: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<String>> {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut objects = vec![];
+    for file_hash in file_hashes {
+        for hash in file_objects(connection, file_hash)? {
+            if seen.insert(hash.clone()) {
+                objects.push(hash);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::types::checkout_strategy::CheckoutStrategy;
+
+    // Bytes that look random, so boundaries are found by content
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_edits_only_change_nearby_chunks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path().to_path_buf();
+        store::init(&root_path)?;
+        let connection = Connection::open_in_memory()?;
+        db::chunk::create_table(&connection)?;
+
+        let original = noise(1, 12 * 1024 * 1024);
+        let mut edited = original.clone();
+        edited.splice(5_000_000..5_000_000, b"a few new bytes".to_vec());
+
+        let original_path = root_path.join("original");
+        let edited_path = root_path.join("edited");
+        fs::write(&original_path, &original)?;
+        fs::write(&edited_path, &edited)?;
+        let hasher = Hasher::default();
+        let original_hash = hasher.hash_file(&original_path)?;
+        let edited_hash = hasher.hash_file(&edited_path)?;
+
+        let before = insert_chunked(
+            &connection,
+            &hasher,
+            &root_path,
+            &original_hash,
+            &original_path,
+            Compression::None,
+        )?;
+        let after = insert_chunked(
+            &connection,
+            &hasher,
+            &root_path,
+            &edited_hash,
+            &edited_path,
+            Compression::None,
+        )?;
+        assert!(before.len() > 1);
+        assert!(before
+            .iter()
+            .all(|c| c.size_bytes as usize <= MAX_CHUNK_SIZE));
+
+        let before_hashes: HashSet<&String> =
+            before.iter().map(|c| &c.chunk_hash).collect();
+        let new_chunks = after
+            .iter()
+            .filter(|c| !before_hashes.contains(&c.chunk_hash))
+            .count();
+        assert!(new_chunks <= 2, "{} new chunks", new_chunks);
+
+        assert!(is_stored(&connection, &root_path, &edited_hash)?);
+        let rebuilt = root_path.join("rebuilt");
+        store::export_to(
+            &connection,
+            &root_path,
+            &edited_hash,
+            &rebuilt,
+            CheckoutStrategy::Hardlink,
+        )?;
+        assert_eq!(fs::read(&rebuilt)?, edited);
+        Ok(())
+    }
+}
@@ -1,15 +1,21 @@
 use std::path::{Path, PathBuf};
+use std::thread;
 
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use rusqlite::Connection;
 
 use crate::actions::{
-    add, branch, clone, commit, diff, init, log, merge, migration, remote,
-    reset, status, tree,
+    add, branch, check_ignore, clone, commit, config, diff, fsck, gc, init,
+    log, merge, migration, mv, remote, reset, status, tree, upgrade, watch,
 };
+use crate::db;
 use crate::db::repo_db_path;
-use crate::store::get_root_path;
+use crate::migration::DEFAULT_JOBS;
+use crate::models::migration::MigrationState;
+use crate::models::remote::S3Settings;
+use crate::store::{check_format, get_root_path};
+use crate::types::hash_algorithm::HashAlgorithm;
 use crate::types::remote_kind::RemoteKind;
 
 #[derive(Parser, Debug)]
@@ -22,6 +28,39 @@ pub struct Cli {
     action: Action,
 }
 
+/* Settings for S3 compatible services (MinIO, Ceph, R2...). Left unset the
+ * standard AWS configuration from the environment is used.
+ */
+#[derive(Args, Debug)]
+pub struct S3Args {
+    /// Endpoint of an S3 compatible service, e.g. http://localhost:9000
+    #[arg(long)]
+    endpoint_url: Option<String>,
+
+    /// Region to sign requests for
+    #[arg(long)]
+    region: Option<String>,
+
+    /// Address buckets as part of the path instead of the host name
+    #[arg(long)]
+    path_style: bool,
+
+    /// Named profile from the AWS credentials file
+    #[arg(long)]
+    profile: Option<String>,
+}
+
+impl S3Args {
+    fn to_settings(&self) -> S3Settings {
+        S3Settings {
+            endpoint_url: self.endpoint_url.clone(),
+            region: self.region.clone(),
+            force_path_style: self.path_style,
+            profile: self.profile.clone(),
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Branch {
     /// Add a branch to the repository
@@ -46,6 +85,80 @@ pub enum Migration {
     List,
     /// Show the status of migration [id]
     Show { id: String },
+
+    /// Retry the unfinished transfers of migration [id] and complete the
+    /// command that created it
+    Resume {
+        id: String,
+
+        #[arg(long)]
+        force: bool,
+
+        /// Number of objects to transfer at the same time
+        #[arg(long, default_value_t = DEFAULT_JOBS)]
+        jobs: usize,
+    },
+
+    /// Delete old migrations and their transfers
+    Prune {
+        /// Only prune migrations created more than this many days ago
+        #[arg(long, default_value_t = 0)]
+        older_than_days: u64,
+
+        /// Only prune migrations in this state, may be repeated.
+        /// Defaults to complete and canceled migrations
+        #[arg(long, value_enum)]
+        state: Vec<MigrationState>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Config {
+    /// Print the value of a setting
+    Get {
+        key: String,
+
+        /// Use the config shared by every repository
+        #[arg(long)]
+        global: bool,
+    },
+
+    /// Change the value of a setting
+    Set {
+        key: String,
+        value: String,
+
+        /// Use the config shared by every repository
+        #[arg(long)]
+        global: bool,
+    },
+
+    /// Go back to the default value of a setting
+    Unset {
+        key: String,
+
+        /// Use the config shared by every repository
+        #[arg(long)]
+        global: bool,
+    },
+
+    /// List every setting
+    List {
+        /// Use the config shared by every repository
+        #[arg(long)]
+        global: bool,
+    },
+}
+
+impl Config {
+    fn global(&self) -> bool {
+        match self {
+            Config::Get { global, .. }
+            | Config::Set { global, .. }
+            | Config::Unset { global, .. }
+            | Config::List { global } => *global,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -55,7 +168,7 @@ pub enum Remote {
         /// Name of the remote
         name: String,
 
-        /// Only s3 for now
+        /// Either s3 or local
         #[arg(long)]
         #[arg(value_enum)]
         kind: RemoteKind,
@@ -63,6 +176,14 @@ pub enum Remote {
         /// URL referencing where the remote will exist
         #[arg(long)]
         location: String,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Encrypt everything stored in the remote with the key found at
+        /// file:<path> or env:<name>
+        #[arg(long, value_name = "SOURCE")]
+        encryption_key: Option<String>,
     },
     /// List all the remotes
     List,
@@ -74,10 +195,20 @@ pub enum Remote {
 
         #[arg(long)]
         force: bool,
+
+        /// Number of objects to transfer at the same time
+        #[arg(long, default_value_t = DEFAULT_JOBS)]
+        jobs: usize,
     },
 
     /// Push latest change to the remote
-    Push { name: String },
+    Push {
+        name: String,
+
+        /// Number of objects to transfer at the same time
+        #[arg(long, default_value_t = DEFAULT_JOBS)]
+        jobs: usize,
+    },
 
     /// Fetches just the remote database
     FetchRemoteDB { name: String },
@@ -92,7 +223,13 @@ pub enum Remote {
     },
 
     /// Fetch the remote database and objects
-    Fetch { name: String },
+    Fetch {
+        name: String,
+
+        /// Number of objects to transfer at the same time
+        #[arg(long, default_value_t = DEFAULT_JOBS)]
+        jobs: usize,
+    },
 
     /// Remove a remote
     Remove { name: String },
@@ -109,6 +246,25 @@ pub enum Remote {
 
     /// List all remote branches
     Branches { name: String },
+
+    /// Move the remote's objects to the current layout
+    Upgrade { name: String },
+
+    /// Write a new random encryption key to a file
+    GenerateKey { path: PathBuf },
+
+    /// Delete objects no branch of the remote reaches
+    Gc {
+        name: String,
+
+        /// Only list the objects that would be deleted
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Keep unreachable objects uploaded within this many days
+        #[arg(long, default_value_t = gc::DEFAULT_GRACE_PERIOD_DAYS)]
+        grace_period_days: u64,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -131,25 +287,65 @@ pub enum Action {
         action: Migration,
     },
 
+    /// Subcommands to manage repository settings
+    Config {
+        #[command(subcommand)]
+        action: Config,
+    },
+
     /// Initialize a new repository
-    Init { path: PathBuf },
+    Init {
+        path: PathBuf,
+
+        /// Hash function naming objects and commits, fixed once the
+        /// repository is created
+        #[arg(long, value_enum, default_value_t = HashAlgorithm::Xxh3)]
+        hash: HashAlgorithm,
+    },
 
     /// Add files to be staged
-    Add { path: PathBuf },
+    Add {
+        path: PathBuf,
+
+        /// Number of files to hash at the same time, defaults to the
+        /// number of cores
+        #[arg(long)]
+        jobs: Option<usize>,
+    },
+
+    /// Move a tracked file or directory and stage the move, without
+    /// hashing it again
+    Mv { from: PathBuf, to: PathBuf },
 
     /// Commit changes to a repository
     Commit {
         #[arg(long, required = true)]
         message: String,
+
+        /// Record someone else as the author, as "name <email>"
+        #[arg(long)]
+        author: Option<String>,
     },
 
     /// Clone the remote located at [url] to destination [path]
     Clone {
-        /// location of the remote. For now only supports s3 urls
+        /// location of the remote, either an s3:// or a local:// url
         url: String,
 
         /// Destination to clone into
         path: PathBuf,
+
+        #[command(flatten)]
+        s3: S3Args,
+
+        /// Encrypt everything stored in the remote with the key found at
+        /// file:<path> or env:<name>
+        #[arg(long, value_name = "SOURCE")]
+        encryption_key: Option<String>,
+
+        /// Number of objects to transfer at the same time
+        #[arg(long, default_value_t = DEFAULT_JOBS)]
+        jobs: usize,
     },
 
     /// Show the list of commits starting at HEAD
@@ -162,17 +358,59 @@ pub enum Action {
 
         #[arg(long)]
         remote: Option<String>,
+
+        /// Show the files each commit added, renamed, copied, changed and
+        /// removed
+        #[arg(long)]
+        files: bool,
     },
 
     /// Show the status of the repository
     Status,
 
+    /// Show whether each path is ignored and the .sssyncignore rule that
+    /// decides it
+    CheckIgnore {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Keep track of changes to the working directory so status doesn't
+    /// have to scan it, until killed
+    Watch,
+
     /// Print a representation of the tree at hash
     Tree { hash: String },
 
     /// Print what files are different between HEAD and [hash]
     Diff { hash: String },
 
+    /// Check the store and database for missing, corrupt and orphaned
+    /// objects and for dangling commits and refs
+    Fsck {
+        /// Download missing and corrupt objects again from this remote
+        #[arg(long)]
+        refetch: Option<String>,
+
+        /// Number of objects to transfer at the same time
+        #[arg(long, default_value_t = DEFAULT_JOBS)]
+        jobs: usize,
+    },
+
+    /// Remove objects that are no longer reachable from the store
+    Gc {
+        /// Only list the objects that would be removed
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Keep unreachable objects added to the store within this many days
+        #[arg(long, default_value_t = gc::DEFAULT_GRACE_PERIOD_DAYS)]
+        grace_period_days: u64,
+    },
+
+    /// Bring the repository up to the current format
+    Upgrade,
+
     /// Clears currently staged changes
     Reset {
         #[arg(long, action)]
@@ -197,23 +435,45 @@ pub fn run() -> Result<()> {
     // Init isn't expected to be run with a valid root_path. We're special
     // casing init so that we can provide convenient access to root_path for
     // all the other commands.
-    if let Action::Init { path } = &cli.action {
-        init::init(path)?;
+    if let Action::Init { path, hash } = &cli.action {
+        init::init(path, *hash)?;
         return Ok(());
     }
 
     // Clone isn't expected to be run with a valid root_path. We're special
     // casing init so that we can provide convenient access to root_path for
     // all the other commands.
-    if let Action::Clone { url, path } = &cli.action {
+    if let Action::Clone {
+        url,
+        path,
+        s3,
+        encryption_key,
+        jobs,
+    } = &cli.action
+    {
         let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(clone::clone(url, path))?;
+        rt.block_on(clone::clone(
+            url,
+            path,
+            &s3.to_settings(),
+            encryption_key.clone(),
+            *jobs,
+        ))?;
         return Ok(());
     }
 
+    // The global config doesn't belong to any repository
+    if let Action::Config { action } = &cli.action {
+        if action.global() {
+            return run_config(&crate::config::open_global()?, action);
+        }
+    }
+
     let root_path = get_root_path(&pwd)
         .ok_or(anyhow!("not in a sssync'd directory: {}", pwd.display()))?;
     let connection = Connection::open(repo_db_path(root_path))?;
+    db::upgrade(&connection)?;
+    check_format(&connection)?;
 
     match &cli.action {
         Action::Remote { action } => match action {
@@ -221,29 +481,44 @@ pub fn run() -> Result<()> {
                 name,
                 kind,
                 location,
+                s3,
+                encryption_key,
             } => {
                 println!("Adding remote: {}", name);
-                remote::add(&connection, name, kind, location)
+                remote::add(
+                    &connection,
+                    name,
+                    kind,
+                    location,
+                    s3.to_settings(),
+                    encryption_key.clone(),
+                )
             }
             Remote::List => remote::list(&connection),
-            Remote::Init { name, force } => {
+            Remote::Init { name, force, jobs } => {
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(remote::init(
                     &connection,
                     root_path,
                     name,
                     *force,
+                    *jobs,
                 ))?;
                 Ok(())
             }
-            Remote::Push { name } => {
+            Remote::Push { name, jobs } => {
                 let rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(remote::push(&connection, root_path, name))?;
+                rt.block_on(remote::push(&connection, root_path, name, *jobs))?;
                 Ok(())
             }
-            Remote::Fetch { name } => {
+            Remote::Fetch { name, jobs } => {
                 let rt = tokio::runtime::Runtime::new().unwrap();
-                rt.block_on(remote::fetch(&connection, root_path, name))?;
+                rt.block_on(remote::fetch(
+                    &connection,
+                    root_path,
+                    name,
+                    *jobs,
+                ))?;
                 Ok(())
             }
             Remote::FetchRemoteDB { name } => {
@@ -270,13 +545,32 @@ pub fn run() -> Result<()> {
                 Ok(())
             }
             Remote::Locate { name, path } => {
-                remote::locate(&connection, name, path)?;
+                remote::locate(&connection, root_path, name, path)?;
                 Ok(())
             }
             Remote::Branches { name } => {
                 remote::branch_list(&connection, root_path, name)?;
                 Ok(())
             }
+            Remote::Upgrade { name } => {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(remote::upgrade(&connection, root_path, name))
+            }
+            Remote::GenerateKey { path } => remote::generate_key(path),
+            Remote::Gc {
+                name,
+                dry_run,
+                grace_period_days,
+            } => {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(remote::gc(
+                    &connection,
+                    root_path,
+                    name,
+                    *grace_period_days,
+                    *dry_run,
+                ))
+            }
         },
         Action::Branch { action } => match action {
             Branch::Add { name, hash } => {
@@ -295,42 +589,93 @@ pub fn run() -> Result<()> {
                 Ok(())
             }
             Migration::Show { id } => migration::show(&connection, id),
+            Migration::Resume { id, force, jobs } => {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(migration::resume(
+                    &connection,
+                    root_path,
+                    id,
+                    *force,
+                    *jobs,
+                ))?;
+                Ok(())
+            }
+            Migration::Prune {
+                older_than_days,
+                state,
+            } => migration::prune(&connection, *older_than_days, state),
         },
-        Action::Commit { message } => {
-            commit::commit(&connection, root_path, &message)
+        Action::Config { action } => run_config(&connection, action),
+        Action::Commit { message, author } => {
+            commit::commit(&connection, root_path, message, author.as_deref())
         }
-        Action::Clone { url, path } => {
+        Action::Clone { url, path, .. } => {
             println!("Action::Clone {} {}", url, path.display());
             Ok(())
         }
+        Action::Mv { from, to } => mv::mv(
+            &connection,
+            root_path,
+            &relative_to_root(root_path, &pwd.join(from))?,
+            &relative_to_root(root_path, &pwd.join(to))?,
+        ),
         Action::Status => {
             status::status(&connection, root_path)?;
             Ok(())
         }
-        Action::Init { path } => {
+        Action::CheckIgnore { paths } => {
+            let rel_paths = paths
+                .iter()
+                .map(|p| relative_to_root(root_path, &pwd.join(p)))
+                .collect::<Result<Vec<PathBuf>>>()?;
+            let rel_paths: Vec<&Path> =
+                rel_paths.iter().map(|p| p.as_path()).collect();
+            check_ignore::check_ignore(root_path, &rel_paths)
+        }
+        Action::Watch => watch::watch(&connection, root_path),
+        Action::Init { path, .. } => {
             // This isn't expected to be run ever, it's special cased at the
             // start but keeping it here means we still get type
             // checking on enum coverage.
             println!("Action::Init {}", path.display());
             Ok(())
         }
-        Action::Add { path } => {
-            let cp = path.canonicalize()?;
-            let rel_path = cp.strip_prefix(root_path)?;
-            add::add(&connection, root_path, rel_path)
+        Action::Add { path, jobs } => {
+            let rel_path = relative_to_root(root_path, &pwd.join(path))?;
+            let jobs = match jobs {
+                Some(jobs) => *jobs,
+                None => thread::available_parallelism()?.get(),
+            };
+            add::add(&connection, root_path, &rel_path, jobs)
         }
         Action::Log {
             hash,
             branch,
             remote,
+            files,
         } => log::log(
             &connection,
             root_path,
             hash.clone(),
             branch.clone(),
             remote.clone(),
+            *files,
         ),
         Action::Diff { hash } => diff::diff(&connection, hash),
+        Action::Fsck { refetch, jobs } => {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(fsck::fsck(
+                &connection,
+                root_path,
+                refetch.as_deref(),
+                *jobs,
+            ))
+        }
+        Action::Gc {
+            dry_run,
+            grace_period_days,
+        } => gc::gc(&connection, root_path, *grace_period_days, *dry_run),
+        Action::Upgrade => upgrade::upgrade(&connection, root_path),
         Action::Reset { hard } => reset::reset(&connection, root_path, *hard),
         Action::Tree { hash } => tree::tree(&connection, hash),
         Action::Merge { branch, remote } => {
@@ -341,3 +686,100 @@ pub fn run() -> Result<()> {
         }
     }
 }
+
+fn run_config(connection: &Connection, action: &Config) -> Result<()> {
+    let global = action.global();
+    match action {
+        Config::Get { key, .. } => config::get(connection, key, global),
+        Config::Set { key, value, .. } => {
+            config::set(connection, key, value, global)
+        }
+        Config::Unset { key, .. } => config::unset(connection, key, global),
+        Config::List { .. } => config::list(connection, global),
+    }
+}
+
+/* The path relative to the root of the repository. The path doesn't have to
+ * exist, only its parent directory does. The path itself is never resolved,
+ * with the symlink checkout strategy files are symlinks into the store.
+ */
+fn relative_to_root(root_path: &Path, path: &Path) -> Result<PathBuf> {
+    let absolute = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent.canonicalize()?.join(name),
+        _ => path.canonicalize()?,
+    };
+    Ok(absolute
+        .strip_prefix(root_path)
+        .map_err(|_| anyhow!("{} is outside the repository", path.display()))?
+        .to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_relative_to_root_keeps_symlinks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path();
+        let object_dir = root_path.join(".sssync/objects/ab");
+        fs::create_dir_all(&object_dir)?;
+        fs::create_dir_all(root_path.join("renders"))?;
+
+        // A file checked out with the symlink strategy
+        fs::write(object_dir.join("cdef"), "contents")?;
+        std::os::unix::fs::symlink(
+            object_dir.join("cdef"),
+            root_path.join("renders/a.exr"),
+        )?;
+
+        assert_eq!(
+            relative_to_root(root_path, &root_path.join("renders/a.exr"))?,
+            PathBuf::from("renders/a.exr")
+        );
+        assert_eq!(
+            relative_to_root(root_path, &root_path.join("renders/new"))?,
+            PathBuf::from("renders/new")
+        );
+        assert_eq!(
+            relative_to_root(root_path, &root_path.join("renders/."))?,
+            PathBuf::from("renders")
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_add_symlink_checkout() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path();
+        crate::store::init(root_path)?;
+        let connection = Connection::open(repo_db_path(root_path))?;
+        db::init(&connection, HashAlgorithm::default())?;
+        db::config::set(&connection, crate::config::USER_NAME, "Ada")?;
+
+        fs::write(root_path.join("a.txt"), "contents")?;
+        add::add(&connection, root_path, Path::new("a.txt"), 1)?;
+        commit::commit(&connection, root_path, "add a", None)?;
+        let file_hash =
+            db::tree::get_by_path(&connection, Path::new("a.txt"))?.file_hash;
+
+        // Checked out again as a symlink into the store
+        fs::remove_file(root_path.join("a.txt"))?;
+        crate::store::export_to(
+            &connection,
+            root_path,
+            &file_hash,
+            &root_path.join("a.txt"),
+            crate::types::checkout_strategy::CheckoutStrategy::Symlink,
+        )?;
+        assert!(fs::symlink_metadata(root_path.join("a.txt"))?.is_symlink());
+
+        let rel_path = relative_to_root(root_path, &root_path.join("a.txt"))?;
+        assert_eq!(rel_path, PathBuf::from("a.txt"));
+        add::add(&connection, root_path, &rel_path, 1)?;
+        // It's unchanged, there's nothing to stage
+        assert!(db::staging::get_all(&connection)?.is_empty());
+        Ok(())
+    }
+}
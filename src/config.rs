@@ -0,0 +1,266 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+
+use crate::db;
+use crate::types::checkout_strategy::CheckoutStrategy;
+use crate::types::compression::Compression;
+
+/* Repository settings live in the config table as plain strings. Every
+ * setting sssync understands is listed in KEYS along with a check of the
+ * values it accepts, `sssync config set` refuses anything else.
+ */
+
+/* How many days finished migrations are kept before they are pruned
+ * automatically, or "never" to keep them forever.
+ */
+pub const MIGRATION_RETENTION_DAYS: &str = "migration.retention_days";
+pub const DEFAULT_MIGRATION_RETENTION_DAYS: u64 = 30;
+
+/* How files are written from the store into the working directory, one of
+ * copy, hardlink, reflink or symlink.
+ */
+pub const CHECKOUT_STRATEGY: &str = "checkout.strategy";
+
+/* Files at least this many bytes are stored in chunks, or "never" to always
+ * store files whole.
+ */
+pub const CHUNKING_MIN_SIZE_BYTES: &str = "chunking.min_size_bytes";
+
+/* How objects are compressed when they're written to the store, none or
+ * zstd. Remotes receive objects the way they're stored.
+ */
+pub const OBJECTS_COMPRESSION: &str = "objects.compression";
+
+/* Who is making commits, recorded as their author and committer. These can
+ * also be set for every repository at once with --global.
+ */
+pub const USER_NAME: &str = "user.name";
+pub const USER_EMAIL: &str = "user.email";
+
+pub struct Key {
+    pub name: &'static str,
+    // Whether the setting can also be made in the global config
+    pub global: bool,
+    pub description: &'static str,
+    validate: fn(&str) -> Result<()>,
+}
+
+pub const KEYS: &[Key] = &[
+    Key {
+        name: MIGRATION_RETENTION_DAYS,
+        global: false,
+        description: "days to keep finished migrations, or \"never\" to \
+                      keep them forever",
+        validate: |v| parse_retention_days(v).map(|_| ()),
+    },
+    Key {
+        name: CHECKOUT_STRATEGY,
+        global: false,
+        description: "how files are written into the working directory: \
+                      copy, hardlink, reflink or symlink",
+        validate: |v| CheckoutStrategy::parse(v).map(|_| ()),
+    },
+    Key {
+        name: CHUNKING_MIN_SIZE_BYTES,
+        global: false,
+        description: "store files of at least this many bytes in chunks, \
+                      or \"never\" to store every file whole",
+        validate: |v| parse_min_size_bytes(v).map(|_| ()),
+    },
+    Key {
+        name: OBJECTS_COMPRESSION,
+        global: false,
+        description: "how objects are compressed in the store and in \
+                      remotes: none or zstd",
+        validate: |v| Compression::parse(v).map(|_| ()),
+    },
+    Key {
+        name: USER_NAME,
+        global: true,
+        description: "name recorded as the author of new commits",
+        validate: validate_identity,
+    },
+    Key {
+        name: USER_EMAIL,
+        global: true,
+        description: "email recorded along with user.name",
+        validate: validate_identity,
+    },
+];
+
+fn find(key: &str) -> Result<&'static Key> {
+    KEYS.iter()
+        .find(|k| k.name == key)
+        .ok_or(anyhow!("unknown config key: {}", key))
+}
+
+pub fn validate_key(key: &str) -> Result<()> {
+    find(key).map(|_| ())
+}
+
+pub fn validate(key: &str, value: &str) -> Result<()> {
+    (find(key)?.validate)(value)
+}
+
+pub fn validate_global_key(key: &str) -> Result<()> {
+    if find(key)?.global {
+        Ok(())
+    } else {
+        Err(anyhow!("{} can only be set per repository", key))
+    }
+}
+
+/* Settings shared by every repository of the user are kept in a database of
+ * their own at $XDG_CONFIG_HOME/sssync/config.db, ~/.config/sssync/config.db
+ * when that's not set. Only keys marked global can be set there, and a
+ * repository's own setting wins over the global one.
+ */
+pub fn global_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("sssync").join("config.db"))
+}
+
+// Opens the global config, creating it if it doesn't exist yet
+pub fn open_global() -> Result<Connection> {
+    let path = global_path()
+        .ok_or(anyhow!("can't find the global config, HOME isn't set"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let connection = Connection::open(&path)?;
+    db::config::upgrade(&connection)?;
+    Ok(connection)
+}
+
+// The repository's setting for key, or the global one if it has none
+fn get_layered(connection: &Connection, key: &str) -> Result<Option<String>> {
+    if let Some(value) = db::config::get(connection, key)? {
+        return Ok(Some(value));
+    }
+    match global_path() {
+        Some(path) if path.exists() => db::config::get(&open_global()?, key),
+        _ => Ok(None),
+    }
+}
+
+fn parse_retention_days(value: &str) -> Result<Option<u64>> {
+    if value == "never" {
+        return Ok(None);
+    }
+    let days = value.parse::<u64>().map_err(|_| {
+        anyhow!("expected a number of days or \"never\", got: {}", value)
+    })?;
+    Ok(Some(days))
+}
+
+/* Returns the number of days finished migrations are kept for, None when
+ * they are kept forever.
+ */
+pub fn migration_retention_days(
+    connection: &Connection,
+) -> Result<Option<u64>> {
+    match db::config::get(connection, MIGRATION_RETENTION_DAYS)? {
+        Some(value) => parse_retention_days(&value),
+        None => Ok(Some(DEFAULT_MIGRATION_RETENTION_DAYS)),
+    }
+}
+
+pub fn checkout_strategy(connection: &Connection) -> Result<CheckoutStrategy> {
+    match db::config::get(connection, CHECKOUT_STRATEGY)? {
+        Some(value) => CheckoutStrategy::parse(&value),
+        None => Ok(CheckoutStrategy::default()),
+    }
+}
+
+fn parse_min_size_bytes(value: &str) -> Result<Option<u64>> {
+    if value == "never" {
+        return Ok(None);
+    }
+    let bytes = value.parse::<u64>().map_err(|_| {
+        anyhow!("expected a number of bytes or \"never\", got: {}", value)
+    })?;
+    Ok(Some(bytes))
+}
+
+/* Returns the size from which files are stored in chunks, None when files
+ * are always stored whole. Chunking is off unless it's been configured.
+ */
+pub fn chunking_min_size_bytes(connection: &Connection) -> Result<Option<u64>> {
+    match db::config::get(connection, CHUNKING_MIN_SIZE_BYTES)? {
+        Some(value) => parse_min_size_bytes(&value),
+        None => Ok(None),
+    }
+}
+
+pub fn compression(connection: &Connection) -> Result<Compression> {
+    match db::config::get(connection, OBJECTS_COMPRESSION)? {
+        Some(value) => Compression::parse(&value),
+        None => Ok(Compression::default()),
+    }
+}
+
+fn validate_identity(value: &str) -> Result<()> {
+    if value.trim().is_empty() {
+        return Err(anyhow!("expected a value, got nothing"));
+    }
+    if value.contains(['<', '>', '\n']) {
+        return Err(anyhow!("can't contain <, > or a newline: {}", value));
+    }
+    Ok(())
+}
+
+/* Who's making commits, as "name <email>", from the repository's config or
+ * the global one. When no name has been set commits are still made, by the
+ * login name at the host name, with a warning.
+ */
+pub fn identity(connection: &Connection) -> Result<String> {
+    let Some(name) = get_layered(connection, USER_NAME)? else {
+        let fallback = fallback_identity();
+        eprintln!(
+            "warning: no identity set, committing as {}, run `sssync config \
+             set --global {} <name>` and `sssync config set --global {} \
+             <email>`",
+            fallback, USER_NAME, USER_EMAIL
+        );
+        return Ok(fallback);
+    };
+    match get_layered(connection, USER_EMAIL)? {
+        Some(email) => Ok(format!("{} <{}>", name, email)),
+        None => Ok(name),
+    }
+}
+
+// $USER <$USER@hostname>, or "unknown" for whatever can't be found
+fn fallback_identity() -> String {
+    let user = env::var("USER")
+        .ok()
+        .filter(|u| validate_identity(u).is_ok())
+        .unwrap_or(String::from("unknown"));
+    match hostname() {
+        Some(host) => format!("{} <{}@{}>", user, user, host),
+        None => user,
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    let result = unsafe {
+        libc::gethostname(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    let end = buffer.iter().position(|b| *b == 0)?;
+    let host = String::from_utf8_lossy(&buffer[..end]).to_string();
+    validate_identity(&host).is_ok().then_some(host)
+}
@@ -0,0 +1,147 @@
+use anyhow::Result;
+use rusqlite::params;
+use rusqlite::Connection;
+
+use crate::db::has_table;
+use crate::models::chunk::Chunk;
+
+/* The manifests of files stored in chunks: for every such file, the hash of
+ * each of its chunks in order. Files stored whole have no rows here.
+ */
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        CREATE TABLE
+            chunks (
+                file_hash TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                chunk_hash TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                PRIMARY KEY (file_hash, position)
+            )
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
+// Repositories created before files could be chunked have no chunks table
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if has_table(connection, "chunks")? {
+        return Ok(());
+    }
+    create_table(connection)
+}
+
+// Manifests never change once written, inserting one again is a no-op
+pub fn insert(connection: &Connection, chunks: &[Chunk]) -> Result<()> {
+    let mut statement = connection.prepare(
+        "
+        INSERT OR IGNORE INTO
+            chunks (file_hash, position, chunk_hash, size_bytes)
+        VALUES
+            (?1, ?2, ?3, ?4)
+        ",
+    )?;
+    for chunk in chunks {
+        statement.execute(params![
+            chunk.file_hash,
+            chunk.position,
+            chunk.chunk_hash,
+            chunk.size_bytes
+        ])?;
+    }
+    Ok(())
+}
+
+fn row_to_chunk(row: &rusqlite::Row) -> Result<Chunk, rusqlite::Error> {
+    Ok(Chunk {
+        file_hash: row.get(0)?,
+        position: row.get(1)?,
+        chunk_hash: row.get(2)?,
+        size_bytes: row.get(3)?,
+    })
+}
+
+/* Returns the manifest of the file file_hash, empty when the file is
+ * stored whole. Remote databases written before chunking may not have the
+ * table at all.
+ */
+pub fn get(connection: &Connection, file_hash: &str) -> Result<Vec<Chunk>> {
+    if !has_table(connection, "chunks")? {
+        return Ok(vec![]);
+    }
+
+    let mut statement = connection.prepare(
+        "
+        SELECT
+            file_hash, position, chunk_hash, size_bytes
+        FROM
+            chunks
+        WHERE
+            file_hash = ?1
+        ORDER BY
+            position
+        ",
+    )?;
+
+    let result: Vec<Chunk> = statement
+        .query_map(params![file_hash], row_to_chunk)?
+        .flatten()
+        .collect();
+    Ok(result)
+}
+
+pub fn get_all(connection: &Connection) -> Result<Vec<Chunk>> {
+    if !has_table(connection, "chunks")? {
+        return Ok(vec![]);
+    }
+
+    let mut statement = connection.prepare(
+        "
+        SELECT
+            file_hash, position, chunk_hash, size_bytes
+        FROM
+            chunks
+        ",
+    )?;
+
+    let result: Vec<Chunk> = statement
+        .query_map(params![], row_to_chunk)?
+        .flatten()
+        .collect();
+    Ok(result)
+}
+
+/* Rename the object old_hash to new_hash wherever it appears in a manifest,
+ * as the file being chunked or as one of its chunks.
+ */
+pub fn replace_hash(
+    connection: &Connection,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            chunks
+        SET
+            file_hash = ?2
+        WHERE
+            file_hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    connection.execute(
+        "
+        UPDATE
+            chunks
+        SET
+            chunk_hash = ?2
+        WHERE
+            chunk_hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    Ok(())
+}
@@ -4,6 +4,7 @@ use rusqlite::params;
 use rusqlite::Connection;
 use rusqlite::OptionalExtension;
 
+use crate::db::{has_column, has_table};
 use crate::models::commit::Commit;
 
 pub fn create_table(connection: &Connection) -> Result<()> {
@@ -15,7 +16,8 @@ pub fn create_table(connection: &Connection) -> Result<()> {
                 message TEXT NOT NULL,
                 author TEXT NOT NULL,
                 created_unix_timestamp INTEGER NOT NULL,
-                parent_hash TEXT
+                parent_hash TEXT,
+                committer TEXT NOT NULL DEFAULT ''
             )
         ",
         params![],
@@ -23,6 +25,24 @@ pub fn create_table(connection: &Connection) -> Result<()> {
     Ok(())
 }
 
+// Commits from before committers were recorded have an empty committer
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if !has_table(connection, "commits")?
+        || has_column(connection, "commits", "committer")?
+    {
+        return Ok(());
+    }
+    connection.execute(
+        "
+        ALTER TABLE
+            commits
+        ADD COLUMN committer TEXT NOT NULL DEFAULT ''
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
 pub fn insert(connection: &Connection, commit: &Commit) -> Result<()> {
     connection.execute(
         "
@@ -32,10 +52,11 @@ pub fn insert(connection: &Connection, commit: &Commit) -> Result<()> {
                 message,
                 author,
                 created_unix_timestamp,
-                parent_hash
+                parent_hash,
+                committer
             )
         VALUES
-            (?1, ?2, ?3, ?4, ?5)
+            (?1, ?2, ?3, ?4, ?5, ?6)
         ",
         params![
             commit.hash,
@@ -43,6 +64,7 @@ pub fn insert(connection: &Connection, commit: &Commit) -> Result<()> {
             commit.author,
             commit.created_unix_timestamp,
             commit.parent_hash,
+            commit.committer,
         ],
     )?;
     Ok(())
@@ -55,7 +77,8 @@ pub fn get(
     connection.query_row(
         "
         SELECT
-            hash, message, author, created_unix_timestamp, parent_hash
+            hash, message, author, created_unix_timestamp, parent_hash,
+            committer
         FROM
             commits
         WHERE
@@ -69,6 +92,7 @@ pub fn get(
                 author: row.get(2)?,
                 created_unix_timestamp: row.get(3)?,
                 parent_hash: row.get(4)?,
+                committer: row.get(5)?,
             })
         },
     )
@@ -78,7 +102,8 @@ pub fn get_all(connection: &Connection) -> Result<Vec<Commit>> {
     let mut statement = connection.prepare(
         "
         SELECT
-            hash, message, author, created_unix_timestamp, parent_hash
+            hash, message, author, created_unix_timestamp, parent_hash,
+            committer
         FROM
             commits
         ",
@@ -92,6 +117,7 @@ pub fn get_all(connection: &Connection) -> Result<Vec<Commit>> {
                 author: row.get(2)?,
                 created_unix_timestamp: row.get(3)?,
                 parent_hash: row.get(4)?,
+                committer: row.get(5)?,
             })
         })?
         .into_iter()
@@ -107,10 +133,18 @@ pub fn get_children(
     let mut statement = connection.prepare(
         "
         WITH RECURSIVE
-            log (hash, message, author, created_unix_timestamp, parent_hash)
+            log (
+                hash,
+                message,
+                author,
+                created_unix_timestamp,
+                parent_hash,
+                committer
+            )
         AS (
             SELECT
-                c.hash, c.message, c.author, c.created_unix_timestamp, c.parent_hash
+                c.hash, c.message, c.author, c.created_unix_timestamp, c.parent_hash,
+                c.committer
             FROM
                 commits c
             WHERE
@@ -119,14 +153,16 @@ pub fn get_children(
             UNION
 
             SELECT
-                c.hash, c.message, c.author, c.created_unix_timestamp, c.parent_hash
+                c.hash, c.message, c.author, c.created_unix_timestamp, c.parent_hash,
+                c.committer
             FROM
                 commits c, log l
             WHERE 
                 c.hash = l.parent_hash
         )
         SELECT
-            hash, message, author, created_unix_timestamp, parent_hash
+            hash, message, author, created_unix_timestamp, parent_hash,
+            committer
         FROM
             log
         ",
@@ -140,6 +176,7 @@ pub fn get_children(
                 author: row.get(2)?,
                 created_unix_timestamp: row.get(3)?,
                 parent_hash: row.get(4)?,
+                committer: row.get(5)?,
             })
         })?
         .into_iter()
@@ -156,7 +193,8 @@ pub fn get_by_ref_name(
         .query_row(
             "
         SELECT
-            c.hash, c.message, c.author, c.created_unix_timestamp, c.parent_hash
+            c.hash, c.message, c.author, c.created_unix_timestamp, c.parent_hash,
+                c.committer
         FROM
             commits AS c
         JOIN
@@ -174,6 +212,7 @@ pub fn get_by_ref_name(
                     author: row.get(2)?,
                     created_unix_timestamp: row.get(3)?,
                     parent_hash: row.get(4)?,
+                committer: row.get(5)?,
                 })
             },
         )
@@ -181,3 +220,34 @@ pub fn get_by_ref_name(
 
     Ok(result)
 }
+
+// Rename the commit old_hash to new_hash, along with its children's parent
+pub fn replace_hash(
+    connection: &Connection,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            commits
+        SET
+            hash = ?2
+        WHERE
+            hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    connection.execute(
+        "
+        UPDATE
+            commits
+        SET
+            parent_hash = ?2
+        WHERE
+            parent_hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use rusqlite::params;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::db::has_table;
+
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        CREATE TABLE
+            config (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
+// Repositories created before settings existed have no config table
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if has_table(connection, "config")? {
+        return Ok(());
+    }
+    create_table(connection)
+}
+
+pub fn set(connection: &Connection, key: &str, value: &str) -> Result<()> {
+    connection.execute(
+        "
+        INSERT INTO
+            config (key, value)
+        VALUES
+            (?1, ?2)
+        ON CONFLICT(key) DO UPDATE SET
+            value = excluded.value
+        ",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+pub fn get(connection: &Connection, key: &str) -> Result<Option<String>> {
+    let value = connection
+        .query_row(
+            "
+            SELECT
+                value
+            FROM
+                config
+            WHERE
+                key = ?1
+            ",
+            params![key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(value)
+}
+
+pub fn delete(connection: &Connection, key: &str) -> Result<()> {
+    connection.execute(
+        "
+        DELETE
+        FROM
+            config
+        WHERE
+            key = ?1
+        ",
+        params![key],
+    )?;
+    Ok(())
+}
+
+pub fn delete_all(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        DELETE FROM config
+        ",
+        params![],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use anyhow::Result;
+use rusqlite::params;
+use rusqlite::Connection;
+
+use crate::db::has_table;
+
+/* Paths the watcher has seen created, deleted or moved since the snapshot
+ * of the working directory was last brought up to date. A path can be a
+ * file or a directory, and may no longer exist.
+ */
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        CREATE TABLE
+            dirty_paths (
+                path TEXT PRIMARY KEY
+            )
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
+// Repositories created before the watcher existed have no dirty_paths table
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if has_table(connection, "dirty_paths")? {
+        return Ok(());
+    }
+    create_table(connection)
+}
+
+pub fn insert_batch(connection: &Connection, paths: &[String]) -> Result<()> {
+    let mut statement = connection.prepare(
+        "
+        INSERT OR IGNORE INTO
+            dirty_paths (path)
+        VALUES
+            (?1)
+        ",
+    )?;
+    for path in paths {
+        statement.execute(params![path])?;
+    }
+    Ok(())
+}
+
+/* Removes and returns every dirty path in one statement, so none the
+ * watcher records meanwhile are lost.
+ */
+pub fn take_all(connection: &Connection) -> Result<Vec<String>> {
+    let mut statement = connection.prepare(
+        "
+        DELETE FROM dirty_paths RETURNING path
+        ",
+    )?;
+    let result: Vec<String> = statement
+        .query_map(params![], |row| row.get(0))?
+        .flatten()
+        .collect();
+    Ok(result)
+}
+
+pub fn delete_all(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        DELETE FROM dirty_paths
+        ",
+        params![],
+    )?;
+    Ok(())
+}
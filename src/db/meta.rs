@@ -2,6 +2,7 @@ use anyhow::Result;
 use rusqlite::params;
 use rusqlite::Connection;
 
+use crate::db::has_column;
 use crate::models::meta::Meta;
 
 pub fn create_table(connection: &Connection) -> Result<()> {
@@ -10,7 +11,10 @@ pub fn create_table(connection: &Connection) -> Result<()> {
         CREATE TABLE
             meta (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
-                head TEXT NOT NULL
+                head TEXT NOT NULL,
+                format_version INTEGER NOT NULL DEFAULT 1,
+                hash_version INTEGER NOT NULL DEFAULT 1,
+                hash_algorithm TEXT NOT NULL DEFAULT 'xxh3'
             )
         ",
         params![],
@@ -18,6 +22,62 @@ pub fn create_table(connection: &Connection) -> Result<()> {
     Ok(())
 }
 
+/* Repositories created before the format was recorded store their objects
+ * flat, which is format version 1. Those created before the hash version
+ * was recorded hash with the legacy encoding, hash version 1, and those
+ * created before the algorithm could be picked hash with xxh3.
+ */
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if !has_column(connection, "meta", "format_version")? {
+        connection.execute(
+            "
+            ALTER TABLE meta
+                ADD COLUMN format_version INTEGER NOT NULL DEFAULT 1
+            ",
+            params![],
+        )?;
+    }
+
+    if !has_column(connection, "meta", "hash_version")? {
+        connection.execute(
+            "
+            ALTER TABLE meta
+                ADD COLUMN hash_version INTEGER NOT NULL DEFAULT 1
+            ",
+            params![],
+        )?;
+    }
+
+    if !has_column(connection, "meta", "hash_algorithm")? {
+        connection.execute(
+            "
+            ALTER TABLE meta
+                ADD COLUMN hash_algorithm TEXT NOT NULL DEFAULT 'xxh3'
+            ",
+            params![],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn insert(connection: &Connection, meta: &Meta) -> Result<()> {
+    connection.execute(
+        "
+        INSERT INTO
+            meta (head, format_version, hash_version, hash_algorithm)
+        VALUES
+            (?1, ?2, ?3, ?4)
+        ",
+        params![
+            meta.head,
+            meta.format_version,
+            meta.hash_version,
+            meta.hash_algorithm
+        ],
+    )?;
+    Ok(())
+}
+
 pub fn update(connection: &Connection, meta: &Meta) -> Result<()> {
     connection.execute(
         "
@@ -35,7 +95,10 @@ pub fn get(connection: &Connection) -> Result<Meta, rusqlite::Error> {
     connection.query_row(
         "
         SELECT
-            head
+            head,
+            format_version,
+            hash_version,
+            hash_algorithm
         FROM
             meta
         ORDER BY
@@ -44,6 +107,39 @@ pub fn get(connection: &Connection) -> Result<Meta, rusqlite::Error> {
             1
         ",
         params![],
-        |row| Ok(Meta { head: row.get(0)? }),
+        |row| {
+            Ok(Meta {
+                head: row.get(0)?,
+                format_version: row.get(1)?,
+                hash_version: row.get(2)?,
+                hash_algorithm: row.get(3)?,
+            })
+        },
     )
 }
+
+pub fn set_format_version(connection: &Connection, version: u32) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            meta
+        SET
+            format_version = ?1
+        ",
+        params![version],
+    )?;
+    Ok(())
+}
+
+pub fn set_hash_version(connection: &Connection, version: u32) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            meta
+        SET
+            hash_version = ?1
+        ",
+        params![version],
+    )?;
+    Ok(())
+}
@@ -2,6 +2,7 @@ use anyhow::Result;
 use rusqlite::params;
 use rusqlite::Connection;
 
+use crate::db::has_column;
 use crate::models::migration::{Migration, MigrationState};
 
 pub fn create_table(connection: &Connection) -> Result<()> {
@@ -14,7 +15,11 @@ pub fn create_table(connection: &Connection) -> Result<()> {
                 remote_name TEXT NOT NULL,
                 remote_kind TEXT NOT NULL,
                 remote_location TEXT NOT NULL,
-                state TEXT NOT NULL
+                state TEXT NOT NULL,
+                action TEXT,
+                created_unix_timestamp INTEGER NOT NULL DEFAULT 0,
+                ref_name TEXT,
+                commit_hash TEXT
             )
         ",
         params![],
@@ -22,6 +27,39 @@ pub fn create_table(connection: &Connection) -> Result<()> {
     Ok(())
 }
 
+/* Migrations created before resume existed don't record the command that
+ * created them, and ones created before pruning existed don't record when
+ * they were created. Those are given a timestamp of 0 so that they are the
+ * first to go. Pushes from before they recorded what they publish can't be
+ * resumed.
+ */
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if !has_column(connection, "migrations", "action")? {
+        connection
+            .execute("ALTER TABLE migrations ADD COLUMN action TEXT", [])?;
+    }
+
+    if !has_column(connection, "migrations", "created_unix_timestamp")? {
+        connection.execute(
+            "
+            ALTER TABLE migrations
+                ADD COLUMN created_unix_timestamp INTEGER NOT NULL DEFAULT 0
+            ",
+            [],
+        )?;
+    }
+
+    for column in ["ref_name", "commit_hash"] {
+        if !has_column(connection, "migrations", column)? {
+            connection.execute(
+                &format!("ALTER TABLE migrations ADD COLUMN {} TEXT", column),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
 pub fn insert(connection: &Connection, migration: &Migration) -> Result<()> {
     connection.execute(
         "
@@ -32,10 +70,14 @@ pub fn insert(connection: &Connection, migration: &Migration) -> Result<()> {
                 remote_name,
                 remote_kind,
                 remote_location,
-                state
+                state,
+                action,
+                created_unix_timestamp,
+                ref_name,
+                commit_hash
             )
         VALUES
-            (?1, ?2, ?3, ?4, ?5, ?6)
+            (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
         ",
         params![
             migration.id,
@@ -43,12 +85,31 @@ pub fn insert(connection: &Connection, migration: &Migration) -> Result<()> {
             migration.remote_name,
             migration.remote_kind,
             migration.remote_location,
-            migration.state
+            migration.state,
+            migration.action,
+            migration.created_unix_timestamp,
+            migration.ref_name,
+            migration.commit_hash
         ],
     )?;
     Ok(())
 }
 
+fn row_to_migration(row: &rusqlite::Row) -> Result<Migration, rusqlite::Error> {
+    Ok(Migration {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        remote_name: row.get(2)?,
+        remote_kind: row.get(3)?,
+        remote_location: row.get(4)?,
+        state: row.get(5)?,
+        action: row.get(6)?,
+        created_unix_timestamp: row.get(7)?,
+        ref_name: row.get(8)?,
+        commit_hash: row.get(9)?,
+    })
+}
+
 pub fn get_all(
     connection: &Connection,
 ) -> Result<Vec<Migration>, rusqlite::Error> {
@@ -60,58 +121,48 @@ pub fn get_all(
             remote_name,
             remote_kind,
             remote_location,
-            state
+            state,
+            action,
+            created_unix_timestamp,
+            ref_name,
+            commit_hash
         FROM
             migrations
         ",
     )?;
 
-    stmt.query_map(params![], |row| {
-        Ok(Migration {
-            id: row.get(0)?,
-            kind: row.get(1)?,
-            remote_name: row.get(2)?,
-            remote_kind: row.get(3)?,
-            remote_location: row.get(4)?,
-            state: row.get(5)?,
-        })
-    })
-    .into_iter()
-    .flatten()
-    .collect()
+    stmt.query_map(params![], row_to_migration)
+        .into_iter()
+        .flatten()
+        .collect()
 }
 
-//pub fn get(
-//    connection: &Connection,
-//    id: &str,
-//) -> Result<Migration, rusqlite::Error> {
-//    connection.query_row(
-//        "
-//        SELECT
-//            id,
-//            kind,
-//            remote_name,
-//            remote_kind,
-//            remote_location,
-//            state
-//        FROM
-//            migrations
-//        WHERE
-//            id = ?1
-//        ",
-//        params![id],
-//        |row| {
-//            Ok(Migration {
-//                id: row.get(0)?,
-//                kind: row.get(1)?,
-//                remote_name: row.get(2)?,
-//                remote_kind: row.get(3)?,
-//                remote_location: row.get(4)?,
-//                state: row.get(5)?,
-//            })
-//        },
-//    )
-//}
+pub fn get(
+    connection: &Connection,
+    id: &str,
+) -> Result<Migration, rusqlite::Error> {
+    connection.query_row(
+        "
+        SELECT
+            id,
+            kind,
+            remote_name,
+            remote_kind,
+            remote_location,
+            state,
+            action,
+            created_unix_timestamp,
+            ref_name,
+            commit_hash
+        FROM
+            migrations
+        WHERE
+            id = ?1
+        ",
+        params![id],
+        row_to_migration,
+    )
+}
 
 pub fn set_state(
     connection: &Connection,
@@ -131,3 +182,17 @@ pub fn set_state(
     )?;
     Ok(())
 }
+
+pub fn delete(connection: &Connection, id: &str) -> Result<()> {
+    connection.execute(
+        "
+        DELETE
+        FROM
+            migrations
+        WHERE
+            id = ?1
+        ",
+        params![id],
+    )?;
+    Ok(())
+}
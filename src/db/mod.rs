@@ -1,18 +1,27 @@
+use rusqlite::params;
 use rusqlite::Connection;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 use crate::models;
 use crate::store;
-use anyhow::Result;
+use crate::types::hash_algorithm::HashAlgorithm;
+use anyhow::{anyhow, Result};
 
+pub mod chunk;
 pub mod commit;
+pub mod config;
+pub mod dirty_path;
 pub mod meta;
 pub mod migration;
 pub mod reference;
 pub mod remote;
 pub mod staging;
+pub mod stat_cache;
 pub mod transfer;
 pub mod tree;
+pub mod watched_file;
+pub mod watcher;
 
 pub const DB_FILE_NAME: &str = "sssync.db";
 
@@ -25,46 +34,149 @@ pub fn repo_db_path(root_path: &Path) -> PathBuf {
     db_path(&store_path)
 }
 
-pub fn init(connection: &Connection) -> Result<()> {
+pub fn init(
+    connection: &Connection,
+    hash_algorithm: HashAlgorithm,
+) -> Result<()> {
+    chunk::create_table(connection)?;
     commit::create_table(connection)?;
+    config::create_table(connection)?;
+    dirty_path::create_table(connection)?;
     meta::create_table(connection)?;
     migration::create_table(connection)?;
     reference::create_table(connection)?;
     remote::create_table(connection)?;
     staging::create_table(connection)?;
+    stat_cache::create_table(connection)?;
     tree::create_table(connection)?;
     transfer::create_table(connection)?;
+    watched_file::create_table(connection)?;
+    watcher::create_table(connection)?;
+
+    meta::insert(connection, &models::meta::Meta::new("main", hash_algorithm))?;
+    Ok(())
+}
+
+/* Brings the schema of an existing database up to date with the one
+ * created by `init`. Safe to run on every open.
+ */
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    chunk::upgrade(connection)?;
+    commit::upgrade(connection)?;
+    config::upgrade(connection)?;
+    dirty_path::upgrade(connection)?;
+    meta::upgrade(connection)?;
+    migration::upgrade(connection)?;
+    remote::upgrade(connection)?;
+    stat_cache::upgrade(connection)?;
+    transfer::upgrade(connection)?;
+    watched_file::upgrade(connection)?;
+    watcher::upgrade(connection)?;
+    Ok(())
+}
 
-    meta::update(connection, &models::meta::Meta::new("main"))?;
+/* State that only means something in the repository it was made in:
+ * what's staged, what's known about the working directory, the config, who
+ * commits among it, and the remotes, along with the keys they're encrypted
+ * with. It never leaves the repository, and is dropped from a database
+ * that comes from elsewhere.
+ */
+pub fn delete_local_state(connection: &Connection) -> Result<()> {
+    staging::delete(connection)?;
+    stat_cache::delete_all(connection)?;
+    watcher::delete_all(connection)?;
+    watched_file::delete_all(connection)?;
+    dirty_path::delete_all(connection)?;
+    remote::delete_all(connection)?;
+    config::delete_all(connection)?;
     Ok(())
 }
 
+/* Writes a copy of the database at source to destination without its local
+ * state, for uploading to a remote. The copy is vacuumed once the local
+ * state is deleted so none of it lingers in free pages.
+ */
+pub fn write_shareable_copy(source: &Path, destination: &Path) -> Result<()> {
+    _ = fs::remove_file(destination);
+    let destination_str = destination
+        .to_str()
+        .ok_or(anyhow!("Invalid path: {}", destination.display()))?;
+    Connection::open(source)?
+        .execute("VACUUM INTO ?1", params![destination_str])?;
+
+    let copy = Connection::open(destination)?;
+    upgrade(&copy)?;
+    delete_local_state(&copy)?;
+    copy.execute_batch("VACUUM")?;
+    Ok(())
+}
+
+pub fn has_table(connection: &Connection, table: &str) -> Result<bool> {
+    let count: i64 = connection.query_row(
+        "
+        SELECT
+            COUNT(*)
+        FROM
+            sqlite_master
+        WHERE
+            type = 'table' AND
+            name = ?1
+        ",
+        params![table],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+pub fn has_column(
+    connection: &Connection,
+    table: &str,
+    column: &str,
+) -> Result<bool> {
+    let mut statement =
+        connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns: Vec<String> = statement
+        .query_map(params![], |row| row.get(1))?
+        .flatten()
+        .collect();
+    Ok(columns.iter().any(|c| c == column))
+}
+
 /* Updates the remote db that's been downloaded locally
  *
- * Used before pushing updates up to the remote location
+ * Used before pushing updates up to the remote location. The commit at
+ * commit_hash and the commits before it are recorded along with their
+ * trees, then ref_name is pointed at it. Commits made since aren't, their
+ * objects may never have been uploaded.
  */
 pub fn update_remote(
     local_connection: &Connection,
     remote_connection: &Connection,
+    ref_name: &str,
+    commit_hash: &str,
 ) -> Result<()> {
-    let local_commits = commit::get_all(local_connection)?;
-    for c in local_commits {
-        commit::insert(remote_connection, &c)?;
-    }
+    chunk::upgrade(remote_connection)?;
 
-    let local_trees = tree::get_all(local_connection)?;
-    for t in local_trees {
-        tree::insert(remote_connection, &t)?;
+    for c in commit::get_children(local_connection, commit_hash)? {
+        // The remote already has this commit's tree
+        if commit::get(remote_connection, &c.hash).is_ok() {
+            continue;
+        }
+        commit::insert(remote_connection, &c)?;
+        for t in tree::get(local_connection, &c.hash)? {
+            tree::insert(remote_connection, &t)?;
+            chunk::insert(
+                remote_connection,
+                &chunk::get(local_connection, &t.file_hash)?,
+            )?;
+        }
     }
 
-    let local_meta = meta::get(local_connection)?;
-    let local_ref = reference::get(local_connection, &local_meta.head)?;
-
     reference::update(
-        &remote_connection,
-        &local_meta.head,
+        remote_connection,
+        ref_name,
         models::reference::Kind::Branch,
-        &local_ref.hash,
+        commit_hash,
     )?;
 
     Ok(())
@@ -112,3 +112,23 @@ pub fn get(
         })
     })
 }
+
+// Point every reference, of any kind, at new_hash instead of old_hash
+pub fn replace_hash(
+    connection: &Connection,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            refs
+        SET
+            hash = ?2
+        WHERE
+            hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    Ok(())
+}
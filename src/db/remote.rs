@@ -2,7 +2,8 @@ use anyhow::Result;
 use rusqlite::params;
 use rusqlite::Connection;
 
-use crate::models::remote::Remote;
+use crate::db::has_column;
+use crate::models::remote::{Remote, S3Settings};
 use crate::types::remote_kind::RemoteKind;
 
 pub fn create_table(connection: &Connection) -> Result<()> {
@@ -12,7 +13,12 @@ pub fn create_table(connection: &Connection) -> Result<()> {
             remotes (
                 name PRIMARY KEY,
                 kind TEXT NOT NULL,
-                location TEXT NOT NULL
+                location TEXT NOT NULL,
+                endpoint_url TEXT,
+                region TEXT,
+                force_path_style INTEGER NOT NULL DEFAULT 0,
+                profile TEXT,
+                encryption_key TEXT
             )
         ",
         params![],
@@ -20,13 +26,59 @@ pub fn create_table(connection: &Connection) -> Result<()> {
     Ok(())
 }
 
+/* Repositories created before remotes carried S3 settings only have the
+ * name, kind and location columns, and those created before remotes could
+ * be encrypted lack encryption_key.
+ */
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if !has_column(connection, "remotes", "endpoint_url")? {
+        connection.execute_batch(
+            "
+            ALTER TABLE remotes ADD COLUMN endpoint_url TEXT;
+            ALTER TABLE remotes ADD COLUMN region TEXT;
+            ALTER TABLE remotes
+                ADD COLUMN force_path_style INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE remotes ADD COLUMN profile TEXT;
+            ",
+        )?;
+    }
+
+    if !has_column(connection, "remotes", "encryption_key")? {
+        connection.execute(
+            "ALTER TABLE remotes ADD COLUMN encryption_key TEXT",
+            params![],
+        )?;
+    }
+    Ok(())
+}
+
 pub fn insert(connection: &Connection, remote: &Remote) -> Result<()> {
     connection.execute(
         "
-        INSERT INTO remotes (name, kind, location)
-        VALUES (?1, ?2, ?3)
+        INSERT INTO
+            remotes (
+                name,
+                kind,
+                location,
+                endpoint_url,
+                region,
+                force_path_style,
+                profile,
+                encryption_key
+            )
+        VALUES
+            (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
         ",
-        params![remote.name, remote.kind, remote.location],
+        params![
+            remote.name,
+            remote.kind,
+            remote.location,
+            remote.s3.endpoint_url,
+            remote.s3.region,
+            remote.s3.force_path_style,
+            remote.s3.profile,
+            remote.encryption_key,
+        ],
     )?;
     Ok(())
 }
@@ -35,14 +87,39 @@ struct IntermediateRemote {
     name: String,
     kind: RemoteKind,
     location: String,
+    s3: S3Settings,
+    encryption_key: Option<String>,
+}
+
+impl IntermediateRemote {
+    fn to_remote(&self) -> Result<Remote> {
+        let mut remote = Remote::new(&self.name, self.kind, &self.location)?;
+        remote.s3 = self.s3.clone();
+        remote.encryption_key = self.encryption_key.clone();
+        Ok(remote)
+    }
+}
+
+fn row_to_intermediate(
+    row: &rusqlite::Row,
+) -> Result<IntermediateRemote, rusqlite::Error> {
+    Ok(IntermediateRemote {
+        name: row.get(0)?,
+        kind: row.get(1)?,
+        location: row.get(2)?,
+        s3: S3Settings {
+            endpoint_url: row.get(3)?,
+            region: row.get(4)?,
+            force_path_style: row.get(5)?,
+            profile: row.get(6)?,
+        },
+        encryption_key: row.get(7)?,
+    })
 }
 
 pub fn get_all(connection: &Connection) -> Result<Vec<Remote>> {
     let inter = get_all_intermediate(connection)?;
-    inter
-        .iter()
-        .map(|e| Remote::new(&e.name, e.kind, &e.location))
-        .collect()
+    inter.iter().map(|e| e.to_remote()).collect()
 }
 
 fn get_all_intermediate(
@@ -51,20 +128,21 @@ fn get_all_intermediate(
     let mut statement = connection.prepare(
         "
         SELECT
-            name, kind, location
+            name,
+            kind,
+            location,
+            endpoint_url,
+            region,
+            force_path_style,
+            profile,
+            encryption_key
         FROM
             remotes
         ",
     )?;
 
     statement
-        .query_map(params![], |row| {
-            Ok(IntermediateRemote {
-                name: row.get(0)?,
-                kind: row.get(1)?,
-                location: row.get(2)?,
-            })
-        })
+        .query_map(params![], row_to_intermediate)
         .into_iter()
         .flatten()
         .collect()
@@ -72,7 +150,7 @@ fn get_all_intermediate(
 
 pub fn get(connection: &Connection, name: &str) -> Result<Remote> {
     let inter = get_intermediate(connection, name)?;
-    Remote::new(&inter.name, inter.kind, &inter.location)
+    inter.to_remote()
 }
 
 fn get_intermediate(
@@ -82,20 +160,21 @@ fn get_intermediate(
     connection.query_row(
         "
         SELECT
-            name, kind, location
+            name,
+            kind,
+            location,
+            endpoint_url,
+            region,
+            force_path_style,
+            profile,
+            encryption_key
         FROM
             remotes
         WHERE
             name = ?1
         ",
         params![name],
-        |row| {
-            Ok(IntermediateRemote {
-                name: row.get(0)?,
-                kind: row.get(1)?,
-                location: row.get(2)?,
-            })
-        },
+        row_to_intermediate,
     )
 }
 
@@ -116,3 +195,88 @@ pub fn delete(
     statement.execute(params![name])?;
     Ok(())
 }
+
+pub fn delete_all(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        DELETE FROM remotes
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_upgrade_adds_s3_settings() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        connection.execute_batch(
+            "
+            CREATE TABLE
+                remotes (
+                    name PRIMARY KEY,
+                    kind TEXT NOT NULL,
+                    location TEXT NOT NULL
+                );
+            INSERT INTO
+                remotes (name, kind, location)
+            VALUES
+                ('origin', 'S3', 's3://bucket/prefix');
+            ",
+        )?;
+
+        upgrade(&connection)?;
+        // upgrading twice is a no-op
+        upgrade(&connection)?;
+
+        let remote = get(&connection, "origin")?;
+        assert_eq!(remote.s3, S3Settings::default());
+
+        let mut minio = Remote::new("minio", RemoteKind::S3, "s3://bucket")?;
+        minio.s3.endpoint_url = Some(String::from("http://localhost:9000"));
+        minio.s3.force_path_style = true;
+        insert(&connection, &minio)?;
+
+        assert_eq!(get(&connection, "minio")?.s3, minio.s3);
+
+        let mut vault = Remote::new("vault", RemoteKind::Local, "local:///v")?;
+        vault.encryption_key = Some(String::from("env:SSSYNC_KEY"));
+        insert(&connection, &vault)?;
+        assert_eq!(
+            get(&connection, "vault")?.encryption_key,
+            vault.encryption_key
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shareable_copy_leaves_local_state_behind() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("sssync.db");
+        let copy = dir.path().join("shared.db");
+
+        let connection = Connection::open(&source)?;
+        crate::db::init(&connection, Default::default())?;
+        let mut vault = Remote::new("vault", RemoteKind::Local, "local:///v")?;
+        vault.encryption_key = Some(String::from("file:/secret/vault.key"));
+        insert(&connection, &vault)?;
+        crate::db::config::set(&connection, "user.name", "Ada Lovelace")?;
+
+        crate::db::write_shareable_copy(&source, &copy)?;
+
+        let shared = Connection::open(&copy)?;
+        assert!(get_all(&shared)?.is_empty());
+        assert_eq!(crate::db::config::get(&shared, "user.name")?, None);
+        let bytes = std::fs::read(&copy)?;
+        assert!(!bytes
+            .windows(b"/secret/vault.key".len())
+            .any(|w| w == b"/secret/vault.key"));
+        // The repository keeps its own remotes
+        assert_eq!(get_all(&connection)?.len(), 1);
+        Ok(())
+    }
+}
@@ -33,6 +33,13 @@ pub fn insert(connection: &Connection, change: &Change) -> Result<()> {
             sf.size_bytes,
             sf.modified_time_seconds,
         ),
+        Change::Modification(sf) => (
+            sf.path.as_str(),
+            ChangeKind::Modification,
+            sf.file_hash.as_str(),
+            sf.size_bytes,
+            sf.modified_time_seconds,
+        ),
         Change::Deletion(p) => {
             let path_str = p.to_str().unwrap();
             (path_str, ChangeKind::Deletion, "", 0, 0)
@@ -79,16 +86,24 @@ pub fn get_all(connection: &Connection) -> Result<Vec<Change>> {
     )?;
 
     let entries: Vec<Change> = stmt
-        .query_map([], |row| match row.get(1)? {
-            ChangeKind::Addition => Ok(Change::Addition(StagedFile {
-                path: row.get(0)?,
-                file_hash: row.get(2)?,
-                size_bytes: row.get(3)?,
-                modified_time_seconds: row.get(4)?,
-            })),
-            ChangeKind::Deletion => {
-                let p: String = row.get(0)?;
-                Ok(Change::Deletion(PathBuf::from(p)))
+        .query_map([], |row| {
+            let staged_file = || -> rusqlite::Result<StagedFile> {
+                Ok(StagedFile {
+                    path: row.get(0)?,
+                    file_hash: row.get(2)?,
+                    size_bytes: row.get(3)?,
+                    modified_time_seconds: row.get(4)?,
+                })
+            };
+            match row.get(1)? {
+                ChangeKind::Addition => Ok(Change::Addition(staged_file()?)),
+                ChangeKind::Modification => {
+                    Ok(Change::Modification(staged_file()?))
+                }
+                ChangeKind::Deletion => {
+                    let p: String = row.get(0)?;
+                    Ok(Change::Deletion(PathBuf::from(p)))
+                }
             }
         })?
         .filter_map(|fe| fe.ok())
@@ -107,3 +122,33 @@ pub fn delete(connection: &Connection) -> Result<()> {
 
     Ok(())
 }
+
+pub fn delete_path(connection: &Connection, path: &str) -> Result<()> {
+    connection.execute(
+        "
+            DELETE FROM staging WHERE path = ?1
+        ",
+        params![path],
+    )?;
+
+    Ok(())
+}
+
+pub fn replace_file_hash(
+    connection: &Connection,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            staging
+        SET
+            file_hash = ?2
+        WHERE
+            file_hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+
+use crate::db::has_table;
+use crate::models::stat_cache::StatCacheEntry;
+
+/* Hashes of files in the working directory, by path, so that files that
+ * haven't changed since they were last hashed aren't read again. Only ever
+ * a cache, any of it can be dropped at any time.
+ */
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        CREATE TABLE
+            stat_cache (
+                path TEXT PRIMARY KEY,
+                size_bytes INTEGER NOT NULL,
+                modified_time_nanos INTEGER NOT NULL,
+                inode INTEGER NOT NULL,
+                file_hash TEXT NOT NULL
+            )
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
+// Repositories created before hashes were cached have no stat_cache table
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if has_table(connection, "stat_cache")? {
+        return Ok(());
+    }
+    create_table(connection)
+}
+
+pub fn insert(connection: &Connection, entry: &StatCacheEntry) -> Result<()> {
+    connection.execute(
+        "
+        INSERT OR REPLACE INTO
+            stat_cache (
+                path,
+                size_bytes,
+                modified_time_nanos,
+                inode,
+                file_hash
+            )
+        VALUES
+            (?1, ?2, ?3, ?4, ?5)
+        ",
+        params![
+            entry.path,
+            entry.size_bytes,
+            entry.modified_time_nanos,
+            entry.inode,
+            entry.file_hash
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get(
+    connection: &Connection,
+    path: &str,
+) -> Result<Option<StatCacheEntry>> {
+    let entry = connection
+        .query_row(
+            "
+            SELECT
+                path, size_bytes, modified_time_nanos, inode, file_hash
+            FROM
+                stat_cache
+            WHERE
+                path = ?1
+            ",
+            params![path],
+            |row| {
+                Ok(StatCacheEntry {
+                    path: row.get(0)?,
+                    size_bytes: row.get(1)?,
+                    modified_time_nanos: row.get(2)?,
+                    inode: row.get(3)?,
+                    file_hash: row.get(4)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(entry)
+}
+
+pub fn delete_all(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        DELETE FROM stat_cache
+        ",
+        params![],
+    )?;
+    Ok(())
+}
@@ -17,9 +17,27 @@ pub fn create_table(connection: &Connection) -> Result<()> {
         ",
         params![],
     )?;
+    create_index(connection)
+}
+
+// Transfers are always looked up and pruned by their migration
+fn create_index(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        CREATE INDEX IF NOT EXISTS
+            transfers_migration_id
+        ON
+            transfers (migration_id)
+        ",
+        params![],
+    )?;
     Ok(())
 }
 
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    create_index(connection)
+}
+
 pub fn insert(connection: &Connection, transfer: &Transfer) -> Result<()> {
     connection.execute(
         "
@@ -127,3 +145,65 @@ pub fn set_state(
     )?;
     Ok(())
 }
+
+/* Put every transfer of the migration that didn't complete back in the
+ * Waiting state so that running the migration again retries it.
+ */
+pub fn reset_unfinished(
+    connection: &Connection,
+    migration_id: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            transfers
+        SET
+            state = ?2
+        WHERE
+            migration_id = ?1 AND
+            state != ?3
+        ",
+        params![
+            migration_id,
+            TransferState::Waiting,
+            TransferState::Complete
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn delete_for_migration(
+    connection: &Connection,
+    migration_id: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        DELETE
+        FROM
+            transfers
+        WHERE
+            migration_id = ?1
+        ",
+        params![migration_id],
+    )?;
+    Ok(())
+}
+
+pub fn replace_object_hash(
+    connection: &Connection,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            transfers
+        SET
+            object_hash = ?2
+        WHERE
+            object_hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    Ok(())
+}
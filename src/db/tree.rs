@@ -128,3 +128,42 @@ pub fn get_all(
         .flatten()
         .collect()
 }
+
+// Point every tree entry for the file old_hash at new_hash
+pub fn replace_file_hash(
+    connection: &Connection,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            trees
+        SET
+            file_hash = ?2
+        WHERE
+            file_hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    Ok(())
+}
+
+pub fn replace_commit_hash(
+    connection: &Connection,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            trees
+        SET
+            commit_hash = ?2
+        WHERE
+            commit_hash = ?1
+        ",
+        params![old_hash, new_hash],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use rusqlite::params;
+use rusqlite::Connection;
+
+use crate::db::has_table;
+
+/* The snapshot of every file in the working directory that isn't ignored,
+ * kept while a watcher is running so status doesn't need to walk the
+ * working directory to find them.
+ */
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        CREATE TABLE
+            watched_files (
+                path TEXT PRIMARY KEY
+            )
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
+// Repositories created before the watcher existed have no watched_files
+// table
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if has_table(connection, "watched_files")? {
+        return Ok(());
+    }
+    create_table(connection)
+}
+
+pub fn insert_batch(connection: &Connection, paths: &[String]) -> Result<()> {
+    let mut statement = connection.prepare(
+        "
+        INSERT OR IGNORE INTO
+            watched_files (path)
+        VALUES
+            (?1)
+        ",
+    )?;
+    for path in paths {
+        statement.execute(params![path])?;
+    }
+    Ok(())
+}
+
+pub fn delete_batch(connection: &Connection, paths: &[String]) -> Result<()> {
+    let mut statement = connection.prepare(
+        "
+        DELETE FROM watched_files WHERE path = ?1
+        ",
+    )?;
+    for path in paths {
+        statement.execute(params![path])?;
+    }
+    Ok(())
+}
+
+pub fn get_all(connection: &Connection) -> Result<Vec<String>> {
+    let mut statement = connection.prepare(
+        "
+        SELECT
+            path
+        FROM
+            watched_files
+        ",
+    )?;
+    let result: Vec<String> = statement
+        .query_map(params![], |row| row.get(0))?
+        .flatten()
+        .collect();
+    Ok(result)
+}
+
+pub fn delete_all(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        DELETE FROM watched_files
+        ",
+        params![],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use anyhow::Result;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+
+use crate::db::has_table;
+use crate::models::watcher::Watcher;
+
+/* The running `sssync watch` process, at most one row */
+pub fn create_table(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        CREATE TABLE
+            watcher (
+                pid INTEGER NOT NULL,
+                generation INTEGER NOT NULL,
+                snapshot_generation INTEGER
+            )
+        ",
+        params![],
+    )?;
+    Ok(())
+}
+
+// Repositories created before the watcher existed have no watcher table
+pub fn upgrade(connection: &Connection) -> Result<()> {
+    if has_table(connection, "watcher")? {
+        return Ok(());
+    }
+    create_table(connection)
+}
+
+// Records pid as the watcher, without a snapshot
+pub fn register(connection: &Connection, pid: i64) -> Result<()> {
+    connection.execute("DELETE FROM watcher", params![])?;
+    connection.execute(
+        "
+        INSERT INTO
+            watcher (pid, generation, snapshot_generation)
+        VALUES
+            (?1, 0, NULL)
+        ",
+        params![pid],
+    )?;
+    Ok(())
+}
+
+pub fn get(connection: &Connection) -> Result<Option<Watcher>> {
+    let watcher = connection
+        .query_row(
+            "
+            SELECT
+                pid, generation, snapshot_generation
+            FROM
+                watcher
+            ",
+            params![],
+            |row| {
+                Ok(Watcher {
+                    pid: row.get(0)?,
+                    generation: row.get(1)?,
+                    snapshot_generation: row.get(2)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(watcher)
+}
+
+// Throws away the snapshot, the next status scans the working directory
+pub fn invalidate(connection: &Connection, pid: i64) -> Result<()> {
+    connection.execute(
+        "
+        UPDATE
+            watcher
+        SET
+            generation = generation + 1
+        WHERE
+            pid = ?1
+        ",
+        params![pid],
+    )?;
+    Ok(())
+}
+
+/* Marks the snapshot as taken during generation. Returns false when the
+ * watcher has moved on to another generation since, or has gone.
+ */
+pub fn set_snapshot_generation(
+    connection: &Connection,
+    pid: i64,
+    generation: i64,
+) -> Result<bool> {
+    let updated = connection.execute(
+        "
+        UPDATE
+            watcher
+        SET
+            snapshot_generation = ?2
+        WHERE
+            pid = ?1 AND generation = ?2
+        ",
+        params![pid, generation],
+    )?;
+    Ok(updated == 1)
+}
+
+pub fn delete_all(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "
+        DELETE FROM watcher
+        ",
+        params![],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,273 @@
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use ring::aead::{
+    Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN,
+};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/* Client side encryption of everything a remote stores
+ *
+ * A remote can be given a repository key, 32 random bytes. Objects and the
+ * remote database are encrypted with it before they leave the repository
+ * and decrypted as they're downloaded, so the bucket only ever holds
+ * ciphertext.
+ *
+ * Every file starts with MAGIC and a random salt. The key used for the file
+ * is derived from the repository key and the salt with HKDF-SHA256, so no
+ * two files share one. The contents follow in segments of SEGMENT_SIZE
+ * bytes, each sealed with ChaCha20-Poly1305. The nonce of a segment is its
+ * position along with whether it's the last one, which means segments can't
+ * be reordered, dropped or cut off the end without decryption failing.
+ *
+ * Objects keep their names, the hash of their plaintext, and their sizes
+ * stay visible to anyone who can list the bucket.
+ */
+const MAGIC: &[u8; 8] = b"SSSYNCE1";
+const SALT_LEN: usize = 16;
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const INFO: &[u8] = b"sssync object";
+
+#[derive(Clone, Copy)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    fn parse(hex_key: &str) -> Result<Key> {
+        let bytes = hex::decode(hex_key.trim())
+            .map_err(|_| anyhow!("encryption key must be hex encoded"))?;
+        let key: [u8; KEY_LEN] = bytes.try_into().map_err(|_| {
+            anyhow!("encryption key must be {} bytes long", KEY_LEN)
+        })?;
+        Ok(Key(key))
+    }
+
+    // The key sealing the file with the given salt
+    fn file_key(&self, salt: &[u8]) -> LessSafeKey {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(&self.0);
+        let info = [INFO];
+        let okm = prk
+            .expand(&info, &CHACHA20_POLY1305)
+            .expect("HKDF output fits a ChaCha20-Poly1305 key");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+/* Loads the repository key from where the remote says to find it, either
+ * "file:<path>" for a file holding the key or "env:<name>" for an
+ * environment variable. Either way the key is written as 64 hex characters.
+ */
+pub fn load_key(source: &str) -> Result<Key> {
+    if let Some(path) = source.strip_prefix("file:") {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading encryption key {}", path))?;
+        Key::parse(&contents)
+    } else if let Some(name) = source.strip_prefix("env:") {
+        let value = env::var(name)
+            .with_context(|| format!("reading encryption key ${}", name))?;
+        Key::parse(&value)
+    } else {
+        Err(anyhow!(
+            "invalid encryption key source: {}, expected file:<path> or \
+             env:<name>",
+            source
+        ))
+    }
+}
+
+/* Writes a new random key to the file at path, readable only by its owner.
+ * An existing file is never overwritten, losing a key loses everything
+ * encrypted with it.
+ */
+pub fn generate_key(path: &Path) -> Result<()> {
+    let mut key = [0u8; KEY_LEN];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| anyhow!("could not generate a random key"))?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    writeln!(file, "{}", hex::encode(key))?;
+    Ok(())
+}
+
+fn segment_nonce(position: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[3..11].copy_from_slice(&position.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+// Fills as much of buffer as the reader has left, returning how much it read
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+pub fn encrypt(
+    key: &Key,
+    source: impl Read,
+    mut destination: impl Write,
+) -> Result<()> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| anyhow!("could not generate a random salt"))?;
+    let file_key = key.file_key(&salt);
+    destination.write_all(MAGIC)?;
+    destination.write_all(&salt)?;
+
+    let mut reader = BufReader::new(source);
+    let mut segment = Vec::with_capacity(SEGMENT_SIZE + TAG_LEN);
+    let mut position: u64 = 0;
+    loop {
+        segment.resize(SEGMENT_SIZE, 0);
+        let read = read_full(&mut reader, &mut segment)?;
+        segment.truncate(read);
+        // An empty file is still sealed as one empty last segment
+        let last = read < SEGMENT_SIZE || reader.fill_buf()?.is_empty();
+
+        file_key
+            .seal_in_place_append_tag(
+                segment_nonce(position, last),
+                Aad::empty(),
+                &mut segment,
+            )
+            .map_err(|_| anyhow!("could not encrypt segment {}", position))?;
+        destination.write_all(&segment)?;
+
+        if last {
+            return Ok(());
+        }
+        position += 1;
+    }
+}
+
+pub fn decrypt(
+    key: &Key,
+    source: impl Read,
+    mut destination: impl Write,
+) -> Result<()> {
+    let mut reader = BufReader::new(source);
+    let mut header = [0u8; MAGIC.len() + SALT_LEN];
+    if read_full(&mut reader, &mut header)? < header.len()
+        || &header[..MAGIC.len()] != MAGIC
+    {
+        return Err(anyhow!(
+            "not encrypted, the remote may have been written without a key"
+        ));
+    }
+    let file_key = key.file_key(&header[MAGIC.len()..]);
+
+    let mut segment = vec![0u8; SEGMENT_SIZE + TAG_LEN];
+    let mut position: u64 = 0;
+    loop {
+        let read = read_full(&mut reader, &mut segment)?;
+        let last = read < segment.len() || reader.fill_buf()?.is_empty();
+
+        let plaintext = file_key
+            .open_in_place(
+                segment_nonce(position, last),
+                Aad::empty(),
+                &mut segment[..read],
+            )
+            .map_err(|_| {
+                anyhow!("could not decrypt, wrong key or corrupt data")
+            })?;
+        destination.write_all(plaintext)?;
+
+        if last {
+            return Ok(());
+        }
+        position += 1;
+    }
+}
+
+pub fn encrypt_file(
+    key: &Key,
+    source: &Path,
+    destination: &Path,
+) -> Result<()> {
+    let mut writer = io::BufWriter::new(File::create(destination)?);
+    encrypt(key, File::open(source)?, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn decrypt_file(
+    key: &Key,
+    source: &Path,
+    destination: &Path,
+) -> Result<()> {
+    let mut writer = io::BufWriter::new(File::create(destination)?);
+    decrypt(key, File::open(source)?, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Whether the file at path was written by encrypt
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; MAGIC.len()];
+    let read = read_full(&mut File::open(path)?, &mut magic)?;
+    Ok(read == magic.len() && &magic == MAGIC)
+}
+
+// A file next to path to hold its encrypted form. It ends in .partial, so
+// it's never mistaken for an object.
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".encrypted.partial");
+    PathBuf::from(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let key = Key([7; KEY_LEN]);
+        for len in [0, 1, SEGMENT_SIZE, 3 * SEGMENT_SIZE + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut ciphertext = vec![];
+            encrypt(&key, plaintext.as_slice(), &mut ciphertext)?;
+
+            let mut decrypted = vec![];
+            decrypt(&key, ciphertext.as_slice(), &mut decrypted)?;
+            assert_eq!(decrypted, plaintext);
+
+            // Dropping the last segment, or any byte, is noticed
+            let cut = ciphertext.len() - TAG_LEN;
+            assert!(decrypt(&key, &ciphertext[..cut], &mut vec![]).is_err());
+            assert!(decrypt(
+                &Key([8; KEY_LEN]),
+                ciphertext.as_slice(),
+                &mut vec![]
+            )
+            .is_err());
+        }
+
+        assert!(decrypt(&key, &b"plaintext"[..], &mut vec![]).is_err());
+        Ok(())
+    }
+}
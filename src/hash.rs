@@ -1,13 +1,32 @@
 use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::Path;
 
-use anyhow::Result;
-use xxhash_rust::xxh3::{xxh3_128, Xxh3};
+use anyhow::{anyhow, Result};
+use ring::digest;
+use rusqlite::Connection;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::db;
+use crate::types::hash_algorithm::HashAlgorithm;
+
+/* How objects and commits are hashed, recorded per repository
+ *
+ * Version 1 shifted the xxh3 digest by bits where it meant bytes, so most of
+ * its 128 bits never made it into the hash. Version 2 writes out every byte
+ * of the digest, big endian. Version 3 hashes objects the same way and
+ * names commits by their canonical serialization, see models::commit,
+ * rather than by their files alone. Repositories keep hashing with the
+ * version they were created with until `sssync upgrade` rehashes them. Only
+ * xxh3 was ever written with version 1.
+ */
+pub const LEGACY_HASH_VERSION: u32 = 1;
+pub const CANONICAL_COMMIT_HASH_VERSION: u32 = 3;
+pub const HASH_VERSION: u32 = 3;
 
 #[allow(clippy::needless_range_loop)]
-fn u128_to_byte_array(n: u128) -> [u8; 16] {
+fn legacy_u128_to_byte_array(n: u128) -> [u8; 16] {
     let mut out: [u8; 16] = [0; 16];
 
     for i in 0..16 {
@@ -17,21 +36,56 @@ fn u128_to_byte_array(n: u128) -> [u8; 16] {
     out
 }
 
-struct Xxh3Writer {
-    hasher: Xxh3,
+// Feeds everything written to it into the hash function of the algorithm
+enum DigestWriter {
+    Xxh3(Box<Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Box<digest::Context>),
 }
 
-impl Xxh3Writer {
-    pub const fn new() -> Self {
-        Self {
-            hasher: Xxh3::new(),
+impl DigestWriter {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Xxh3 => DigestWriter::Xxh3(Box::new(Xxh3::new())),
+            HashAlgorithm::Blake3 => {
+                DigestWriter::Blake3(Box::new(blake3::Hasher::new()))
+            }
+            HashAlgorithm::Sha256 => DigestWriter::Sha256(Box::new(
+                digest::Context::new(&digest::SHA256),
+            )),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        // Writing to a DigestWriter never fails
+        _ = self.write(bytes);
+    }
+
+    fn finish(self, version: u32) -> String {
+        match self {
+            DigestWriter::Xxh3(hasher) if version == LEGACY_HASH_VERSION => {
+                hex::encode(legacy_u128_to_byte_array(hasher.digest128()))
+            }
+            DigestWriter::Xxh3(hasher) => {
+                hex::encode(hasher.digest128().to_be_bytes())
+            }
+            DigestWriter::Blake3(hasher) => {
+                hasher.finalize().to_hex().to_string()
+            }
+            DigestWriter::Sha256(context) => hex::encode((*context).finish()),
         }
     }
 }
 
-impl Write for Xxh3Writer {
+impl Write for DigestWriter {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-        self.hasher.update(buf);
+        match self {
+            DigestWriter::Xxh3(hasher) => hasher.update(buf),
+            DigestWriter::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+            DigestWriter::Sha256(context) => context.update(buf),
+        }
         Ok(buf.len())
     }
 
@@ -40,14 +94,163 @@ impl Write for Xxh3Writer {
     }
 }
 
-pub fn hash_file(path: &Path) -> Result<String> {
-    let mut hasher = Xxh3Writer::new();
-    let mut file = File::open(&path)?;
-    io::copy(&mut file, &mut hasher)?;
-    let hash = hasher.hasher.digest128();
-    Ok(hex::encode(&u128_to_byte_array(hash)))
+// Hashes files, objects and commits the way a repository expects
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hasher {
+    algorithm: HashAlgorithm,
+    version: u32,
 }
 
-pub fn hash_string(s: String) -> String {
-    hex::encode(&u128_to_byte_array(xxh3_128(s.as_bytes())))
+impl Default for Hasher {
+    fn default() -> Self {
+        Self {
+            algorithm: HashAlgorithm::default(),
+            version: HASH_VERSION,
+        }
+    }
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm, version: u32) -> Result<Self> {
+        if !(LEGACY_HASH_VERSION..=HASH_VERSION).contains(&version) {
+            return Err(anyhow!(
+                "hash version {} is newer than the supported version {}, \
+                 upgrade sssync",
+                version,
+                HASH_VERSION
+            ));
+        }
+        if version == LEGACY_HASH_VERSION && algorithm != HashAlgorithm::Xxh3 {
+            return Err(anyhow!(
+                "hash version {} only exists for xxh3, not {}",
+                version,
+                algorithm
+            ));
+        }
+        Ok(Self { algorithm, version })
+    }
+
+    // The hasher for the repository behind connection
+    pub fn for_repository(connection: &Connection) -> Result<Self> {
+        let meta = db::meta::get(connection)?;
+        Hasher::new(meta.hash_algorithm, meta.hash_version)
+    }
+
+    // The same algorithm, at the current hash version
+    pub fn upgraded(&self) -> Self {
+        Self {
+            algorithm: self.algorithm,
+            version: HASH_VERSION,
+        }
+    }
+
+    // Whether objects are named the same by both hashers
+    pub fn names_objects_like(&self, other: &Hasher) -> bool {
+        self.algorithm == other.algorithm
+            && (self.version == LEGACY_HASH_VERSION)
+                == (other.version == LEGACY_HASH_VERSION)
+    }
+
+    // Whether commits are named by their canonical serialization
+    pub fn hashes_canonical_commits(&self) -> bool {
+        self.version >= CANONICAL_COMMIT_HASH_VERSION
+    }
+
+    pub fn hash_file(&self, path: &Path) -> Result<String> {
+        self.hash_reader(File::open(path)?)
+    }
+
+    // Hashes everything read from reader
+    pub fn hash_reader(&self, mut reader: impl Read) -> Result<String> {
+        let mut writer = DigestWriter::new(self.algorithm);
+        io::copy(&mut reader, &mut writer)?;
+        Ok(writer.finish(self.version))
+    }
+
+    // Hashes bytes in memory, giving the same hash as hash_file would for a
+    // file with the same contents.
+    pub fn hash_bytes(&self, bytes: &[u8]) -> String {
+        let mut writer = DigestWriter::new(self.algorithm);
+        writer.update(bytes);
+        writer.finish(self.version)
+    }
+
+    pub fn hash_string(&self, s: String) -> String {
+        self.hash_bytes(s.as_bytes())
+    }
+
+    /* Hashes everything read from each of readers in turn the way both this
+     * hasher and to would, reading it only once. Readers are only opened as
+     * they're reached. Used to move a repository between hash versions.
+     */
+    pub fn rehash<R: Read>(
+        &self,
+        to: &Hasher,
+        readers: impl IntoIterator<Item = Result<R>>,
+    ) -> Result<(String, String)> {
+        let mut from_writer = DigestWriter::new(self.algorithm);
+        let mut to_writer = DigestWriter::new(to.algorithm);
+        let mut buffer = vec![0u8; 64 * 1024];
+        for reader in readers {
+            let mut reader = reader?;
+            loop {
+                let read = match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                from_writer.update(&buffer[..read]);
+                to_writer.update(&buffer[..read]);
+            }
+        }
+        Ok((
+            from_writer.finish(self.version),
+            to_writer.finish(to.version),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_keeps_every_byte_of_the_digest() -> Result<()> {
+        let digest = xxhash_rust::xxh3::xxh3_128(b"sssync");
+        let hash = Hasher::default().hash_bytes(b"sssync");
+        assert_eq!(u128::from_str_radix(&hash, 16)?, digest);
+
+        // Legacy hashes of inputs differing only in their high bits collide
+        assert_eq!(
+            legacy_u128_to_byte_array(1 << 100 | 0xff),
+            legacy_u128_to_byte_array(1 << 120 | 0xff)
+        );
+        assert!(Hasher::new(HashAlgorithm::Xxh3, HASH_VERSION + 1).is_err());
+        assert!(
+            Hasher::new(HashAlgorithm::Blake3, LEGACY_HASH_VERSION).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_algorithms() -> Result<()> {
+        let sha256 = Hasher::new(HashAlgorithm::Sha256, HASH_VERSION)?;
+        assert_eq!(
+            sha256.hash_bytes(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let blake3 = Hasher::new(HashAlgorithm::Blake3, HASH_VERSION)?;
+        assert_eq!(blake3.hash_bytes(b"abc").len(), 64);
+        for hasher in [Hasher::default(), sha256, blake3] {
+            assert_eq!(
+                hasher.hash_reader(&b"streamed"[..])?,
+                hasher.hash_bytes(b"streamed")
+            );
+        }
+        Ok(())
+    }
 }
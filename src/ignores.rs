@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+use crate::store::STORE_DIR;
+
+pub const IGNORE_FILE_NAME: &str = ".sssyncignore";
+
+/* Files the working directory holds but sssync should leave alone
+ *
+ * Any directory can have a .sssyncignore, written like a .gitignore: one
+ * glob per line, `#` for comments, `!` in front to include again what an
+ * earlier line ignored, a `/` at the start anchoring the pattern to the
+ * directory of the file and one at the end matching only directories.
+ * Patterns apply to everything below the directory they're in, and the
+ * file nearest a path wins over those further up. Nothing inside an ignored
+ * directory is looked at, so it can't be included again.
+ *
+ * The .sssync directory is always ignored.
+ */
+pub struct Ignores {
+    root: PathBuf,
+    // The rules of every directory entered, outermost first
+    stack: Vec<Gitignore>,
+}
+
+// The rule deciding whether a path is ignored
+#[derive(Debug, PartialEq, Eq)]
+pub struct Rule {
+    // The ignore file holding the pattern, relative to the root, None for
+    // the rule ignoring the .sssync directory
+    pub source: Option<PathBuf>,
+    pub pattern: String,
+    // Whether the pattern includes the path again rather than ignoring it
+    pub negated: bool,
+}
+
+impl Ignores {
+    pub fn new(root: &Path) -> Result<Self> {
+        let mut ignores = Self {
+            root: root.to_path_buf(),
+            stack: vec![],
+        };
+        ignores.enter(Path::new(""))?;
+        Ok(ignores)
+    }
+
+    /* Loads the rules of the directory at rel_dir, to be undone with leave
+     * once everything in it has been checked.
+     */
+    pub fn enter(&mut self, rel_dir: &Path) -> Result<()> {
+        let dir = self.root.join(rel_dir);
+        let path = dir.join(IGNORE_FILE_NAME);
+        let mut builder = GitignoreBuilder::new(&dir);
+        if path.is_file() {
+            if let Some(e) = builder.add(&path) {
+                return Err(anyhow!("invalid {}: {}", path.display(), e));
+            }
+        }
+        self.stack.push(builder.build()?);
+        Ok(())
+    }
+
+    pub fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    /* The rule that decides whether rel_path is ignored, if any does. Only
+     * the rules of the directories entered are looked at.
+     */
+    pub fn matched(&self, rel_path: &Path, is_dir: bool) -> Option<Rule> {
+        if rel_path.starts_with(STORE_DIR) {
+            return Some(Rule {
+                source: None,
+                pattern: String::from(STORE_DIR),
+                negated: false,
+            });
+        }
+
+        let path = self.root.join(rel_path);
+        self.stack.iter().rev().find_map(|rules| {
+            let glob = match rules.matched(&path, is_dir) {
+                Match::None => return None,
+                Match::Ignore(glob) | Match::Whitelist(glob) => glob,
+            };
+            Some(Rule {
+                source: glob.from().map(|from| {
+                    from.strip_prefix(&self.root).unwrap_or(from).to_path_buf()
+                }),
+                pattern: glob.original().to_string(),
+                negated: glob.is_whitelist(),
+            })
+        })
+    }
+
+    pub fn is_ignored(&self, rel_path: &Path, is_dir: bool) -> bool {
+        self.matched(rel_path, is_dir)
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+/* The rule that decides whether rel_path is ignored, for a path anywhere
+ * in the working directory. A path inside an ignored directory is decided
+ * by the rule ignoring the directory.
+ */
+pub fn check(root: &Path, rel_path: &Path) -> Result<Option<Rule>> {
+    let mut ignores = Ignores::new(root)?;
+    let mut dir = PathBuf::new();
+    let components: Vec<_> = rel_path.components().collect();
+    for (i, component) in components.iter().enumerate() {
+        let path = dir.join(component);
+        let last = i + 1 == components.len();
+        let is_dir =
+            !last || fs::metadata(root.join(&path)).is_ok_and(|m| m.is_dir());
+        let rule = ignores.matched(&path, is_dir);
+        if last || rule.as_ref().is_some_and(|r| !r.negated) {
+            return Ok(rule);
+        }
+        ignores.enter(&path)?;
+        dir = path;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_rules() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("renders/keep"))?;
+        fs::create_dir_all(root.join("cache"))?;
+        fs::write(
+            root.join(IGNORE_FILE_NAME),
+            "*.tmp\n!keep.tmp\n/cache/\n.DS_Store\n",
+        )?;
+        fs::write(root.join("renders").join(IGNORE_FILE_NAME), "*.exr\n")?;
+        fs::write(root.join("renders/keep").join(IGNORE_FILE_NAME), "!*\n")?;
+        for file in ["a.tmp", "keep.tmp", "renders/a.exr", "renders/keep/b.exr"]
+        {
+            fs::write(root.join(file), "")?;
+        }
+
+        let ignored = |p: &str| -> Result<bool> {
+            Ok(check(root, Path::new(p))?.is_some_and(|r| !r.negated))
+        };
+        assert!(ignored("a.tmp")?);
+        assert!(!ignored("keep.tmp")?);
+        assert!(ignored("cache")?);
+        assert!(ignored("cache/anything")?);
+        assert!(ignored("renders/a.exr")?);
+        assert!(!ignored("renders/keep/b.exr")?);
+        assert!(ignored(".sssync/sssync.db")?);
+
+        let rule = check(root, Path::new("renders/a.exr"))?;
+        assert_eq!(
+            rule,
+            Some(Rule {
+                source: Some(PathBuf::from("renders/.sssyncignore")),
+                pattern: String::from("*.exr"),
+                negated: false,
+            })
+        );
+        Ok(())
+    }
+}
@@ -3,16 +3,24 @@ use std::process::exit;
 use crate::cli::run;
 
 mod actions;
+mod backend;
+mod chunk;
 mod cli;
+mod config;
 mod db;
+mod encryption;
 mod hash;
+mod ignores;
 mod migration;
 mod models;
 mod remote;
 mod s3;
 mod store;
+#[cfg(test)]
+mod testing;
 mod tree;
 mod types;
+mod watch;
 
 fn main() {
     exit(match run() {
@@ -1,26 +1,38 @@
-use std::fs::File;
+use std::fs;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
+use futures::stream::{self, StreamExt};
 use rusqlite::Connection;
 
+use crate::backend::ObjectBackend;
+use crate::config;
 use crate::db;
-use crate::models::migration::{Migration, MigrationState};
+use crate::hash::Hasher;
+use crate::models::migration::{Migration, MigrationAction, MigrationState};
+use crate::models::reference::Reference;
 use crate::models::transfer::{Transfer, TransferKind, TransferState};
-use crate::remote::RemoteInfo;
-use crate::s3::upload_multipart::upload_multipart;
-use crate::s3::{download_object, make_client};
 use crate::store;
+use crate::types::compression::Compression;
+
+pub const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
 
 pub fn create(
     connection: &Connection,
     kind: TransferKind,
+    action: MigrationAction,
     remote_name: &str,
     object_hashes: &[String],
+    head: Option<&Reference>,
 ) -> Result<Migration> {
     let remote = db::remote::get(connection, remote_name)?;
 
-    let migration = Migration::new(kind.clone(), &remote);
+    // Every command that transfers objects passes through here, which makes
+    // it a good place to keep the history from growing without bound.
+    apply_retention(connection)?;
+
+    let migration = Migration::new(kind.clone(), action, &remote, head)?;
     db::migration::insert(connection, &migration)?;
 
     let transfers: Vec<Transfer> = object_hashes
@@ -35,25 +47,86 @@ pub fn create(
     Ok(migration)
 }
 
+/* Delete the migrations in one of states that were created more than
+ * older_than seconds ago, along with their transfers. Returns the number of
+ * migrations deleted.
+ */
+pub fn prune(
+    connection: &Connection,
+    older_than: u64,
+    states: &[MigrationState],
+) -> Result<usize> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cutoff = now.saturating_sub(older_than);
+
+    let migrations: Vec<Migration> = db::migration::get_all(connection)?
+        .into_iter()
+        .filter(|m| states.contains(&m.state))
+        .filter(|m| m.created_unix_timestamp <= cutoff)
+        .collect();
+
+    let transaction = connection.unchecked_transaction()?;
+    for m in &migrations {
+        db::transfer::delete_for_migration(&transaction, &m.id)?;
+        db::migration::delete(&transaction, &m.id)?;
+    }
+    transaction.commit()?;
+
+    Ok(migrations.len())
+}
+
+/* Prune finished migrations that are older than the configured retention.
+ * Failed migrations are kept around so that they can still be resumed.
+ */
+pub fn apply_retention(connection: &Connection) -> Result<usize> {
+    match config::migration_retention_days(connection)? {
+        Some(days) => prune(
+            connection,
+            days * SECONDS_PER_DAY,
+            &[MigrationState::Complete, MigrationState::Canceled],
+        ),
+        None => Ok(0),
+    }
+}
+
+/* Number of transfers run at the same time when nothing else is asked for. */
+pub const DEFAULT_JOBS: usize = 4;
+
+/* Run every waiting transfer of the migration, at most `jobs` of them at a
+ * time. Each transfer's state is written back to the database as soon as it
+ * finishes so an interrupted migration still knows what's left to do.
+ */
 pub async fn run(
     connection: &Connection,
+    backend: &dyn ObjectBackend,
     root_path: &Path,
     migration: &Migration,
     force: bool,
     ignore_existing: bool,
+    jobs: usize,
 ) -> Result<()> {
     match migration.kind {
         TransferKind::Upload => {
-            run_upload(connection, root_path, migration, force, ignore_existing)
-                .await
+            run_upload(
+                connection,
+                backend,
+                root_path,
+                migration,
+                force,
+                ignore_existing,
+                jobs,
+            )
+            .await
         }
         TransferKind::Download => {
             run_download(
                 connection,
+                backend,
                 root_path,
                 migration,
                 force,
                 ignore_existing,
+                jobs,
             )
             .await
         }
@@ -62,122 +135,495 @@ pub async fn run(
 
 async fn run_upload(
     connection: &Connection,
+    backend: &dyn ObjectBackend,
     root_path: &Path,
     migration: &Migration,
     force: bool,
     ignore_existing: bool,
+    jobs: usize,
 ) -> Result<()> {
     let uploads =
         db::transfer::get_waiting_for_migration(connection, &migration.id)?;
 
-    let client = make_client().await;
-    let remote_info = RemoteInfo::from_url(&migration.remote_location)?;
     let upload_count = uploads.len();
 
     println!("uploading {} files", upload_count);
     db::migration::set_state(connection, migration, MigrationState::Running)?;
-    for (i, upload) in uploads.iter().enumerate() {
-        let key = remote_info.object_key(&upload.object_hash);
-
-        let local_object_path =
-            store::object_path(root_path, &upload.object_hash);
-
-        db::transfer::set_state(connection, upload, TransferState::Running)?;
 
-        println!("Upload {}/{}", i, upload_count);
-        println!("\tUploading {} to {}", local_object_path.display(), key,);
-
-        let result = upload_multipart(
-            &client,
-            &remote_info.bucket,
-            &key,
-            &local_object_path,
-            force,
-        )
-        .await;
-
-        if result.is_ok() || ignore_existing {
-            db::transfer::set_state(
+    // The closure runs when the stream pulls the next upload, which is when
+    // the upload actually starts.
+    let mut finished = stream::iter(&uploads)
+        .map(|upload| {
+            let running = db::transfer::set_state(
                 connection,
                 upload,
-                TransferState::Complete,
-            )?;
-        } else {
-            db::transfer::set_state(connection, upload, TransferState::Failed)?;
-            return Err(result.unwrap_err());
+                TransferState::Running,
+            );
+            async move {
+                let result = match running {
+                    Ok(_) => {
+                        upload_object(
+                            backend,
+                            root_path,
+                            &upload.object_hash,
+                            force,
+                            ignore_existing,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                (upload, result)
+            }
+        })
+        .buffer_unordered(jobs.max(1));
+
+    let mut done = 0;
+    let mut failures = 0;
+    while let Some((upload, result)) = finished.next().await {
+        done += 1;
+        match result {
+            Ok(_) => {
+                println!(
+                    "Upload {}/{}: {}",
+                    done, upload_count, upload.object_hash
+                );
+                db::transfer::set_state(
+                    connection,
+                    upload,
+                    TransferState::Complete,
+                )?;
+            }
+            Err(e) => {
+                failures += 1;
+                eprintln!(
+                    "Upload {}/{} failed: {}: {}",
+                    done, upload_count, upload.object_hash, e
+                );
+                db::transfer::set_state(
+                    connection,
+                    upload,
+                    TransferState::Failed,
+                )?;
+            }
         }
     }
-    db::migration::set_state(connection, migration, MigrationState::Complete)?;
-    Ok(())
+
+    finish(connection, migration, failures)
+}
+
+async fn upload_object(
+    backend: &dyn ObjectBackend,
+    root_path: &Path,
+    hash: &str,
+    force: bool,
+    ignore_existing: bool,
+) -> Result<()> {
+    // Objects are addressed by their contents, if the remote already has
+    // the object there is nothing left to do.
+    if ignore_existing && backend.head_object(hash).await? {
+        return Ok(());
+    }
+
+    // Objects are uploaded the way they're stored, compressed or not
+    let local_object_path = store::find_object(root_path, hash)
+        .ok_or(anyhow!("object {} is not in the store", hash))?;
+    let compression = store::object_compression(&local_object_path);
+    backend
+        .put_object(hash, compression, &local_object_path, force)
+        .await
 }
 
 pub async fn run_download(
     connection: &Connection,
+    backend: &dyn ObjectBackend,
     root_path: &Path,
     migration: &Migration,
     force: bool,
     ignore_existing: bool,
+    jobs: usize,
 ) -> Result<()> {
     let downloads =
         db::transfer::get_waiting_for_migration(connection, &migration.id)?;
     let download_count = downloads.len();
-
-    let client = make_client().await;
-
-    let remote_info = RemoteInfo::from_url(&migration.remote_location)?;
+    let hasher = Hasher::for_repository(connection)?;
 
     println!("Downloading {} files", download_count);
     db::migration::set_state(connection, migration, MigrationState::Running)?;
-    for (i, download) in downloads.iter().enumerate() {
-        let key = remote_info.object_key(&download.object_hash);
-
-        let local_file_path =
-            store::object_path(root_path, &download.object_hash);
-
-        println!("Download {}/{}", i, download_count);
-        println!("Downloading {} to {}", key, local_file_path.display());
-
-        // If the file is already in our store skip it
-        //
-        // If we've set force, overwrite the file
-        // If We've set ignore_existing continue
-        if store::exists(root_path, &download.object_hash) {
-            if ignore_existing {
-                continue;
-            }
-            if !force {
-                return Err(anyhow!("File already found: {}, set `force` to override or ignore_existing to ignore", &download.object_hash));
-            }
-        }
 
-        let mut output_file = File::create(&local_file_path)?;
-        let result = download_object(
-            &client,
-            &remote_info.bucket,
-            &key,
-            &mut output_file,
-        )
-        .await;
+    let mut finished = stream::iter(&downloads)
+        .map(|download| {
+            let running = db::transfer::set_state(
+                connection,
+                download,
+                TransferState::Running,
+            );
+            async move {
+                let result = match running {
+                    Ok(_) => {
+                        download_object(
+                            backend,
+                            &hasher,
+                            root_path,
+                            &download.object_hash,
+                            force,
+                            ignore_existing,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                (download, result)
+            }
+        })
+        .buffer_unordered(jobs.max(1));
 
+    let mut done = 0;
+    let mut failures = 0;
+    while let Some((download, result)) = finished.next().await {
+        done += 1;
         match result {
-            Ok(_) => {
+            Ok(compression) => {
+                println!(
+                    "Download {}/{}: {}",
+                    done, download_count, download.object_hash
+                );
+                store::record_compression(connection, compression)?;
                 db::transfer::set_state(
                     connection,
                     download,
                     TransferState::Complete,
                 )?;
-                Ok(())
             }
             Err(e) => {
+                failures += 1;
+                eprintln!(
+                    "Download {}/{} failed: {}: {}",
+                    done, download_count, download.object_hash, e
+                );
                 db::transfer::set_state(
                     connection,
                     download,
                     TransferState::Failed,
                 )?;
-                Err(e)
             }
-        }?
+        }
+    }
+
+    finish(connection, migration, failures)
+}
+
+// Returns the compression the object was stored with, none when it was
+// already in the store.
+async fn download_object(
+    backend: &dyn ObjectBackend,
+    hasher: &Hasher,
+    root_path: &Path,
+    hash: &str,
+    force: bool,
+    ignore_existing: bool,
+) -> Result<Compression> {
+    // If the file is already in our store skip it
+    //
+    // If we've set force, overwrite the file
+    // If We've set ignore_existing continue
+    if store::exists(root_path, hash) {
+        if ignore_existing {
+            return Ok(Compression::None);
+        }
+        if !force {
+            return Err(anyhow!("File already found: {}, set `force` to override or ignore_existing to ignore", hash));
+        }
+    }
+
+    // Objects are downloaded next to the store and only moved into it once
+    // their contents are known to match their hash, a failed or corrupt
+    // download never leaves a bad object behind.
+    let partial_path = store::partial_object_path(root_path, hash);
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let result = download_verified(backend, hasher, hash, &partial_path).await;
+    if result.is_err() {
+        _ = fs::remove_file(&partial_path);
+    }
+    let compression = result?;
+
+    fs::rename(
+        &partial_path,
+        store::encoded_object_path(root_path, hash, compression),
+    )?;
+    Ok(compression)
+}
+
+// Downloads the object and checks the hash of its uncompressed contents,
+// returning the compression it was downloaded with.
+async fn download_verified(
+    backend: &dyn ObjectBackend,
+    hasher: &Hasher,
+    hash: &str,
+    destination: &Path,
+) -> Result<Compression> {
+    let compression = backend.get_object(hash, destination).await?;
+
+    // Hashing large objects takes a while, keep it off of the threads
+    // driving the other transfers.
+    let path = destination.to_path_buf();
+    let hasher = *hasher;
+    let downloaded_hash = tokio::task::spawn_blocking(move || {
+        hasher.hash_reader(store::read_object_file(&path, compression)?)
+    })
+    .await??;
+
+    if downloaded_hash != hash {
+        return Err(anyhow!(
+            "corrupt download: {} has contents hashing to {}",
+            hash,
+            downloaded_hash
+        ));
+    }
+    Ok(compression)
+}
+
+// Record the outcome of a migration once all of its transfers have finished
+fn finish(
+    connection: &Connection,
+    migration: &Migration,
+    failures: usize,
+) -> Result<()> {
+    if failures > 0 {
+        db::migration::set_state(
+            connection,
+            migration,
+            MigrationState::Failed,
+        )?;
+        return Err(anyhow!(
+            "{} transfers failed in migration {}",
+            failures,
+            migration.id
+        ));
+    }
+
+    db::migration::set_state(connection, migration, MigrationState::Complete)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::backend::memory::MemoryBackend;
+    use crate::models::remote::Remote;
+    use crate::testing::TempDir;
+    use crate::types::hash_algorithm::HashAlgorithm;
+    use crate::types::remote_kind::RemoteKind;
+
+    // The directory is removed when the TempDir is dropped
+    fn test_repo() -> Result<(TempDir, PathBuf, Connection)> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path().to_path_buf();
+        store::init(&root_path)?;
+
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+        db::remote::insert(
+            &connection,
+            &Remote::new("origin", RemoteKind::Local, "local:///unused")?,
+        )?;
+        Ok((dir, root_path, connection))
+    }
+
+    #[tokio::test]
+    async fn test_upload_then_download() -> Result<()> {
+        let backend = MemoryBackend::new();
+
+        let (_source_dir, source_root, source) = test_repo()?;
+        let file_path = source_root.join("a.txt");
+        fs::write(&file_path, "contents of a")?;
+        let hash = Hasher::default().hash_file(&file_path)?;
+        store::insert_from(&source_root, &hash, &file_path, Compression::None)?;
+
+        let upload = create(
+            &source,
+            TransferKind::Upload,
+            MigrationAction::Push,
+            "origin",
+            std::slice::from_ref(&hash),
+            None,
+        )?;
+        run(
+            &source,
+            &backend,
+            &source_root,
+            &upload,
+            false,
+            true,
+            DEFAULT_JOBS,
+        )
+        .await?;
+
+        assert!(backend.head_object(&hash).await?);
+        let complete = db::transfer::get_all_with_state(
+            &source,
+            &upload.id,
+            TransferState::Complete,
+        )?;
+        assert_eq!(complete.len(), 1);
+
+        let (_destination_dir, destination_root, destination) = test_repo()?;
+        let download = create(
+            &destination,
+            TransferKind::Download,
+            MigrationAction::Fetch,
+            "origin",
+            std::slice::from_ref(&hash),
+            None,
+        )?;
+        run(
+            &destination,
+            &backend,
+            &destination_root,
+            &download,
+            false,
+            true,
+            DEFAULT_JOBS,
+        )
+        .await?;
+
+        assert_eq!(
+            fs::read_to_string(store::object_path(&destination_root, &hash))?,
+            "contents of a"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_transfer_does_not_stop_others() -> Result<()> {
+        let backend = MemoryBackend::new();
+        let (_dir, root_path, connection) = test_repo()?;
+
+        let file_path = root_path.join("a.txt");
+        fs::write(&file_path, "contents of a")?;
+        let hash = Hasher::default().hash_file(&file_path)?;
+        store::insert_from(&root_path, &hash, &file_path, Compression::None)?;
+        backend
+            .put_object(&hash, Compression::None, &file_path, false)
+            .await?;
+        fs::remove_file(store::object_path(&root_path, &hash))?;
+
+        let hashes = vec![String::from("missing"), hash.clone()];
+        let download = create(
+            &connection,
+            TransferKind::Download,
+            MigrationAction::Fetch,
+            "origin",
+            &hashes,
+            None,
+        )?;
+        let result =
+            run(&connection, &backend, &root_path, &download, false, true, 2)
+                .await;
+        assert!(result.is_err());
+
+        let complete = db::transfer::get_all_with_state(
+            &connection,
+            &download.id,
+            TransferState::Complete,
+        )?;
+        let failed = db::transfer::get_all_with_state(
+            &connection,
+            &download.id,
+            TransferState::Failed,
+        )?;
+        assert_eq!(complete.len(), 1);
+        assert_eq!(complete[0].object_hash, hash);
+        assert_eq!(failed.len(), 1);
+        assert!(store::exists(&root_path, &hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_keeps_failed_migrations() -> Result<()> {
+        let (_dir, _, connection) = test_repo()?;
+        let hashes = vec![String::from("a"), String::from("b")];
+
+        let complete = create(
+            &connection,
+            TransferKind::Upload,
+            MigrationAction::Push,
+            "origin",
+            &hashes,
+            None,
+        )?;
+        db::migration::set_state(
+            &connection,
+            &complete,
+            MigrationState::Complete,
+        )?;
+        let failed = create(
+            &connection,
+            TransferKind::Upload,
+            MigrationAction::Push,
+            "origin",
+            &hashes,
+            None,
+        )?;
+        db::migration::set_state(&connection, &failed, MigrationState::Failed)?;
+
+        // Nothing is old enough yet
+        let states = [MigrationState::Complete];
+        assert_eq!(prune(&connection, SECONDS_PER_DAY, &states)?, 0);
+
+        assert_eq!(prune(&connection, 0, &states)?, 1);
+        let remaining = db::migration::get_all(&connection)?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, failed.id);
+        assert!(db::transfer::get_all(&connection, &complete.id)?.is_empty());
+        assert_eq!(db::transfer::get_all(&connection, &failed.id)?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_download_fails() -> Result<()> {
+        let backend = MemoryBackend::new();
+        let (_dir, root_path, connection) = test_repo()?;
+
+        let file_path = root_path.join("a.txt");
+        fs::write(&file_path, "contents of a")?;
+        let hash = Hasher::default().hash_file(&file_path)?;
+        backend.objects.lock().unwrap().insert(
+            hash.clone(),
+            (Compression::None, b"not the contents of a".to_vec()),
+        );
+
+        let download = create(
+            &connection,
+            TransferKind::Download,
+            MigrationAction::Fetch,
+            "origin",
+            std::slice::from_ref(&hash),
+            None,
+        )?;
+        let result = run(
+            &connection,
+            &backend,
+            &root_path,
+            &download,
+            false,
+            true,
+            DEFAULT_JOBS,
+        )
+        .await;
+        assert!(result.is_err());
+
+        let failed = db::transfer::get_all_with_state(
+            &connection,
+            &download.id,
+            TransferState::Failed,
+        )?;
+        assert_eq!(failed.len(), 1);
+        assert!(!store::exists(&root_path, &hash));
+        assert!(!store::partial_object_path(&root_path, &hash).exists());
+        Ok(())
     }
-    db::migration::set_state(connection, migration, MigrationState::Complete)?;
-    Ok(())
 }
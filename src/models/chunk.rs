@@ -0,0 +1,11 @@
+/* A piece of a file stored in the object store as an object of its own,
+ * named by the hash of its contents. A file stored in chunks is rebuilt by
+ * writing its chunks out in order of position.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub file_hash: String,
+    pub position: i64,
+    pub chunk_hash: String,
+    pub size_bytes: i64,
+}
@@ -2,6 +2,8 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
 
+use crate::hash::Hasher;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Commit {
     pub hash: String,
@@ -9,6 +11,9 @@ pub struct Commit {
     pub author: String,
     pub created_unix_timestamp: u64,
     pub parent_hash: Option<String>,
+    // Who made the commit, when that's not the author, for example when it
+    // was rebased. Empty for commits from before committers were recorded.
+    pub committer: String,
 }
 
 impl Commit {
@@ -25,8 +30,98 @@ impl Commit {
             message: message.to_string(),
             author: author.to_string(),
             created_unix_timestamp: time.as_secs(),
+            committer: String::new(),
         })
     }
+
+    /* A new commit of files, given as (path, file hash) pairs, named by the
+     * hash the repository gives it. Repositories from before hash version
+     * 3 still name commits by their file hashes alone, in the order given.
+     */
+    pub fn create(
+        hasher: &Hasher,
+        files: &[(String, String)],
+        message: &str,
+        author: &str,
+        committer: &str,
+        parent_hash: Option<String>,
+    ) -> Result<Commit> {
+        let mut commit = Commit::new("", message, author, parent_hash)?;
+        commit.committer = committer.to_string();
+        commit.hash = if hasher.hashes_canonical_commits() {
+            commit.canonical_hash(hasher, files)
+        } else {
+            let hashes: Vec<&str> =
+                files.iter().map(|(_, h)| h.as_str()).collect();
+            hasher.hash_string(hashes.join(""))
+        };
+        Ok(commit)
+    }
+
+    // The hash of the commit's canonical serialization with these files
+    pub fn canonical_hash(
+        &self,
+        hasher: &Hasher,
+        files: &[(String, String)],
+    ) -> String {
+        hasher.hash_bytes(&self.serialize(&tree_digest(hasher, files)))
+    }
+
+    /* The canonical serialization of a commit, what its hash covers
+     *
+     *     sssync commit\n
+     *     tree <tree digest>\n
+     *     parent <parent hash>\n
+     *     author <length> <author>\n
+     *     committer <length> <committer>\n
+     *     timestamp <created unix timestamp>\n
+     *     message <length>\n
+     *     <message>
+     *
+     * There's one parent line per parent, none for a first commit, and no
+     * committer line for commits from before committers were recorded.
+     * Lengths are in bytes, so authors and messages can hold any text,
+     * newlines included, without two commits serializing the same.
+     */
+    fn serialize(&self, tree_digest: &str) -> Vec<u8> {
+        let mut out = format!("sssync commit\ntree {}\n", tree_digest);
+        if let Some(parent) = &self.parent_hash {
+            out.push_str(&format!("parent {}\n", parent));
+        }
+        out.push_str(&format!(
+            "author {} {}\n",
+            self.author.len(),
+            self.author
+        ));
+        if !self.committer.is_empty() {
+            out.push_str(&format!(
+                "committer {} {}\n",
+                self.committer.len(),
+                self.committer
+            ));
+        }
+        out.push_str(&format!(
+            "timestamp {}\nmessage {}\n{}",
+            self.created_unix_timestamp,
+            self.message.len(),
+            self.message
+        ));
+        out.into_bytes()
+    }
+}
+
+/* The hash of a commit's files, each written as `<path>\0<file hash>\n` in
+ * order of path. Paths can't hold a nul, so no two trees write out the
+ * same.
+ */
+pub fn tree_digest(hasher: &Hasher, files: &[(String, String)]) -> String {
+    let mut sorted: Vec<&(String, String)> = files.iter().collect();
+    sorted.sort();
+    let mut out = String::new();
+    for (path, file_hash) in sorted {
+        out.push_str(&format!("{}\0{}\n", path, file_hash));
+    }
+    hasher.hash_string(out)
 }
 
 // Look back through two commit lists and find a shared parent
@@ -54,32 +149,16 @@ impl Commit {
 //      assert_eq!(result, Some(commit_b));
 //
 pub fn get_shared_parent(left: &[Commit], right: &[Commit]) -> Result<Commit> {
-    let mut left_i = left.len() - 1;
-    let mut right_i = right.len() - 1;
-    let mut n = 0;
-
     // Walk both lists in reverse order (starting from the first commit)
-    // look for the first commit that is different, the shared parent
-    // is the commit before this one.
-    while let (Some(l), Some(r)) = (left.get(left_i), right.get(right_i)) {
-        // end of one of the lists
-        if left_i == 0 || right_i == 0 {
-            break;
-        }
+    // until they differ, the shared parent is the last commit they agree on.
+    let mut shared_parent = None;
+    for (l, r) in left.iter().rev().zip(right.iter().rev()) {
         if l.hash != r.hash {
-            // oops the very first commit doesn't match
-            // something bad happened
-            if n == 0 {
-                return Err(anyhow!("No shared parent"));
-            }
-            return Ok(l.clone());
+            break;
         }
-        left_i -= 1;
-        right_i -= 1;
-        n += 1;
+        shared_parent = Some(l);
     }
-
-    Ok(left[left_i].clone())
+    shared_parent.cloned().ok_or(anyhow!("No shared parent"))
 }
 
 /* Takes a list of ordered commits and returns the tail of that list
@@ -146,6 +225,51 @@ pub fn diff_commit_list_left(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_canonical_hash_covers_every_field() -> Result<()> {
+        let hasher = Hasher::default();
+        let files = vec![
+            (String::from("b"), String::from("2")),
+            (String::from("a"), String::from("1")),
+        ];
+        let mut commit = Commit::new("", "first\nline", "me", None)?;
+        commit.created_unix_timestamp = 10;
+        commit.parent_hash = Some(String::from("p"));
+        assert_eq!(
+            String::from_utf8(commit.serialize("t"))?,
+            "sssync commit\ntree t\nparent p\nauthor 2 me\ntimestamp 10\n\
+             message 10\nfirst\nline"
+        );
+
+        let hash = commit.canonical_hash(&hasher, &files);
+        let reordered: Vec<(String, String)> =
+            files.iter().rev().cloned().collect();
+        assert_eq!(commit.canonical_hash(&hasher, &reordered), hash);
+
+        for changed in [
+            Commit {
+                parent_hash: None,
+                ..commit.clone()
+            },
+            Commit {
+                author: String::from("you"),
+                ..commit.clone()
+            },
+            Commit {
+                message: String::from("first"),
+                ..commit.clone()
+            },
+            Commit {
+                created_unix_timestamp: 11,
+                ..commit.clone()
+            },
+        ] {
+            assert_ne!(changed.canonical_hash(&hasher, &files), hash);
+        }
+        assert_ne!(commit.canonical_hash(&hasher, &files[..1]), hash);
+        Ok(())
+    }
+
     #[test]
     fn test_get_shared_parent_with_equal_lists() -> Result<()> {
         let commit_a = Commit::new("a", "", "", None)?;
@@ -187,6 +311,43 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_shared_parent_with_diverged_lists() -> Result<()> {
+        let commit_a = Commit::new("a", "", "", None)?;
+        let commit_b = Commit::new("b", "", "", Some(String::from("a")))?;
+        let commit_c = Commit::new("c", "", "", Some(String::from("b")))?;
+        let commit_d = Commit::new("d", "", "", Some(String::from("c")))?;
+        let commit_e = Commit::new("e", "", "", Some(String::from("a")))?;
+
+        let left = vec![
+            commit_d.clone(),
+            commit_c.clone(),
+            commit_b.clone(),
+            commit_a.clone(),
+        ];
+        let right = vec![commit_e.clone(), commit_a.clone()];
+
+        assert_eq!(get_shared_parent(&left, &right)?, commit_a);
+        assert_eq!(get_shared_parent(&right, &left)?, commit_a);
+        match diff_commit_list(&left, &right) {
+            CompareResult::Diff {
+                left: left_diff,
+                right: right_diff,
+                ..
+            } => {
+                assert_eq!(left_diff, vec![commit_d, commit_c, commit_b]);
+                assert_eq!(right_diff, vec![commit_e]);
+            }
+            CompareResult::NoSharedParent => {
+                return Err(anyhow!("should be a diff"))
+            }
+        }
+
+        let unrelated = vec![Commit::new("f", "", "", None)?];
+        assert!(get_shared_parent(&left, &unrelated).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_diff_commit_list_right_longer() -> Result<()> {
         let commit_a = Commit::new("a", "", "", None)?;
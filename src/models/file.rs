@@ -1,37 +1,65 @@
-use std::collections::HashSet;
 use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use anyhow::Result;
 
-fn default_ignore() -> HashSet<String> {
-    let mut ignore = HashSet::new();
-    ignore.insert(".sssync".to_string());
-    ignore
+use crate::ignores::Ignores;
+
+/* Every file in the working directory below root, relative to it, leaving
+ * out anything .sssyncignore files say to ignore.
+ */
+pub fn get_all(root: &Path) -> Result<Vec<PathBuf>> {
+    get_all_in(root, Path::new(""))
 }
 
-fn should_ignore(ignore: &HashSet<String>, path: &Path) -> bool {
-    match path.to_str() {
-        Some(path_str) => ignore.contains(&path_str.to_string()),
-        None => true,
-    }
+// Every file below the directory rel_dir, relative to root
+pub fn get_all_in(root: &Path, rel_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    walk_in(root, rel_dir, &mut |path, is_dir| {
+        if !is_dir {
+            files.push(path)
+        }
+    })?;
+    Ok(files)
 }
 
-pub fn get_all(root: &Path) -> Result<Vec<PathBuf>> {
-    get_all_inner(root, PathBuf::from(""), &default_ignore())
+// rel_dir and every directory below it, relative to root
+pub fn get_dirs_in(root: &Path, rel_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![rel_dir.to_path_buf()];
+    walk_in(root, rel_dir, &mut |path, is_dir| {
+        if is_dir {
+            dirs.push(path)
+        }
+    })?;
+    Ok(dirs)
 }
 
-fn get_all_inner(
+/* Calls found with every file and directory below rel_dir that isn't
+ * ignored, relative to root, with the rules of every .sssyncignore on the
+ * way down to rel_dir applied.
+ */
+fn walk_in(
     root: &Path,
-    rel_path: PathBuf,
-    ignore: &HashSet<String>,
-) -> Result<Vec<PathBuf>> {
-    let mut results: Vec<PathBuf> = Vec::new();
-
-    if should_ignore(ignore, &rel_path) {
-        return Ok(results);
+    rel_dir: &Path,
+    found: &mut impl FnMut(PathBuf, bool),
+) -> Result<()> {
+    let mut ignores = Ignores::new(root)?;
+    let mut dir = PathBuf::new();
+    for component in rel_dir.components() {
+        dir.push(component);
+        ignores.enter(&dir)?;
     }
+    walk_inner(&root.join(rel_dir), dir, &mut ignores, found)
+}
+
+fn walk_inner(
+    root: &Path,
+    rel_path: PathBuf,
+    ignores: &mut Ignores,
+    found: &mut impl FnMut(PathBuf, bool),
+) -> Result<()> {
     let contents = fs::read_dir(root)?;
 
     for entry in contents {
@@ -40,31 +68,41 @@ fn get_all_inner(
         let mut next_path = rel_path.clone();
         next_path.push(entry.file_name());
 
-        if path.is_dir() {
-            let sub_results = get_all_inner(&path, next_path, ignore)?;
-            results.extend(sub_results);
-        } else {
-            results.push(next_path);
+        let is_dir = path.is_dir();
+        if ignores.is_ignored(&next_path, is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            ignores.enter(&next_path)?;
+            let result = walk_inner(&path, next_path.clone(), ignores, found);
+            ignores.leave();
+            result?;
         }
+        found(next_path, is_dir);
     }
-    Ok(results)
+    Ok(())
 }
 
 pub struct FileMeta {
     pub size_bytes: i64,
     pub modified_time_seconds: i64,
+    pub modified_time_nanos: i64,
+    pub inode: i64,
 }
 
+// Links are followed, a file checked out as a link to an object reports the
+// size of the object rather than of the link.
 pub fn metadata(path: &Path) -> Result<FileMeta> {
-    let res = fs::symlink_metadata(path)?;
+    let res = fs::metadata(path)?;
 
-    let modified_time_seconds = res
-        .modified()?
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs() as i64;
+    let modified_time =
+        res.modified()?.duration_since(SystemTime::UNIX_EPOCH)?;
 
     Ok(FileMeta {
-        modified_time_seconds,
+        modified_time_seconds: modified_time.as_secs() as i64,
+        modified_time_nanos: modified_time.as_nanos() as i64,
         size_bytes: res.len() as i64,
+        inode: res.ino() as i64,
     })
 }
@@ -1,12 +1,24 @@
+use crate::hash::HASH_VERSION;
+use crate::store::FORMAT_VERSION;
+use crate::types::hash_algorithm::HashAlgorithm;
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct Meta {
     pub head: String,
+    // Layout of the object store, see store::FORMAT_VERSION
+    pub format_version: u32,
+    // Encoding of hashes, see hash::HASH_VERSION
+    pub hash_version: u32,
+    pub hash_algorithm: HashAlgorithm,
 }
 
 impl Meta {
-    pub fn new(head: &str) -> Self {
+    pub fn new(head: &str, hash_algorithm: HashAlgorithm) -> Self {
         Self {
             head: String::from(head),
+            format_version: FORMAT_VERSION,
+            hash_version: HASH_VERSION,
+            hash_algorithm,
         }
     }
 }
@@ -1,13 +1,18 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use clap::ValueEnum;
 use rusqlite::types::{
     FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
 };
 use uuid::Uuid;
 
+use crate::models::reference::Reference;
 use crate::models::remote::Remote;
 use crate::models::transfer::TransferKind;
 use crate::types::remote_kind::RemoteKind;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, ValueEnum)]
 pub enum MigrationState {
     Waiting,
     Running,
@@ -54,6 +59,59 @@ impl ToSql for MigrationState {
     }
 }
 
+/* The command that created a migration. Once its transfers are done each
+ * command has a step of its own left to do, resuming a migration needs to
+ * know which one to run.
+ */
+#[derive(Debug)]
+pub enum MigrationAction {
+    Init,
+    Push,
+    Fetch,
+    Clone,
+    Fsck,
+}
+
+impl MigrationAction {
+    pub fn parse(s: &str) -> Result<MigrationAction, String> {
+        match s {
+            "Init" => Ok(MigrationAction::Init),
+            "Push" => Ok(MigrationAction::Push),
+            "Fetch" => Ok(MigrationAction::Fetch),
+            "Clone" => Ok(MigrationAction::Clone),
+            "Fsck" => Ok(MigrationAction::Fsck),
+            _ => Err(format!("invalid Migration action: {}", s)),
+        }
+    }
+
+    pub fn to_str(&self) -> &str {
+        match self {
+            MigrationAction::Init => "Init",
+            MigrationAction::Push => "Push",
+            MigrationAction::Fetch => "Fetch",
+            MigrationAction::Clone => "Clone",
+            MigrationAction::Fsck => "Fsck",
+        }
+    }
+}
+
+impl FromSql for MigrationAction {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()
+            .and_then(|s| match MigrationAction::parse(s) {
+                Ok(a) => Ok(a),
+                Err(_) => Err(FromSqlError::InvalidType),
+            })
+    }
+}
+
+impl ToSql for MigrationAction {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_str()))
+    }
+}
+
 pub fn print_table(items: Vec<Migration>) {
     for i in items {
         print_migration_tabular(i)
@@ -62,8 +120,15 @@ pub fn print_table(items: Vec<Migration>) {
 
 fn print_migration_tabular(m: Migration) {
     println!(
-        "|{}, {:?}, {}, {}, {}, {:?}|",
-        m.id, m.kind, m.remote_location, m.remote_kind, m.remote_name, m.state
+        "|{}, {}, {:?}, {}, {}, {}, {:?}, {:?}|",
+        m.id,
+        m.created_unix_timestamp,
+        m.kind,
+        m.remote_location,
+        m.remote_kind,
+        m.remote_name,
+        m.state,
+        m.action
     )
 }
 
@@ -74,17 +139,34 @@ pub struct Migration {
     pub remote_kind: RemoteKind,
     pub remote_name: String,
     pub state: MigrationState,
+    // Migrations created before actions were recorded don't have one
+    pub action: Option<MigrationAction>,
+    pub created_unix_timestamp: u64,
+    // The branch a push publishes and the commit it points it at once every
+    // object is uploaded. Only pushes record them.
+    pub ref_name: Option<String>,
+    pub commit_hash: Option<String>,
 }
 
 impl Migration {
-    pub fn new(kind: TransferKind, remote: &Remote) -> Self {
-        Self {
+    pub fn new(
+        kind: TransferKind,
+        action: MigrationAction,
+        remote: &Remote,
+        head: Option<&Reference>,
+    ) -> Result<Self> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        Ok(Self {
             kind,
             id: Uuid::new_v4().hyphenated().to_string(),
             state: MigrationState::Waiting,
             remote_location: remote.location.to_string(),
             remote_kind: remote.kind,
             remote_name: remote.name.to_string(),
-        }
+            action: Some(action),
+            created_unix_timestamp: time.as_secs(),
+            ref_name: head.map(|r| r.name.clone()),
+            commit_hash: head.map(|r| r.hash.clone()),
+        })
     }
 }
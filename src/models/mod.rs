@@ -1,3 +1,4 @@
+pub mod chunk;
 pub mod commit;
 pub mod file;
 pub mod meta;
@@ -5,6 +6,8 @@ pub mod migration;
 pub mod reference;
 pub mod remote;
 pub mod staged_file;
+pub mod stat_cache;
 pub mod status;
 pub mod transfer;
 pub mod tree_file;
+pub mod watcher;
@@ -3,13 +3,32 @@ use std::path::Path;
 use anyhow::Result;
 use url::{ParseError, Url};
 
+use crate::store;
+use crate::store::COMPRESSED_FORMAT_VERSION;
+use crate::types::compression::Compression;
 use crate::types::remote_kind::RemoteKind;
 
-pub fn remote_object_path(url: &str, hash: &str) -> Result<Url, ParseError> {
+/* Where the object is in a remote at format_version, laid out the way the
+ * backends write it. From format version 3 the name depends on the
+ * compression it was uploaded with.
+ */
+pub fn remote_object_path(
+    url: &str,
+    hash: &str,
+    format_version: u32,
+    compression: Compression,
+) -> Result<Url, ParseError> {
     let u = Url::parse(url)?;
 
+    let name = if format_version < 2 {
+        hash.to_string()
+    } else if format_version < COMPRESSED_FORMAT_VERSION {
+        store::object_name(hash)
+    } else {
+        store::encoded_object_name(hash, compression)
+    };
     let remote_directory = Path::new(u.path());
-    let p = remote_directory.join(".sssync/objects").join(hash);
+    let p = remote_directory.join(".sssync/objects").join(name);
     let new_url = format!(
         "{scheme}://{host_str}{path}",
         scheme = u.scheme(),
@@ -19,10 +38,26 @@ pub fn remote_object_path(url: &str, hash: &str) -> Result<Url, ParseError> {
     Url::parse(&new_url)
 }
 
+/* Settings for reaching S3 compatible services like MinIO, Ceph or R2.
+ *
+ * Anything left unset falls back to the standard AWS configuration found in
+ * the environment.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct S3Settings {
+    pub endpoint_url: Option<String>,
+    pub region: Option<String>,
+    pub force_path_style: bool,
+    pub profile: Option<String>,
+}
+
 pub struct Remote {
     pub name: String,
     pub kind: RemoteKind,
     pub location: String,
+    pub s3: S3Settings,
+    // Where to load the key encrypting the remote from, see encryption
+    pub encryption_key: Option<String>,
 }
 
 impl Remote {
@@ -31,6 +66,8 @@ impl Remote {
             kind,
             name: name.to_string(),
             location: location.to_string(),
+            s3: S3Settings::default(),
+            encryption_key: None,
         })
     }
 }
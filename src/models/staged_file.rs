@@ -1,7 +1,6 @@
 use std::path::{Path, PathBuf};
 
-use crate::hash::hash_file;
-use crate::models::file::metadata;
+use crate::models::file::FileMeta;
 use crate::models::tree_file::TreeFile;
 use anyhow::{anyhow, Result};
 use rusqlite::types::{
@@ -18,10 +17,11 @@ pub struct StagedFile {
 }
 
 impl StagedFile {
-    pub fn new(full_path: &Path, relative_path: &Path) -> Result<Self> {
-        let meta = metadata(full_path)?;
-
-        let file_hash = hash_file(full_path)?;
+    pub fn new(
+        relative_path: &Path,
+        meta: &FileMeta,
+        file_hash: String,
+    ) -> Result<Self> {
         let relative_path_str = relative_path
             .to_str()
             .ok_or(anyhow!("Invalid path: {}", relative_path.display()))?;
@@ -42,19 +42,11 @@ impl StagedFile {
             commit_hash: String::from(commit_hash),
         }
     }
-
-    // Lstat the file found at path and compare the results to the StagedFile
-    // compares size_bytes and modified_time. Use this function to help
-    // avoid expensive file hashes.
-    pub fn compare_metadata(&self, path: &Path) -> Result<bool> {
-        let meta = metadata(path)?;
-        Ok(self.size_bytes == meta.size_bytes
-            && self.modified_time_seconds == meta.modified_time_seconds)
-    }
 }
 
 pub enum ChangeKind {
     Addition,
+    Modification,
     Deletion,
 }
 
@@ -62,6 +54,7 @@ impl ChangeKind {
     pub fn parse(s: &str) -> Result<ChangeKind, String> {
         match s {
             "addition" => Ok(ChangeKind::Addition),
+            "modification" => Ok(ChangeKind::Modification),
             "deletion" => Ok(ChangeKind::Deletion),
             _ => Err(format!("invalid Change kind: {}", s)),
         }
@@ -70,6 +63,7 @@ impl ChangeKind {
     pub fn to_str(&self) -> &str {
         match self {
             ChangeKind::Addition => "addition",
+            ChangeKind::Modification => "modification",
             ChangeKind::Deletion => "deletion",
         }
     }
@@ -92,5 +86,7 @@ impl ToSql for ChangeKind {
 
 pub enum Change {
     Addition(StagedFile),
+    // New contents for a file tracked at HEAD
+    Modification(StagedFile),
     Deletion(PathBuf),
 }
@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+
+use crate::db;
+use crate::hash::Hasher;
+use crate::models::file::FileMeta;
+
+/* The hash of a file in the working directory, along with what its stat
+ * looked like when it was hashed. As long as the file's size, modified
+ * time and inode haven't changed it's taken to still have that hash and
+ * isn't read again.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatCacheEntry {
+    pub path: String,
+    pub size_bytes: i64,
+    pub modified_time_nanos: i64,
+    pub inode: i64,
+    pub file_hash: String,
+}
+
+/* Files modified this close to being hashed aren't cached. Filesystems that
+ * keep coarse modified times could give a write landing just after the file
+ * was hashed the same time, leaving its stat looking unchanged.
+ */
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+impl StatCacheEntry {
+    pub fn matches(&self, meta: &FileMeta) -> bool {
+        self.size_bytes == meta.size_bytes
+            && self.modified_time_nanos == meta.modified_time_nanos
+            && self.inode == meta.inode
+    }
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or(anyhow!("Invalid path: {}", path.display()))
+}
+
+// The nanosecond timestamp files must be modified before to be cached
+pub fn racy_after() -> Result<i64> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    Ok(now.saturating_sub(RACY_WINDOW).as_nanos() as i64)
+}
+
+// The cached hash of the file at rel_path, if its stat is still meta
+pub fn get(
+    connection: &Connection,
+    rel_path: &Path,
+    meta: &FileMeta,
+) -> Result<Option<String>> {
+    Ok(db::stat_cache::get(connection, path_str(rel_path)?)?
+        .filter(|entry| entry.matches(meta))
+        .map(|entry| entry.file_hash))
+}
+
+/* Caches the hash of the file at rel_path, read while its stat was meta,
+ * unless it was modified after racy_after.
+ */
+pub fn remember(
+    connection: &Connection,
+    rel_path: &Path,
+    meta: &FileMeta,
+    file_hash: &str,
+    racy_after: i64,
+) -> Result<()> {
+    if meta.modified_time_nanos >= racy_after {
+        return Ok(());
+    }
+    db::stat_cache::insert(
+        connection,
+        &StatCacheEntry {
+            path: path_str(rel_path)?.to_string(),
+            size_bytes: meta.size_bytes,
+            modified_time_nanos: meta.modified_time_nanos,
+            inode: meta.inode,
+            file_hash: file_hash.to_string(),
+        },
+    )
+}
+
+/* The hash of the file at rel_path, whose stat is meta, read from the file
+ * only when the cache doesn't have it.
+ */
+pub fn hash(
+    connection: &Connection,
+    hasher: &Hasher,
+    root_path: &Path,
+    rel_path: &Path,
+    meta: &FileMeta,
+) -> Result<String> {
+    if let Some(file_hash) = get(connection, rel_path, meta)? {
+        return Ok(file_hash);
+    }
+    let racy_after = racy_after()?;
+    let file_hash = hasher.hash_file(&root_path.join(rel_path))?;
+    remember(connection, rel_path, meta, &file_hash, racy_after)?;
+    Ok(file_hash)
+}
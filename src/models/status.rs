@@ -7,12 +7,14 @@ use anyhow::Result;
 use rusqlite::Connection;
 
 use crate::db;
+use crate::hash::Hasher;
 use crate::models::commit::Commit;
 use crate::models::file;
 use crate::models::staged_file::{Change, StagedFile};
+use crate::models::stat_cache;
 use crate::models::tree_file::TreeFile;
-
-use crate::hash::hash_string;
+use crate::tree::{self, FilePair};
+use crate::watch;
 
 /* Status is a struct that contains derived information about the current
  * repository. It's the struct that is responsible for printing the "status"
@@ -23,16 +25,16 @@ pub struct Status {
     /* The set of files tracked at HEAD
      */
     pub tracked_files: HashMap<PathBuf, TreeFile>,
-    /* Staged changes can be either additions or deletions:
+    /* Staged changes can be additions, modifications or deletions:
      *
-     * For ease of use later we'll move them into hash sets for both
-     * additions and deletions.
+     * For ease of use later we'll move them into hash sets for each kind.
      */
     pub staged_additions: HashSet<PathBuf>,
+    pub staged_modifications: HashSet<PathBuf>,
     pub staged_deletions: HashSet<PathBuf>,
 
-    /* A staged addition could potentially have changed or been deleted
-     * since it's addition to the index. Conversely a staged deletion
+    /* A staged addition or modification could potentially have changed or
+     * been deleted since it's addition to the index. Conversely a staged deletion
      * could have the file appear again.
      */
     pub staged_but_changed: HashSet<PathBuf>,
@@ -44,11 +46,25 @@ pub struct Status {
      */
     pub unstaged_additions: Vec<PathBuf>,
 
+    /* Unstaged modifications are tracked files on disk whose contents
+     * differ from HEAD, and that aren't staged.
+     */
+    pub unstaged_modifications: Vec<PathBuf>,
+
     /* Unstaged deletions are files deleted from disk that are in the set of
      * tracked files, but not in the set of staged deletions.
      */
     pub unstaged_deletions: Vec<PathBuf>,
 
+    /* Additions with the contents of a deletion were renamed, and staged
+     * additions with the contents of a tracked file copied. Both map the
+     * path of the addition to the path it came from. Only new files the
+     * size of a deleted one are hashed, so unstaged copies aren't found.
+     */
+    pub staged_renames: HashMap<PathBuf, PathBuf>,
+    pub staged_copies: HashMap<PathBuf, PathBuf>,
+    pub unstaged_renames: HashMap<PathBuf, PathBuf>,
+
     /* The current commit */
     pub head: Option<Commit>,
     /* The current ref name */
@@ -72,15 +88,51 @@ impl fmt::Display for Status {
                     writeln!(w, "\tmodified: {}", f.to_str().unwrap())?;
                 } else if self.staged_but_deleted.contains(f) {
                     writeln!(w, "\tdeleted: {}", f.to_str().unwrap())?;
+                } else if let Some(from) = self.staged_renames.get(f) {
+                    writeln!(
+                        w,
+                        "\trenamed: {} -> {}",
+                        from.to_str().unwrap(),
+                        f.to_str().unwrap()
+                    )?;
+                } else if let Some(from) = self.staged_copies.get(f) {
+                    writeln!(
+                        w,
+                        "\tcopied: {} -> {}",
+                        from.to_str().unwrap(),
+                        f.to_str().unwrap()
+                    )?;
                 } else {
                     writeln!(w, "\tadded: {}", f.to_str().unwrap())?;
                 }
             }
         }
 
-        if !self.staged_deletions.is_empty() {
+        if !self.staged_modifications.is_empty() {
+            writeln!(w, "\nFiles staged to be modified:")?;
+            for f in &self.staged_modifications {
+                if self.staged_but_changed.contains(f) {
+                    writeln!(w, "\tmodified again: {}", f.to_str().unwrap())?;
+                } else if self.staged_but_deleted.contains(f) {
+                    writeln!(w, "\tdeleted: {}", f.to_str().unwrap())?;
+                } else {
+                    writeln!(w, "\tmodified: {}", f.to_str().unwrap())?;
+                }
+            }
+        }
+
+        // Renamed files are listed where they were moved to
+        let renamed: HashSet<&PathBuf> = self.staged_renames.values().collect();
+        let staged_deletions: Vec<&PathBuf> = self
+            .staged_deletions
+            .iter()
+            .filter(|f| {
+                !renamed.contains(f) || self.staged_but_added.contains(*f)
+            })
+            .collect();
+        if !staged_deletions.is_empty() {
             writeln!(w, "\nFiles staged to be deleted:")?;
-            for f in &self.staged_deletions {
+            for f in staged_deletions {
                 if self.staged_but_added.contains(f) {
                     writeln!(w, "\tadded: {}", f.to_str().unwrap())?;
                 } else {
@@ -92,13 +144,36 @@ impl fmt::Display for Status {
         if !self.unstaged_additions.is_empty() {
             writeln!(w, "\nUnstaged additions:")?;
             for f in &self.unstaged_additions {
+                if let Some(from) = self.unstaged_renames.get(f) {
+                    writeln!(
+                        w,
+                        "\trenamed: {} -> {}",
+                        from.to_str().unwrap(),
+                        f.to_str().unwrap()
+                    )?;
+                } else {
+                    writeln!(w, "\tadded: {}", f.to_str().unwrap())?;
+                }
+            }
+        }
+
+        if !self.unstaged_modifications.is_empty() {
+            writeln!(w, "\nUnstaged modifications:")?;
+            for f in &self.unstaged_modifications {
                 writeln!(w, "\tmodified: {}", f.to_str().unwrap())?;
             }
         }
 
-        if !self.unstaged_deletions.is_empty() {
+        let renamed: HashSet<&PathBuf> =
+            self.unstaged_renames.values().collect();
+        let unstaged_deletions: Vec<&PathBuf> = self
+            .unstaged_deletions
+            .iter()
+            .filter(|f| !renamed.contains(f))
+            .collect();
+        if !unstaged_deletions.is_empty() {
             writeln!(w, "\nUnstaged deletions:")?;
-            for f in &self.unstaged_deletions {
+            for f in unstaged_deletions {
                 writeln!(w, "\tdeleted: {}", f.to_str().unwrap())?;
             }
         }
@@ -132,21 +207,40 @@ impl Status {
         };
 
         /* Fetch all files on disk */
-        let disk_files: HashSet<PathBuf> =
-            HashSet::from_iter(file::get_all(root_path)?);
+        let mut disk_files = watch::disk_files(connection, root_path)?;
 
-        /* Staged changes can be either additions or deletions:
+        /* Staged changes can be additions, modifications or deletions:
          *
-         * For ease of use later we'll move them into hash sets for both
-         * additions and deletions.
+         * For ease of use later we'll move them into hash sets for each
+         * kind.
          */
         let staged_changes = db::staging::get_all(connection)?;
+
+        /* Ignore rules only keep new files out. Files that are already
+         * tracked or staged stay that way even if a rule matches them.
+         */
+        let known = tracked_files.keys().cloned().chain(
+            staged_changes.iter().filter_map(|sc| match sc {
+                Change::Addition(sf) | Change::Modification(sf) => {
+                    Some(PathBuf::from(&sf.path))
+                }
+                Change::Deletion(_) => None,
+            }),
+        );
+        for path in known.collect::<Vec<PathBuf>>() {
+            if !disk_files.contains(&path)
+                && root_path.join(&path).symlink_metadata().is_ok()
+            {
+                disk_files.insert(path);
+            }
+        }
         let mut staged_additions: HashSet<PathBuf> = HashSet::new();
+        let mut staged_modifications: HashSet<PathBuf> = HashSet::new();
         let mut staged_deletions: HashSet<PathBuf> = HashSet::new();
 
-        /* A staged addition could potentially have changed or been deleted
-         * since it's addition to the index. Conversely a staged
-         * deletion could have the file appear again.
+        /* A staged addition or modification could potentially have changed
+         * or been deleted since it's addition to the index. Conversely a
+         * staged deletion could have the file appear again.
          */
         let mut staged_but_changed: HashSet<PathBuf> = HashSet::new();
         let mut staged_but_deleted: HashSet<PathBuf> = HashSet::new();
@@ -157,66 +251,74 @@ impl Status {
          */
         let mut unstaged_additions: Vec<PathBuf> = Vec::new();
 
+        /* Unstaged modifications are tracked files on disk whose contents
+         * differ from HEAD, and that aren't staged.
+         */
+        let mut unstaged_modifications: Vec<PathBuf> = Vec::new();
+
         /* Unstaged deletions are files deleted from disk that are in the set
          * of tracked files, but not in the set of staged deletions.
          */
         let mut unstaged_deletions: Vec<PathBuf> = Vec::new();
 
-        staged_changes.iter().for_each(|sc| match sc {
-            Change::Addition(sf) => {
-                let path = PathBuf::from(&sf.path);
-                staged_additions.insert(path.clone());
-
-                let full_path = root_path.join(&sf.path);
-
-                if sf.compare_metadata(&full_path).unwrap_or(false) {
-                    staged_but_changed.insert(path);
-                } else if !disk_files.contains(&path) {
-                    staged_but_deleted.insert(path);
+        /* A file whose size changed has changed. Otherwise files whose stat
+         * changed since they were last hashed are hashed again to find out
+         * if their contents did, everything else comes from the stat cache.
+         */
+        let hasher = Hasher::for_repository(connection)?;
+        let changed =
+            |path: &Path, file_hash: &str, size_bytes: i64| -> Result<bool> {
+                let meta = file::metadata(&root_path.join(path))?;
+                if meta.size_bytes != size_bytes {
+                    return Ok(true);
                 }
-            }
-            Change::Deletion(pb) => {
-                staged_deletions.insert(pb.clone());
+                let hash = stat_cache::hash(
+                    connection, &hasher, root_path, path, &meta,
+                )?;
+                Ok(hash != file_hash)
+            };
+
+        for sc in &staged_changes {
+            match sc {
+                Change::Addition(sf) | Change::Modification(sf) => {
+                    let path = PathBuf::from(&sf.path);
+                    if let Change::Addition(_) = sc {
+                        staged_additions.insert(path.clone());
+                    } else {
+                        staged_modifications.insert(path.clone());
+                    }
 
-                if disk_files.contains(pb) {
-                    staged_but_added.insert(pb.clone());
+                    if !disk_files.contains(&path) {
+                        staged_but_deleted.insert(path);
+                    } else if changed(&path, &sf.file_hash, sf.size_bytes)? {
+                        staged_but_changed.insert(path);
+                    }
                 }
-            }
-        });
+                Change::Deletion(pb) => {
+                    staged_deletions.insert(pb.clone());
 
-        // Note - bug - should compare the lstat of df against the tracked
-        // file if the tracked file exists.
-        disk_files.iter().for_each(|df| {
-            if !staged_additions.contains(df) {
-                if !tracked_files.contains_key(df) {
-                    unstaged_additions.push(df.clone());
-                    return;
+                    if disk_files.contains(pb) {
+                        staged_but_added.insert(pb.clone());
+                    }
                 }
+            }
+        }
 
-                // at this point I need a way to cheaply compare
-                // a tracked file and a disk file. Staged files
-                // contain a modified time, but tracked files don't
-                // given that, I think the best we can do here is
-                // checking the size_bytes? Or kick off an expensive
-                // hash?
-                //
-                //
-                // Maybe I should consider a cheapper hash like md5
-                // just as a backup for situations like this?
-                let tf = tracked_files.get(df).unwrap();
-
-                match file::metadata(df) {
-                    Ok(meta) => {
-                        if tf.size_bytes != meta.size_bytes {
-                            unstaged_additions.push(df.clone());
-                        }
-                    }
-                    _ => {
-                        return;
+        for df in &disk_files {
+            if staged_additions.contains(df)
+                || staged_modifications.contains(df)
+            {
+                continue;
+            }
+            match tracked_files.get(df) {
+                None => unstaged_additions.push(df.clone()),
+                Some(tf) => {
+                    if changed(df, &tf.file_hash, tf.size_bytes)? {
+                        unstaged_modifications.push(df.clone());
                     }
                 }
             }
-        });
+        }
 
         tracked_files.iter().for_each(|(pb, _)| {
             if !disk_files.contains(pb) && !staged_deletions.contains(pb) {
@@ -224,15 +326,67 @@ impl Status {
             }
         });
 
+        /* Objects are content addressed, so a new file with the contents of
+         * a deleted one is exactly a rename of it. Staged files already
+         * have their hashes, new files are only hashed when they're the size
+         * of a deleted file.
+         */
+        let tracked = |path: &PathBuf| tracked_files.get(path).cloned();
+        let staged_added: Vec<TreeFile> = staged_changes
+            .iter()
+            .filter_map(|sc| match sc {
+                Change::Addition(sf) => Some(sf.to_tree_file("")),
+                _ => None,
+            })
+            .collect();
+        let (renames, copies) = tree::pair_contents(
+            &tracked_files.values().cloned().collect::<Vec<TreeFile>>(),
+            &staged_added,
+            &staged_deletions
+                .iter()
+                .filter_map(tracked)
+                .collect::<Vec<_>>(),
+        );
+        let staged_renames = pairs_by_path(&renames);
+        let staged_copies = pairs_by_path(&copies);
+
+        let deleted: Vec<TreeFile> =
+            unstaged_deletions.iter().filter_map(tracked).collect();
+        let deleted_sizes: HashSet<i64> =
+            deleted.iter().map(|tf| tf.size_bytes).collect();
+        let mut unstaged_added: Vec<TreeFile> = vec![];
+        for path in &unstaged_additions {
+            let meta = file::metadata(&root_path.join(path))?;
+            if !deleted_sizes.contains(&meta.size_bytes) {
+                continue;
+            }
+            unstaged_added.push(TreeFile {
+                path: path.to_string_lossy().to_string(),
+                file_hash: stat_cache::hash(
+                    connection, &hasher, root_path, path, &meta,
+                )?,
+                size_bytes: meta.size_bytes,
+                commit_hash: String::new(),
+            });
+        }
+        let (renames, _) =
+            tree::pair_contents(&deleted, &unstaged_added, &deleted);
+        let unstaged_renames = pairs_by_path(&renames);
+
         Ok(Status {
             tracked_files,
             staged_additions,
+            staged_modifications,
             staged_deletions,
             staged_but_changed,
             staged_but_deleted,
             staged_but_added,
             unstaged_additions,
+            unstaged_modifications,
             unstaged_deletions,
+            staged_renames,
+            staged_copies,
+            unstaged_renames,
             head,
             ref_name: meta.head,
         })
@@ -240,23 +394,20 @@ impl Status {
 
     pub fn has_uncomitted_changes(&self) -> bool {
         !self.staged_additions.is_empty()
-            && !self.staged_deletions.is_empty()
-            && !self.unstaged_additions.is_empty()
-            && !self.unstaged_deletions.is_empty()
+            || !self.staged_modifications.is_empty()
+            || !self.staged_deletions.is_empty()
+            || !self.unstaged_additions.is_empty()
+            || !self.unstaged_modifications.is_empty()
+            || !self.unstaged_deletions.is_empty()
     }
 }
 
-pub trait Hashable {
-    fn file_hash(&self) -> String;
-}
-
-impl Hashable for IntermediateTree {
-    fn file_hash(&self) -> String {
-        match self {
-            IntermediateTree::Staged(sf) => sf.file_hash.clone(),
-            IntermediateTree::Committed(tf) => tf.file_hash.clone(),
-        }
-    }
+// Maps the path each file went to to the path it came from
+fn pairs_by_path(pairs: &[FilePair]) -> HashMap<PathBuf, PathBuf> {
+    pairs
+        .iter()
+        .map(|p| (PathBuf::from(&p.to.path), PathBuf::from(&p.from.path)))
+        .collect()
 }
 
 #[derive(Clone, Debug)]
@@ -280,7 +431,74 @@ pub fn intermediate_to_tree_files(
         .collect()
 }
 
-pub fn hash_all(files: &Vec<Box<dyn Hashable>>) -> String {
-    let hashes: Vec<String> = files.iter().map(|f| f.file_hash()).collect();
-    hash_string(hashes.join(""))
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use super::*;
+    use crate::actions::{add, commit};
+    use crate::testing::TempDir;
+    use crate::types::hash_algorithm::HashAlgorithm;
+
+    fn clean() -> Status {
+        Status {
+            tracked_files: HashMap::new(),
+            staged_additions: HashSet::new(),
+            staged_modifications: HashSet::new(),
+            staged_deletions: HashSet::new(),
+            staged_but_changed: HashSet::new(),
+            staged_but_deleted: HashSet::new(),
+            staged_but_added: HashSet::new(),
+            unstaged_additions: vec![],
+            unstaged_modifications: vec![],
+            unstaged_deletions: vec![],
+            staged_renames: HashMap::new(),
+            staged_copies: HashMap::new(),
+            unstaged_renames: HashMap::new(),
+            head: None,
+            ref_name: String::from("main"),
+        }
+    }
+
+    #[test]
+    fn test_modifications_are_uncommitted_changes() {
+        assert!(!clean().has_uncomitted_changes());
+
+        let mut status = clean();
+        status.unstaged_modifications.push(PathBuf::from("a"));
+        assert!(status.has_uncomitted_changes());
+
+        let mut status = clean();
+        status.staged_modifications.insert(PathBuf::from("a"));
+        assert!(status.has_uncomitted_changes());
+    }
+
+    #[test]
+    fn test_racy_modification_is_detected() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path();
+        crate::store::init(root_path)?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+        db::config::set(&connection, crate::config::USER_NAME, "Ada")?;
+
+        let path = root_path.join("a.txt");
+        fs::write(&path, "before")?;
+        let modified = fs::metadata(&path)?.modified()?;
+        add::add(&connection, root_path, Path::new("a.txt"), 1)?;
+        commit::commit(&connection, root_path, "add a", None)?;
+        let status = Status::new(&connection, root_path)?;
+        assert!(!status.has_uncomitted_changes());
+
+        // Rewritten within the same tick, same size and modified time
+        fs::write(&path, "after!")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)?;
+
+        let status = Status::new(&connection, root_path)?;
+        assert_eq!(status.unstaged_modifications, vec![PathBuf::from("a.txt")]);
+        Ok(())
+    }
 }
@@ -1,7 +1,4 @@
 use std::cmp::{Eq, PartialEq};
-use std::hash::{Hash, Hasher};
-
-use crate::models::status::Hashable;
 
 #[derive(Clone, Debug, Hash)]
 pub struct TreeFile {
@@ -30,41 +27,3 @@ impl TreeFile {
         }
     }
 }
-
-impl Hashable for TreeFile {
-    fn file_hash(&self) -> String {
-        self.file_hash.clone()
-    }
-}
-
-pub struct TreeFileFileHash(pub TreeFile);
-
-impl PartialEq for TreeFileFileHash {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.file_hash == other.0.file_hash
-    }
-}
-
-impl Eq for TreeFileFileHash {}
-
-impl Hash for TreeFileFileHash {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.file_hash.hash(state);
-    }
-}
-
-pub struct TreeFilePathHash(pub TreeFile);
-
-impl PartialEq for TreeFilePathHash {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.path == other.0.path
-    }
-}
-
-impl Eq for TreeFilePathHash {}
-
-impl Hash for TreeFilePathHash {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.path.hash(state);
-    }
-}
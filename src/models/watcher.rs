@@ -0,0 +1,19 @@
+/* The `sssync watch` process keeping track of the working directory
+ *
+ * Every time the watcher loses track of changes, its queue overflows or an
+ * ignore file changes, it bumps generation. The snapshot of the files on
+ * disk is only up to date when it was taken during the current generation.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watcher {
+    pub pid: i64,
+    pub generation: i64,
+    // The generation the snapshot of files on disk was taken in, if any
+    pub snapshot_generation: Option<i64>,
+}
+
+impl Watcher {
+    pub fn has_snapshot(&self) -> bool {
+        self.snapshot_generation == Some(self.generation)
+    }
+}
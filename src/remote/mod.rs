@@ -1,16 +1,19 @@
-use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
-use aws_sdk_s3::Client;
 use rusqlite::Connection;
 use url::Url;
 
+use crate::backend::ObjectBackend;
+use crate::chunk;
 use crate::db;
 use crate::migration;
+use crate::models::migration::MigrationAction;
 use crate::models::transfer::TransferKind;
-use crate::s3;
+use crate::models::tree_file::TreeFile;
 use crate::store;
+use crate::types::checkout_strategy::CheckoutStrategy;
+use crate::types::compression::Compression;
 use crate::types::remote_kind::RemoteKind;
 
 pub struct RemoteInfo {
@@ -20,14 +23,43 @@ pub struct RemoteInfo {
 }
 
 impl RemoteInfo {
+    // Key objects are written to, see store::FORMAT_VERSION for the layout
     pub fn object_key(&self, hash: &str) -> String {
-        format!("{}/.sssync/objects/{}", self.prefix, hash)
+        format!("{}{}", self.objects_prefix(), store::object_name(hash))
+    }
+
+    // Key of an object stored with compression
+    pub fn encoded_object_key(
+        &self,
+        hash: &str,
+        compression: Compression,
+    ) -> String {
+        format!(
+            "{}{}",
+            self.objects_prefix(),
+            store::encoded_object_name(hash, compression)
+        )
+    }
+
+    // Key objects were written to before they were fanned out
+    pub fn flat_object_key(&self, hash: &str) -> String {
+        format!("{}{}", self.objects_prefix(), hash)
+    }
+
+    pub fn objects_prefix(&self) -> String {
+        format!("{}/.sssync/objects/", self.prefix)
     }
 
     pub fn database_key(&self) -> String {
         format!("{}/.sssync/sssync.db", self.prefix)
     }
 
+    // Local remotes don't have a bucket, their keys are resolved against the
+    // root of the filesystem.
+    pub fn local_path(&self, key: &str) -> PathBuf {
+        Path::new("/").join(key)
+    }
+
     pub fn from_url(url: &str) -> Result<Self> {
         let u = Url::parse(url)?;
         let scheme = u.scheme();
@@ -36,13 +68,34 @@ impl RemoteInfo {
         // for a url like `s3://anders.conbere.org/games` the url decomposes to
         // bucket: anders.conbere.org
         // key: /games
-        let bucket = u
-            .host_str()
-            .ok_or(anyhow!("unable to unwrap host to bucket"))?
-            .to_string();
-
-        let mut prefix = u.path().to_string();
-        prefix.remove(0);
+        //
+        // for a url like `local:///mnt/backup/games` there is no bucket and
+        // the path is the directory holding the remote.
+        let bucket = match kind {
+            RemoteKind::S3 => u
+                .host_str()
+                .ok_or(anyhow!("unable to unwrap host to bucket"))?
+                .to_string(),
+            RemoteKind::Local => String::new(),
+        };
+
+        // A host in a local url, `local://mnt/backup`, or no leading slash,
+        // `local:mnt/backup`, would leave the path relative to wherever
+        // sssync happens to run.
+        let path = u.path().strip_prefix('/');
+        let prefix = match (kind, path) {
+            (RemoteKind::Local, Some(path)) if u.host_str().is_none() => {
+                path.to_string()
+            }
+            (RemoteKind::Local, _) => {
+                return Err(anyhow!(
+                    "local remotes need an absolute path, like \
+                     local:///mnt/backup, got {}",
+                    url
+                ))
+            }
+            (RemoteKind::S3, path) => path.unwrap_or(u.path()).to_string(),
+        };
 
         Ok(Self {
             bucket,
@@ -52,60 +105,102 @@ impl RemoteInfo {
     }
 }
 
-pub async fn fetch_remote_db(
-    client: &Client,
-    remote_info: &RemoteInfo,
-    destination: &Path,
-) -> Result<()> {
-    let mut remote_db = File::create(&destination)?;
-
-    s3::download_object(
-        &client,
-        &remote_info.bucket,
-        &remote_info.database_key(),
-        &mut remote_db,
-    )
-    .await?;
-
-    Ok(())
-}
-
+/* Download every object referenced by the tree at the head of the database
+ * behind `source`. For a fetch `source` is the freshly downloaded remote
+ * database, for a clone it is the local database itself.
+ *
+ * Returns the files in that tree so the caller can decide whether to check
+ * them out.
+ */
 pub async fn fetch_remote_objects(
     connection: &Connection,
+    backend: &dyn ObjectBackend,
+    source: &Connection,
     root_path: &Path,
     remote_name: &str,
-) -> Result<()> {
-    let remote = db::remote::get(connection, remote_name)?;
+    action: MigrationAction,
+    jobs: usize,
+) -> Result<Vec<TreeFile>> {
+    let meta = db::meta::get(source)?;
 
-    match remote.kind {
-        RemoteKind::S3 => {
-            let meta = db::meta::get(&connection)?;
+    let head = db::commit::get_by_ref_name(source, &meta.head)?
+        .ok_or(anyhow!("Head is bad - no matching ref name"))?;
 
-            let head = db::commit::get_by_ref_name(&connection, &meta.head)?
-                .ok_or(anyhow!("Head is bad - no matching ref name"))?;
+    let files = db::tree::get(source, &head.hash)?;
 
-            let files = db::tree::get(&connection, &head.hash)?;
+    // Manifests of files stored in chunks come with the database, keep a
+    // copy of the ones for the files being downloaded.
+    for f in &files {
+        db::chunk::insert(connection, &db::chunk::get(source, &f.file_hash)?)?;
+    }
+    let object_hashes = chunk::object_hashes(
+        connection,
+        files.iter().map(|f| f.file_hash.as_str()),
+    )?;
+
+    let migration = migration::create(
+        connection,
+        TransferKind::Download,
+        action,
+        remote_name,
+        &object_hashes,
+        None,
+    )?;
+
+    migration::run(
+        connection, backend, root_path, &migration, false, true, jobs,
+    )
+    .await?;
 
-            let object_hashes: Vec<String> =
-                files.iter().map(|f| f.file_hash.clone()).collect();
+    Ok(files)
+}
 
-            let migration = migration::create(
-                &connection,
-                TransferKind::Download,
-                remote_name,
-                &object_hashes,
-            )?;
+// Write every file in files out of the store into the working directory
+pub fn checkout(
+    connection: &Connection,
+    root_path: &Path,
+    files: &[TreeFile],
+    strategy: CheckoutStrategy,
+) -> Result<()> {
+    for f in files {
+        let p = &root_path.join(&f.path);
+        store::export_to(connection, root_path, &f.file_hash, p, strategy)?;
+    }
+    Ok(())
+}
 
-            migration::run(connection, root_path, &migration, false, true)
-                .await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_info_from_s3_url() -> Result<()> {
+        let info = RemoteInfo::from_url("s3://bucket.example.com/games")?;
+        assert_eq!(info.kind, RemoteKind::S3);
+        assert_eq!(info.bucket, "bucket.example.com");
+        assert_eq!(info.object_key("abc"), "games/.sssync/objects/ab/c");
+        assert_eq!(info.flat_object_key("abc"), "games/.sssync/objects/abc");
+        Ok(())
+    }
 
-            for f in files {
-                let p = &root_path.join(f.path);
-                store::export_to(&root_path, &f.file_hash, p)?;
-            }
+    #[test]
+    fn test_remote_info_from_local_url() -> Result<()> {
+        let info = RemoteInfo::from_url("local:///mnt/backup/games")?;
+        assert_eq!(info.kind, RemoteKind::Local);
+        assert_eq!(
+            info.local_path(&info.object_key("abc")),
+            PathBuf::from("/mnt/backup/games/.sssync/objects/ab/c")
+        );
+        assert_eq!(
+            info.local_path(&info.database_key()),
+            PathBuf::from("/mnt/backup/games/.sssync/sssync.db")
+        );
+        Ok(())
+    }
 
-            Ok(())
-        }
-        RemoteKind::Local => Ok(()),
+    #[test]
+    fn test_remote_info_rejects_relative_local_url() {
+        assert!(RemoteInfo::from_url("local://mnt/backup/games").is_err());
+        assert!(RemoteInfo::from_url("local:mnt/backup/games").is_err());
     }
 }
@@ -1,25 +1,60 @@
 use std::io;
 
 use anyhow::{anyhow, Result};
-use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+use aws_sdk_s3::types::{
+    ChecksumAlgorithm, ChecksumMode, CompletedMultipartUpload, CompletedPart,
+    Delete, Object, ObjectIdentifier,
+};
 use aws_sdk_s3::Client;
 
+use crate::models::remote::S3Settings;
+
 pub mod upload;
 pub mod upload_multipart;
 
-pub async fn make_client() -> Client {
-    let config =
-        aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
-    Client::new(&config)
+// S3 compatible services rarely care about the region, but the SDK refuses to
+// sign requests without one.
+const DEFAULT_REGION: &str = "us-east-1";
+
+// Every object and part we upload carries a checksum of this kind, S3
+// refuses the request if the bytes it received don't match it.
+pub const CHECKSUM_ALGORITHM: ChecksumAlgorithm = ChecksumAlgorithm::Crc32C;
+
+pub async fn make_client(settings: &S3Settings) -> Client {
+    let mut loader =
+        aws_config::defaults(aws_config::BehaviorVersion::latest());
+
+    if let Some(profile) = &settings.profile {
+        loader = loader.profile_name(profile);
+    }
+
+    if let Some(endpoint_url) = &settings.endpoint_url {
+        loader = loader.endpoint_url(endpoint_url);
+    }
+
+    match (&settings.region, &settings.endpoint_url) {
+        (Some(region), _) => {
+            loader = loader.region(aws_config::Region::new(region.clone()));
+        }
+        (None, Some(_)) => {
+            loader = loader.region(aws_config::Region::new(DEFAULT_REGION));
+        }
+        (None, None) => {}
+    }
+
+    let config = loader.load().await;
+    let s3_config = aws_sdk_s3::config::Builder::from(&config)
+        .force_path_style(settings.force_path_style)
+        .build();
+    Client::from_conf(s3_config)
 }
 
 pub async fn download_object(
     client: &Client,
     bucket_name: &str,
     key: &str,
-    writer: &mut dyn io::Write,
+    writer: &mut (dyn io::Write + Send),
 ) -> Result<ByteStream> {
     println!("downloading: {}", key);
 
@@ -27,6 +62,7 @@ pub async fn download_object(
         .get_object()
         .bucket(bucket_name)
         .key(key)
+        .checksum_mode(ChecksumMode::Enabled)
         .send()
         .await?;
 
@@ -37,35 +73,45 @@ pub async fn download_object(
     Ok(resp.body)
 }
 
-#[allow(dead_code)]
-pub async fn list_objects(client: &Client, bucket_name: &str) -> Result<()> {
-    let objects = client.list_objects_v2().bucket(bucket_name).send().await?;
+// Lists every object in the bucket whose key starts with prefix
+pub async fn list_objects(
+    client: &Client,
+    bucket_name: &str,
+    prefix: &str,
+) -> Result<Vec<Object>> {
+    let mut objects: Vec<Object> = vec![];
 
-    if let Some(objects) = objects.contents {
-        for obj in objects {
-            println!("{:?}", obj.key().unwrap());
-        }
-    } else {
-        println!("No objects found for bucket: {}", bucket_name)
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket_name)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+
+    while let Some(page) = pages.next().await {
+        objects.extend(page?.contents.unwrap_or_default());
     }
-    Ok(())
+
+    Ok(objects)
 }
 
-#[allow(dead_code)]
-pub async fn delete_objects(client: &Client, bucket_name: &str) -> Result<()> {
-    let objects = client.list_objects_v2().bucket(bucket_name).send().await?;
+// S3 accepts at most 1000 keys in a single delete request
+const DELETE_BATCH_SIZE: usize = 1000;
 
-    let mut delete_objects: Vec<ObjectIdentifier> = vec![];
+pub async fn delete_objects(
+    client: &Client,
+    bucket_name: &str,
+    keys: &[String],
+) -> Result<()> {
+    for batch in keys.chunks(DELETE_BATCH_SIZE) {
+        let mut delete_objects: Vec<ObjectIdentifier> = vec![];
 
-    if let Some(objects) = objects.contents {
-        for obj in objects {
-            let obj_id = ObjectIdentifier::builder()
-                .set_key(Some(obj.key().unwrap().to_string()))
-                .build()?;
+        for key in batch {
+            let obj_id = ObjectIdentifier::builder().key(key).build()?;
             delete_objects.push(obj_id);
         }
 
-        client
+        let result = client
             .delete_objects()
             .bucket(bucket_name)
             .delete(
@@ -76,17 +122,133 @@ pub async fn delete_objects(client: &Client, bucket_name: &str) -> Result<()> {
             .send()
             .await?;
 
-        let objects: ListObjectsV2Output =
-            client.list_objects_v2().bucket(bucket_name).send().await?;
+        if let Some(error) = result.errors().first() {
+            return Err(anyhow!(
+                "Failed to delete {}: {}",
+                error.key().unwrap_or_default(),
+                error.message().unwrap_or_default()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// CopyObject refuses sources larger than 5GB, bigger objects are copied in
+// parts of COPY_PART_BYTES.
+const MAX_COPY_OBJECT_BYTES: i64 = 5_000_000_000;
+const COPY_PART_BYTES: i64 = 1_000_000_000;
+
+// Copies the object at source_key to destination_key within the bucket
+pub async fn copy_object(
+    client: &Client,
+    bucket_name: &str,
+    source_key: &str,
+    destination_key: &str,
+    size_bytes: i64,
+) -> Result<()> {
+    let copy_source = copy_source(bucket_name, source_key);
+
+    if size_bytes <= MAX_COPY_OBJECT_BYTES {
+        client
+            .copy_object()
+            .bucket(bucket_name)
+            .key(destination_key)
+            .copy_source(copy_source)
+            .checksum_algorithm(CHECKSUM_ALGORITHM)
+            .send()
+            .await?;
+        return Ok(());
+    }
+
+    let multipart = client
+        .create_multipart_upload()
+        .bucket(bucket_name)
+        .key(destination_key)
+        .checksum_algorithm(CHECKSUM_ALGORITHM)
+        .send()
+        .await?;
+    let upload_id = multipart
+        .upload_id
+        .ok_or(anyhow!("no upload id copying {}", source_key))?;
 
-        match objects.key_count {
-            Some(0) => Ok(()),
-            None => {
-                Err(anyhow!("No objects found for bucket: {}", bucket_name))
+    let mut parts: Vec<CompletedPart> = vec![];
+    let mut start = 0;
+    let mut part_number = 1;
+    while start < size_bytes {
+        let end = (start + COPY_PART_BYTES).min(size_bytes) - 1;
+        let result = client
+            .upload_part_copy()
+            .bucket(bucket_name)
+            .key(destination_key)
+            .upload_id(&upload_id)
+            .part_number(part_number)
+            .copy_source(&copy_source)
+            .copy_source_range(format!("bytes={}-{}", start, end))
+            .send()
+            .await;
+
+        let part = match result {
+            Ok(part) => part,
+            Err(e) => {
+                client
+                    .abort_multipart_upload()
+                    .bucket(bucket_name)
+                    .key(destination_key)
+                    .upload_id(&upload_id)
+                    .send()
+                    .await?;
+                return Err(e.into());
             }
-            _ => Err(anyhow!("There were still objects left in the bucket.")),
+        };
+
+        let copied = part.copy_part_result();
+        parts.push(
+            CompletedPart::builder()
+                .set_e_tag(copied.and_then(|c| c.e_tag()).map(String::from))
+                .set_checksum_crc32_c(
+                    copied.and_then(|c| c.checksum_crc32_c()).map(String::from),
+                )
+                .part_number(part_number)
+                .build(),
+        );
+
+        start = end + 1;
+        part_number += 1;
+    }
+
+    client
+        .complete_multipart_upload()
+        .bucket(bucket_name)
+        .key(destination_key)
+        .upload_id(&upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .send()
+        .await?;
+    Ok(())
+}
+
+/* The x-amz-copy-source header names the source as bucket/key with the key
+ * URL encoded.
+ */
+fn copy_source(bucket_name: &str, key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for b in key.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'~'
+            | b'/' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
         }
-    } else {
-        Err(anyhow!("No objects found for bucket: {}", bucket_name))
     }
+    format!("{}/{}", bucket_name, encoded)
 }
@@ -1,7 +0,0 @@
-struct ThreadPool {}
-
-impl ThreadPool {
-    fn new(size: u8) -> Self {
-        Self {}
-    }
-}
@@ -4,6 +4,8 @@ use anyhow::Result;
 use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::Client;
 
+use crate::s3::CHECKSUM_ALGORITHM;
+
 /* Simple single file upload function
  */
 pub async fn upload_object(
@@ -19,6 +21,7 @@ pub async fn upload_object(
         .bucket(bucket)
         .key(key)
         .body(body)
+        .checksum_algorithm(CHECKSUM_ALGORITHM)
         .send()
         .await?;
 
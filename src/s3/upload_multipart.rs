@@ -10,6 +10,7 @@ use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
 use aws_sdk_s3::Client;
 
 use crate::s3::upload::upload_object;
+use crate::s3::CHECKSUM_ALGORITHM;
 
 const TEN_MEGABYTES: u64 = 10_000_000;
 const FIVE_MEGABYTES: u64 = 5_000_000;
@@ -45,6 +46,7 @@ pub async fn upload_multipart(
         .create_multipart_upload()
         .bucket(bucket)
         .key(key)
+        .checksum_algorithm(CHECKSUM_ALGORITHM)
         .send()
         .await?;
 
@@ -56,7 +58,8 @@ pub async fn upload_multipart(
 
     match result {
         Err(e) => {
-            println!("upload_multipart: upload failed: {}", e);
+            // The parts S3 already has are only thrown away once aborted,
+            // the upload itself still failed either way.
             client
                 .abort_multipart_upload()
                 .bucket(bucket)
@@ -64,6 +67,7 @@ pub async fn upload_multipart(
                 .upload_id(upload_id)
                 .send()
                 .await?;
+            return Err(e.context(format!("uploading {} failed", key)));
         }
 
         Ok(upload_parts) => {
@@ -82,7 +86,6 @@ pub async fn upload_multipart(
                 .await?;
         }
     }
-    println!("\ndone");
     Ok(())
 }
 
@@ -91,7 +94,7 @@ async fn run(
     upload_id: &str,
     bucket: &str,
     key: &str,
-    reader: &mut dyn Read,
+    reader: &mut (dyn Read + Send),
 ) -> Result<Vec<CompletedPart>> {
     let mut part_number = 1;
 
@@ -101,8 +104,11 @@ async fn run(
         print!(".");
         stdout().flush()?;
 
-        let mut buf = vec![0u8; TEN_MEGABYTES as usize];
-        let bytes_read = reader.read(&mut buf)?;
+        // A single read can come back short, keep reading until the part is
+        // full so only the last part is smaller than the rest and no part
+        // carries bytes past the end of the file.
+        let mut buf = Vec::with_capacity(TEN_MEGABYTES as usize);
+        let bytes_read = reader.take(TEN_MEGABYTES).read_to_end(&mut buf)?;
 
         if bytes_read == 0 {
             break;
@@ -115,12 +121,16 @@ async fn run(
             .upload_id(upload_id)
             .part_number(part_number)
             .body(ByteStream::from(buf))
+            .checksum_algorithm(CHECKSUM_ALGORITHM)
             .send()
             .await?;
 
+        // Completing the upload checks the checksum of every part against
+        // the one S3 computed when it received it.
         upload_parts.push(
             CompletedPart::builder()
                 .e_tag(upload_part_res.e_tag.unwrap_or_default())
+                .set_checksum_crc32_c(upload_part_res.checksum_crc32_c)
                 .part_number(part_number)
                 .build(),
         );
@@ -130,3 +140,120 @@ async fn run(
 
     Ok(upload_parts)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use aws_sdk_s3::config::retry::RetryConfig;
+    use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /* Answers just enough of the S3 API for a multipart upload, refusing
+     * every part the way S3 does when a part's checksum doesn't match.
+     * Requests are recorded as "METHOD path?query".
+     */
+    async fn serve(listener: TcpListener, requests: Arc<Mutex<Vec<String>>>) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let request = respond(stream).await.unwrap_or_default();
+            requests.lock().unwrap().push(request);
+        }
+    }
+
+    async fn respond(stream: TcpStream) -> Result<String> {
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        reader.read_line(&mut request).await?;
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            if line.trim().is_empty() {
+                break;
+            }
+            headers.push(line.trim().to_lowercase());
+        }
+        let header = |name: &str| {
+            headers.iter().find_map(|h| {
+                h.strip_prefix(name)
+                    .and_then(|v| v.strip_prefix(':'))
+                    .map(|v| v.trim().to_string())
+            })
+        };
+
+        // A refused part's body is never sent when the client waits to be
+        // told to go ahead.
+        if header("expect").is_none() {
+            if let Some(length) = header("content-length") {
+                let mut body = vec![0; length.parse()?];
+                reader.read_exact(&mut body).await?;
+            }
+        }
+
+        let mut parts = request.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let target = parts.next().unwrap_or_default();
+        let (status, body) = match method {
+            "POST" if target.contains("uploads") => (
+                "200 OK",
+                "<InitiateMultipartUploadResult><Bucket>bucket</Bucket>\
+                 <Key>key</Key><UploadId>upload-1</UploadId>\
+                 </InitiateMultipartUploadResult>",
+            ),
+            "PUT" => (
+                "400 Bad Request",
+                "<Error><Code>BadDigest</Code><Message>The CRC32C you \
+                 specified did not match the calculated checksum.</Message>\
+                 </Error>",
+            ),
+            "DELETE" => ("204 No Content", ""),
+            _ => ("500 Internal Server Error", ""),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        reader.get_mut().write_all(response.as_bytes()).await?;
+        reader.get_mut().shutdown().await?;
+        Ok(format!("{} {}", method, target))
+    }
+
+    #[tokio::test]
+    async fn test_failed_part_fails_the_upload() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let requests = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(serve(listener, requests.clone()));
+
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .force_path_style(true)
+            .credentials_provider(Credentials::for_tests())
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let client = Client::from_conf(config);
+
+        let dir = TempDir::new()?;
+        let file_path = dir.path().join("large");
+        std::fs::write(&file_path, vec![7; FIVE_MEGABYTES as usize + 1])?;
+
+        let result =
+            upload_multipart(&client, "bucket", "key", &file_path, true).await;
+        assert!(result.is_err());
+
+        let requests = requests.lock().unwrap();
+        assert!(requests.iter().any(|r| r.starts_with("DELETE")));
+        assert!(!requests
+            .iter()
+            .any(|r| r.starts_with("POST") && r.contains("uploadId")));
+        Ok(())
+    }
+}
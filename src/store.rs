@@ -1,9 +1,21 @@
 use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
+use rusqlite::Connection;
 
+use crate::db;
+use crate::hash::Hasher;
+use crate::models::chunk::Chunk;
+use crate::models::file;
 use crate::tree;
+use crate::types::checkout_strategy::CheckoutStrategy;
+use crate::types::compression::{Compression, ZSTD_LEVEL};
 
 pub const STORE_DIR: &str = ".sssync";
 pub const OBJECTS_DIR: &str = "objects";
@@ -17,10 +29,16 @@ pub fn store_path(root_path: &Path) -> PathBuf {
     root_path.join(STORE_DIR)
 }
 
-pub fn remote_db_path(root_path: &Path, name: &str) -> Result<PathBuf> {
-    let path = store_path(root_path)
+// Location of the local copy of the remote database, whether or not it has
+// been fetched yet.
+pub fn remote_db_file(root_path: &Path, name: &str) -> PathBuf {
+    store_path(root_path)
         .join(REMOTES_DIR)
-        .join(format!("{}.db", name));
+        .join(format!("{}.db", name))
+}
+
+pub fn remote_db_path(root_path: &Path, name: &str) -> Result<PathBuf> {
+    let path = remote_db_file(root_path, name);
 
     if !path.exists() {
         return Err(anyhow!("Remote with name: {} does not exist!", name));
@@ -33,12 +51,200 @@ pub fn db_path(root_path: &Path) -> PathBuf {
     root_path.join(".sssync/sssync.db")
 }
 
+/* Repository format versions
+ *
+ * 1: objects are stored flat, in objects/<hash>
+ * 2: objects are fanned out by the first two characters of their hash, in
+ *    objects/<ab>/<cdef...>, so no directory holds too many of them
+ * 3: objects may be stored compressed, in objects/<ab>/<cdef...>.zst
+ *
+ * Objects are always written in the current layout and looked for in the
+ * flat one when they aren't found there. A repository still on version 1
+ * may hold objects in either until `sssync upgrade` moves them. Remotes
+ * follow the same layout and record their version in the remote database.
+ */
+pub const FORMAT_VERSION: u32 = 3;
+pub const COMPRESSED_FORMAT_VERSION: u32 = 3;
+
+/* Refuse to work with a database written by a newer sssync, it may lay
+ * objects out or hash them in a way this one can't read.
+ */
+pub fn check_format(connection: &Connection) -> Result<()> {
+    // Databases that don't record a format predate format versions
+    if !db::has_column(connection, "meta", "format_version")? {
+        return Ok(());
+    }
+
+    match db::meta::get(connection) {
+        Ok(meta) if meta.format_version > FORMAT_VERSION => Err(anyhow!(
+            "repository format version {} is newer than the supported \
+             version {}, upgrade sssync",
+            meta.format_version,
+            FORMAT_VERSION
+        )),
+        Ok(meta) => {
+            Hasher::new(meta.hash_algorithm, meta.hash_version).map(|_| ())
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/* Objects and commits are named by their hash, a repository and a remote
+ * hashing differently can't exchange them.
+ */
+pub fn check_hashes(
+    connection: &Connection,
+    remote_connection: &Connection,
+    remote_name: &str,
+) -> Result<()> {
+    let local_meta = db::meta::get(connection)?;
+    let remote_meta = db::meta::get(remote_connection)?;
+    if local_meta.hash_algorithm != remote_meta.hash_algorithm {
+        return Err(anyhow!(
+            "{} hashes with {} and the repository with {}, they can't be \
+             mixed",
+            remote_name,
+            remote_meta.hash_algorithm,
+            local_meta.hash_algorithm
+        ));
+    }
+
+    let local = local_meta.hash_version;
+    let remote = remote_meta.hash_version;
+    if local == remote {
+        return Ok(());
+    }
+
+    if local > remote {
+        Err(anyhow!(
+            "{} uses hash version {} and the repository {}, publish the \
+             rehashed repository with `sssync remote init {} --force`",
+            remote_name,
+            remote,
+            local,
+            remote_name
+        ))
+    } else {
+        Err(anyhow!(
+            "{} uses hash version {} and the repository {}, run \
+             `sssync upgrade` first",
+            remote_name,
+            remote,
+            local
+        ))
+    }
+}
+
+/* Versions of sssync from before format 3 can't read compressed objects.
+ * Writing one into a fanned out repository moves it to version 3, which
+ * needs nothing else done to it. Repositories still on version 1 get there
+ * with `sssync upgrade`.
+ */
+pub fn record_compression(
+    connection: &Connection,
+    compression: Compression,
+) -> Result<()> {
+    if compression == Compression::None
+        || !db::has_column(connection, "meta", "format_version")?
+    {
+        return Ok(());
+    }
+
+    let meta = db::meta::get(connection)?;
+    if meta.format_version >= 2
+        && meta.format_version < COMPRESSED_FORMAT_VERSION
+    {
+        db::meta::set_format_version(connection, COMPRESSED_FORMAT_VERSION)?;
+    }
+    Ok(())
+}
+
+// The name of an object below the objects directory, "abcd" -> "ab/cd"
+pub fn object_name(hash: &str) -> String {
+    match (hash.get(..2), hash.get(2..)) {
+        (Some(shard), Some(rest)) if !rest.is_empty() => {
+            format!("{}/{}", shard, rest)
+        }
+        _ => hash.to_string(),
+    }
+}
+
+// The name of an object stored with compression, "abcd" -> "ab/cd.zst"
+pub fn encoded_object_name(hash: &str, compression: Compression) -> String {
+    match compression.extension() {
+        Some(extension) => format!("{}.{}", object_name(hash), extension),
+        None => object_name(hash),
+    }
+}
+
+pub fn objects_path(root_path: &Path) -> PathBuf {
+    store_path(root_path).join(OBJECTS_DIR)
+}
+
+// Where the object is written
 pub fn object_path(root_path: &Path, hash: &str) -> PathBuf {
-    let mut p = PathBuf::new();
-    p.push(STORE_DIR);
-    p.push("objects");
-    p.push(hash);
-    root_path.join(p)
+    objects_path(root_path).join(object_name(hash))
+}
+
+// Where the object is written when it's stored with compression
+pub fn encoded_object_path(
+    root_path: &Path,
+    hash: &str,
+    compression: Compression,
+) -> PathBuf {
+    objects_path(root_path).join(encoded_object_name(hash, compression))
+}
+
+// Where the object was written before objects were fanned out
+pub fn flat_object_path(root_path: &Path, hash: &str) -> PathBuf {
+    objects_path(root_path).join(hash)
+}
+
+// Where the object can be read from, if it's in the store
+pub fn find_object(root_path: &Path, hash: &str) -> Option<PathBuf> {
+    [
+        object_path(root_path, hash),
+        encoded_object_path(root_path, hash, Compression::Zstd),
+        flat_object_path(root_path, hash),
+    ]
+    .into_iter()
+    .find(|p| p.is_file())
+}
+
+// The compression of the object stored at path, found from its extension
+pub fn object_compression(path: &Path) -> Compression {
+    match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => Compression::from_name(name).1,
+        None => Compression::None,
+    }
+}
+
+// Reads the contents of the object file at path, decompressing them
+pub fn read_object_file(
+    path: &Path,
+    compression: Compression,
+) -> Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    match compression {
+        Compression::None => Ok(Box::new(file)),
+        Compression::Zstd => Ok(Box::new(zstd::Decoder::new(file)?)),
+    }
+}
+
+// Reads the uncompressed contents of the object hash
+pub fn open_object(root_path: &Path, hash: &str) -> Result<Box<dyn Read>> {
+    let path = find_object(root_path, hash)
+        .ok_or(anyhow!("object {} is not in the store", hash))?;
+    read_object_file(&path, object_compression(&path))
+}
+
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+// Where an object is written while it's being downloaded, before it's been
+// verified and moved to object_path.
+pub fn partial_object_path(root_path: &Path, hash: &str) -> PathBuf {
+    object_path(root_path, hash).with_extension(PARTIAL_EXTENSION)
 }
 
 pub fn get_root_path(path: &Path) -> Option<&Path> {
@@ -53,33 +259,273 @@ pub fn get_root_path(path: &Path) -> Option<&Path> {
 }
 
 // Writes the contents of the store indexed by hash to the file
-// at the path destination.
+// at the path destination, using strategy to materialize it. Files stored
+// in chunks are rebuilt from the manifest found in connection.
 pub fn export_to(
+    connection: &Connection,
     root_path: &Path,
     hash: &str,
     destination: &Path,
+    strategy: CheckoutStrategy,
 ) -> Result<()> {
     // Ensure the directory where we're going to write this file exists
     if let Some(parent) = destination.parent() {
         fs::create_dir_all(parent)?;
     }
-    fs::copy(object_path(root_path, hash), destination)?;
+
+    // Whatever is at destination goes first. Writing into it could write
+    // through a link into the store.
+    if fs::symlink_metadata(destination).is_ok() {
+        fs::remove_file(destination)?;
+    }
+
+    let Some(source) = find_object(root_path, hash) else {
+        let manifest = db::chunk::get(connection, hash)?;
+        if manifest.is_empty() {
+            return Err(anyhow!("object {} is not in the store", hash));
+        }
+        return export_chunks(root_path, &manifest, destination);
+    };
+
+    // Compressed objects can only be copied out
+    if object_compression(&source) != Compression::None {
+        let mut file = File::create(destination)?;
+        io::copy(&mut open_object(root_path, hash)?, &mut file)?;
+        return Ok(());
+    }
+
+    match strategy {
+        CheckoutStrategy::Copy => copy_object(&source, destination),
+        CheckoutStrategy::Reflink => match reflink(&source, destination) {
+            Ok(()) => Ok(()),
+            // Not every filesystem can share blocks between files
+            Err(_) => copy_object(&source, destination),
+        },
+        CheckoutStrategy::Hardlink => {
+            set_read_only(&source)?;
+            match fs::hard_link(&source, destination) {
+                Ok(()) => Ok(()),
+                // The working directory may be on another filesystem
+                Err(_) => copy_object(&source, destination),
+            }
+        }
+        CheckoutStrategy::Symlink => {
+            set_read_only(&source)?;
+            let target = fs::canonicalize(&source)?;
+            std::os::unix::fs::symlink(target, destination)?;
+            Ok(())
+        }
+    }
+}
+
+// Rebuilds a file stored in chunks by writing out each chunk in turn. Chunks
+// are shared between files so they are copied whatever the strategy.
+fn export_chunks(
+    root_path: &Path,
+    manifest: &[Chunk],
+    destination: &Path,
+) -> Result<()> {
+    let mut file = File::create(destination)?;
+    for chunk in manifest {
+        io::copy(&mut open_object(root_path, &chunk.chunk_hash)?, &mut file)?;
+    }
+    Ok(())
+}
+
+// Copies an object out of the store, leaving the copy writable even if the
+// object has been made read only.
+fn copy_object(source: &Path, destination: &Path) -> Result<()> {
+    fs::copy(source, destination)?;
+    let mut permissions = fs::metadata(destination)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o200);
+    fs::set_permissions(destination, permissions)?;
     Ok(())
 }
 
+// Clones source into a new file at destination, sharing its blocks
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+    let source_file = File::open(source)?;
+    let destination_file = File::create(destination)?;
+
+    let result = unsafe {
+        libc::ioctl(
+            destination_file.as_raw_fd(),
+            libc::FICLONE,
+            source_file.as_raw_fd(),
+        )
+    };
+
+    if result == -1 {
+        let error = io::Error::last_os_error();
+        drop(destination_file);
+        _ = fs::remove_file(destination);
+        return Err(error);
+    }
+    Ok(())
+}
+
+// Objects linked into the working directory are read only so editing the
+// file can't change the object underneath it.
+fn set_read_only(path: &Path) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() & !0o222);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/* Returns the hash of every object a symlink in the working directory points
+ * at. These are kept by gc, even when no commit reaches them, so that no
+ * file in the working directory is left dangling.
+ */
+pub fn linked_objects(root_path: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let objects = fs::canonicalize(objects_path(root_path))?;
+    let mut linked = vec![];
+    for path in file::get_all(root_path)? {
+        let Ok(target) = fs::read_link(root_path.join(&path)) else {
+            continue;
+        };
+        let Ok(name) = target.strip_prefix(&objects) else {
+            continue;
+        };
+        if let Some(name) = name.to_str() {
+            linked.push((name.replace('/', ""), path));
+        }
+    }
+    Ok(linked)
+}
+
 pub fn exists(root_path: &Path, hash: &str) -> bool {
-    let p = object_path(root_path, hash);
-    p.exists()
+    find_object(root_path, hash).is_some()
+}
+
+// Returns the hash of every object in the store, in either layout, skipping
+// downloads that haven't finished.
+pub fn list_objects(root_path: &Path) -> Result<Vec<String>> {
+    let mut hashes = vec![];
+    for entry in fs::read_dir(objects_path(root_path))? {
+        let path = entry?.path();
+        let Some(name) = object_file_name(&path) else {
+            continue;
+        };
+
+        if !path.is_dir() {
+            hashes.push(Compression::from_name(name).0.to_string());
+            continue;
+        }
+
+        for shard_entry in fs::read_dir(&path)? {
+            if let Some(rest) = object_file_name(&shard_entry?.path()) {
+                let (rest, _) = Compression::from_name(rest);
+                hashes.push(format!("{}{}", name, rest));
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+fn object_file_name(path: &Path) -> Option<&str> {
+    if path.extension().is_some_and(|e| e == PARTIAL_EXTENSION) {
+        return None;
+    }
+    path.file_name().and_then(|n| n.to_str())
+}
+
+/* Move every object still stored flat into its fanned out location.
+ * Returns the number of objects moved.
+ */
+pub fn shard_objects(root_path: &Path) -> Result<usize> {
+    let mut moved = 0;
+    for entry in fs::read_dir(objects_path(root_path))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            continue;
+        }
+        let Some(hash) = object_file_name(&path) else {
+            continue;
+        };
+
+        let destination = object_path(root_path, hash);
+        if destination == path {
+            continue;
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&path, destination)?;
+        moved += 1;
+    }
+    Ok(moved)
 }
 
 // Writes the contents of the file found at source into the store
-// with the hash hash.
-pub fn insert_from(root_path: &Path, hash: &str, source: &Path) -> Result<()> {
-    let p = object_path(root_path, hash);
+// with the hash hash, compressed with compression when that makes it
+// smaller.
+pub fn insert_from(
+    root_path: &Path,
+    hash: &str,
+    source: &Path,
+    compression: Compression,
+) -> Result<()> {
+    if exists(root_path, hash) {
+        return Ok(());
+    }
+
+    let partial_path = partial_object_path(root_path, hash);
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if compression == Compression::Zstd {
+        zstd::stream::copy_encode(
+            File::open(source)?,
+            File::create(&partial_path)?,
+            ZSTD_LEVEL,
+        )?;
+        if fs::metadata(&partial_path)?.len() < fs::metadata(source)?.len() {
+            fs::rename(
+                partial_path,
+                encoded_object_path(root_path, hash, compression),
+            )?;
+            return Ok(());
+        }
+    }
+
+    fs::copy(source, &partial_path)?;
+    fs::rename(partial_path, object_path(root_path, hash))?;
+    Ok(())
+}
+
+// Writes bytes into the store as the object hash, compressed with
+// compression when that makes them smaller.
+pub fn insert_bytes(
+    root_path: &Path,
+    hash: &str,
+    bytes: &[u8],
+    compression: Compression,
+) -> Result<()> {
+    if exists(root_path, hash) {
+        return Ok(());
+    }
+
+    let partial_path = partial_object_path(root_path, hash);
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
 
-    if !p.exists() {
-        fs::copy(source, p)?;
+    if compression == Compression::Zstd {
+        let compressed = zstd::encode_all(bytes, ZSTD_LEVEL)?;
+        if compressed.len() < bytes.len() {
+            fs::write(&partial_path, compressed)?;
+            fs::rename(
+                partial_path,
+                encoded_object_path(root_path, hash, compression),
+            )?;
+            return Ok(());
+        }
     }
+
+    fs::write(&partial_path, bytes)?;
+    fs::rename(partial_path, object_path(root_path, hash))?;
     Ok(())
 }
 
@@ -107,16 +553,16 @@ pub fn init(path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn apply_diff(path: &Path, diff: &tree::TreeDiff) -> Result<()> {
-    for a in &diff.additions {
-        let destination = path.join(&a.path);
-        println!("copying: {} -> {}", a.file_hash, destination.display());
-        export_to(path, &a.file_hash, &destination)?;
-    }
-    for a in &diff.changes {
+pub fn apply_diff(
+    connection: &Connection,
+    path: &Path,
+    diff: &tree::TreeDiff,
+    strategy: CheckoutStrategy,
+) -> Result<()> {
+    for a in diff.additions.iter().chain(&diff.changes) {
         let destination = path.join(&a.path);
-        println!("copying: {} -> {}", a.file_hash, destination.display());
-        export_to(path, &a.file_hash, &destination)?;
+        println!("{}: {} -> {}", strategy, a.file_hash, destination.display());
+        export_to(connection, path, &a.file_hash, &destination, strategy)?;
     }
     for d in &diff.deletions {
         let destination = path.join(&d.path);
@@ -126,3 +572,135 @@ pub fn apply_diff(path: &Path, diff: &tree::TreeDiff) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_flat_objects_are_found_and_sharded() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path().to_path_buf();
+        init(&root_path)?;
+
+        assert_eq!(object_name("abcdef"), "ab/cdef");
+        assert_eq!(object_name("ab"), "ab");
+
+        fs::write(flat_object_path(&root_path, "abcdef"), "flat")?;
+        assert!(exists(&root_path, "abcdef"));
+        assert_eq!(list_objects(&root_path)?, vec![String::from("abcdef")]);
+
+        assert_eq!(shard_objects(&root_path)?, 1);
+        assert!(!flat_object_path(&root_path, "abcdef").exists());
+        assert_eq!(
+            fs::read_to_string(object_path(&root_path, "abcdef"))?,
+            "flat"
+        );
+        assert_eq!(list_objects(&root_path)?, vec![String::from("abcdef")]);
+        assert_eq!(shard_objects(&root_path)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_strategies() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path().to_path_buf();
+        init(&root_path)?;
+
+        let connection = Connection::open_in_memory()?;
+        db::chunk::create_table(&connection)?;
+
+        let source = root_path.join("source");
+        fs::write(&source, "object")?;
+        insert_from(&root_path, "abcdef", &source, Compression::None)?;
+        let object = object_path(&root_path, "abcdef");
+        let file = root_path.join("dir/file");
+
+        export_to(
+            &connection,
+            &root_path,
+            "abcdef",
+            &file,
+            CheckoutStrategy::Symlink,
+        )?;
+        assert_eq!(fs::read_link(&file)?, object);
+        assert!(fs::metadata(&object)?.permissions().readonly());
+        assert_eq!(
+            linked_objects(&root_path)?,
+            vec![(String::from("abcdef"), PathBuf::from("dir/file"))]
+        );
+
+        // Copying over the link replaces it rather than writing through it
+        export_to(
+            &connection,
+            &root_path,
+            "abcdef",
+            &file,
+            CheckoutStrategy::Copy,
+        )?;
+        assert!(!fs::symlink_metadata(&file)?.is_symlink());
+        assert!(!fs::metadata(&file)?.permissions().readonly());
+        fs::write(&file, "changed")?;
+        assert_eq!(fs::read_to_string(&object)?, "object");
+
+        export_to(
+            &connection,
+            &root_path,
+            "abcdef",
+            &file,
+            CheckoutStrategy::Hardlink,
+        )?;
+        assert_eq!(fs::read_to_string(&file)?, "object");
+
+        export_to(
+            &connection,
+            &root_path,
+            "abcdef",
+            &file,
+            CheckoutStrategy::Reflink,
+        )?;
+        assert_eq!(fs::read_to_string(&file)?, "object");
+        assert!(linked_objects(&root_path)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_objects() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path().to_path_buf();
+        init(&root_path)?;
+        let connection = Connection::open_in_memory()?;
+        db::chunk::create_table(&connection)?;
+
+        let contents = "a,b,c\n".repeat(10_000);
+        let source = root_path.join("dump.csv");
+        fs::write(&source, &contents)?;
+        insert_from(&root_path, "abcdef", &source, Compression::Zstd)?;
+
+        let path = find_object(&root_path, "abcdef").unwrap();
+        assert_eq!(object_compression(&path), Compression::Zstd);
+        assert!(fs::metadata(&path)?.len() < contents.len() as u64);
+        assert_eq!(list_objects(&root_path)?, vec![String::from("abcdef")]);
+
+        // Compressed objects are always copied out
+        let file = root_path.join("file");
+        export_to(
+            &connection,
+            &root_path,
+            "abcdef",
+            &file,
+            CheckoutStrategy::Symlink,
+        )?;
+        assert!(!fs::symlink_metadata(&file)?.is_symlink());
+        assert_eq!(fs::read_to_string(&file)?, contents);
+
+        // Bytes that don't get smaller are stored as they are
+        insert_bytes(&root_path, "123456", b"x", Compression::Zstd)?;
+        assert_eq!(
+            find_object(&root_path, "123456"),
+            Some(object_path(&root_path, "123456"))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use uuid::Uuid;
+
+/* A fresh directory under the system's temporary directory, removed along
+ * with everything in it when dropped, so a failing test doesn't leave it
+ * behind. The path is canonical, paths resolved on disk compare equal to
+ * ones joined onto it.
+ */
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<Self> {
+        let path = fs::canonicalize(env::temp_dir())?
+            .join(format!("sssync-test-{}", Uuid::new_v4()));
+        fs::create_dir(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -1,157 +1,185 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use anyhow::{anyhow, Result};
 use rusqlite::Connection;
 
+use crate::chunk;
 use crate::db;
 use crate::models::commit::Commit;
-use crate::models::tree_file::{TreeFile, TreeFileFileHash, TreeFilePathHash};
+use crate::models::reference;
+use crate::models::tree_file::TreeFile;
 
 #[derive(Debug)]
 pub struct TreeDiff {
     pub additions: Vec<TreeFile>,
     pub deletions: Vec<TreeFile>,
     pub changes: Vec<TreeFile>,
+
+    /* Additions with the contents of a deletion, and additions with the
+     * contents of any other file in the older tree. Both are still listed
+     * in additions, and renamed files in deletions, so applying a diff
+     * doesn't have to know about them.
+     */
+    pub renames: Vec<FilePair>,
+    pub copies: Vec<FilePair>,
 }
 
-impl TreeDiff {
-    pub fn empty() -> Self {
-        Self {
-            additions: vec![],
-            deletions: vec![],
-            changes: vec![],
-        }
-    }
+// A file in the newer tree, and the one in the older tree it has the
+// contents of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilePair {
+    pub from: TreeFile,
+    pub to: TreeFile,
+}
 
+impl TreeDiff {
     // The Problem:
     //
     // a: what files were added?
     // b: what files were deleted?
     // c: what files were changed?
+    // d: which added files were renamed or copied?
     //
-    // to do this we're going to first focus on file_hashes. We'll build a set
-    // of file_hashes for the newer and older. Any file_hashes in newer but
-    // not in older are either New or Changed. Any file_hashes in older but
-    // not in newer are deletions.
+    // Files are matched up by path. A path only found in newer is an
+    // addition, one only found in older a deletion, and one in both with a
+    // different file_hash a change.
     //
-    // Now that we have new or chagned, we'll walk through the new or changed
-    // files and look up if the path exists in the older. If the file is new
-    // or changed, but the path exists in older then the file is changed,
-    // otherwise it's new.
+    // Objects are content addressed, so an added file with the file_hash of
+    // a deleted one is exactly a rename of it. Each deleted file is the
+    // source of one rename at most, any other added file with the file_hash
+    // of a file in older is a copy of it. Empty files all share a file_hash,
+    // pairing them up would say nothing, so they're left alone.
     //
     // Directionality is from older -> newer So for example if the newer set
     // contains a file that isn't found in the older. That file will end up
     // in the additions set.
     pub fn new(older: &Vec<TreeFile>, newer: &Vec<TreeFile>) -> Self {
-        let older_by_hashes: HashSet<TreeFileFileHash> =
-            older.iter().map(|f| TreeFileFileHash(f.clone())).collect();
-
-        let newer_by_hashes: HashSet<TreeFileFileHash> =
-            newer.iter().map(|f| TreeFileFileHash(f.clone())).collect();
-
-        let older_by_path: HashSet<TreeFilePathHash> =
-            older.iter().map(|f| TreeFilePathHash(f.clone())).collect();
+        let older_by_path: HashMap<&str, &TreeFile> =
+            older.iter().map(|f| (f.path.as_str(), f)).collect();
+        let newer_paths: HashSet<&str> =
+            newer.iter().map(|f| f.path.as_str()).collect();
 
         let mut additions = Vec::new();
-        let mut changes_by_path = HashSet::new();
-
-        // All new hashes found in the the new tree, files might either be
-        // additions or changes. We can determine which by filtering the set of
-        // additions by those that have matching paths in older.
-        let all_positive_deltas: Vec<TreeFile> = newer_by_hashes
-            .difference(&older_by_hashes)
-            .map(|f| f.0.clone())
-            .collect();
-
-        for f in &all_positive_deltas {
-            let path_hash = TreeFilePathHash(f.clone());
-            if older_by_path.contains(&path_hash) {
-                changes_by_path.insert(path_hash);
-            } else {
-                additions.push(f.clone());
+        let mut changes = Vec::new();
+        for f in newer {
+            match older_by_path.get(f.path.as_str()) {
+                None => additions.push(f.clone()),
+                Some(old) if old.file_hash != f.file_hash => {
+                    changes.push(f.clone())
+                }
+                Some(_) => {}
             }
         }
 
-        // Deletions are files in older state that can no longer be found in the
-        // older state
-        let deletions: Vec<TreeFile> = older_by_hashes
-            .difference(&newer_by_hashes)
-            .filter(|f| {
-                let path_hash = TreeFilePathHash(f.0.clone());
-                !changes_by_path.contains(&path_hash)
-            })
-            .map(|f| f.0.clone())
+        let deletions: Vec<TreeFile> = older
+            .iter()
+            .filter(|f| !newer_paths.contains(f.path.as_str()))
+            .cloned()
             .collect();
 
-        let changes: Vec<TreeFile> =
-            changes_by_path.into_iter().map(|f| f.0).collect();
+        let (renames, copies) = pair_contents(older, &additions, &deletions);
 
         TreeDiff {
             additions,
             deletions,
             changes,
+            renames,
+            copies,
         }
     }
 
+    // Additions that are neither renames nor copies
+    pub fn new_files(&self) -> Vec<&TreeFile> {
+        let paired: HashSet<&str> = self
+            .renames
+            .iter()
+            .chain(&self.copies)
+            .map(|p| p.to.path.as_str())
+            .collect();
+        self.additions
+            .iter()
+            .filter(|f| !paired.contains(f.path.as_str()))
+            .collect()
+    }
+
+    // Deletions that aren't the source of a rename
+    pub fn removed_files(&self) -> Vec<&TreeFile> {
+        let renamed: HashSet<&str> =
+            self.renames.iter().map(|p| p.from.path.as_str()).collect();
+        self.deletions
+            .iter()
+            .filter(|f| !renamed.contains(f.path.as_str()))
+            .collect()
+    }
+
     pub fn updates(&self) -> Vec<TreeFile> {
         let mut updated_files: Vec<TreeFile> = self.additions.clone();
         updated_files.extend(self.changes.clone());
         updated_files
     }
 
-    /* Adds two diffs together
-     *
-     * Operation is directionaly with other being applied "on top of" self.
-     * operation will return an error if other tries to change a files
-     * that self has deleted.
+    /* The files of tree with this diff applied on top, deletions removed
+     * and additions and changes put in place by path. The files keep the
+     * commit hash they had, in either tree or the diff.
      */
-    pub fn add(&self, other: &Self) -> Result<TreeDiff> {
-        // additions
-        // deletions
-        // changes
-
-        let additions_self = HashSet::from_iter(self.additions.clone());
-        let deletions_self = HashSet::from_iter(self.deletions.clone());
-        let changes_self = HashSet::from_iter(self.changes.clone());
-
-        let additions_other = HashSet::from_iter(other.additions.clone());
-        let deletions_other = HashSet::from_iter(other.deletions.clone());
-        let changes_other = HashSet::from_iter(other.changes.clone());
-
-        let deleted_changes: HashSet<TreeFile> = deletions_self
-            .intersection(&changes_other)
-            .cloned()
-            .collect();
-
-        if !deleted_changes.is_empty() {
-            return Err(anyhow!("Later change was deleted in earlier diff"));
+    pub fn apply(&self, tree: &[TreeFile]) -> Vec<TreeFile> {
+        let mut files: BTreeMap<&str, &TreeFile> =
+            tree.iter().map(|f| (f.path.as_str(), f)).collect();
+        for f in &self.deletions {
+            files.remove(f.path.as_str());
         }
+        for f in self.additions.iter().chain(&self.changes) {
+            files.insert(f.path.as_str(), f);
+        }
+        files.into_values().cloned().collect()
+    }
+}
 
-        let changes: Vec<TreeFile> =
-            changes_self.union(&changes_other).cloned().collect();
-
-        let additions: Vec<TreeFile> = additions_self
-            .union(&additions_other)
-            .cloned()
-            .collect::<HashSet<TreeFile>>()
-            .difference(&deletions_other)
-            .cloned()
-            .collect();
+/* Pairs additions up with the deletion, or else the file in older, they
+ * have the contents of, returning the renames and the copies. Paths are
+ * taken in order so the same files always pair up the same way.
+ */
+pub fn pair_contents(
+    older: &[TreeFile],
+    additions: &[TreeFile],
+    deletions: &[TreeFile],
+) -> (Vec<FilePair>, Vec<FilePair>) {
+    let by_path = |a: &&TreeFile, b: &&TreeFile| a.path.cmp(&b.path);
+
+    // Popped from the back, so the first path is renamed first
+    let mut deleted: HashMap<&str, Vec<&TreeFile>> = HashMap::new();
+    let mut sorted: Vec<&TreeFile> = deletions.iter().collect();
+    sorted.sort_by(|a, b| by_path(b, a));
+    for f in sorted {
+        deleted.entry(&f.file_hash).or_default().push(f);
+    }
 
-        let deletions: Vec<TreeFile> = deletions_self
-            .union(&deletions_other)
-            .cloned()
-            .collect::<HashSet<TreeFile>>()
-            .difference(&additions_other)
-            .cloned()
-            .collect();
+    let mut sources: HashMap<&str, &TreeFile> = HashMap::new();
+    let mut sorted: Vec<&TreeFile> = older.iter().collect();
+    sorted.sort_by(by_path);
+    for f in sorted {
+        sources.entry(&f.file_hash).or_insert(f);
+    }
 
-        Ok(TreeDiff {
-            additions,
-            deletions,
-            changes,
-        })
+    let mut renames = vec![];
+    let mut copies = vec![];
+    let mut sorted: Vec<&TreeFile> = additions.iter().collect();
+    sorted.sort_by(by_path);
+    for to in sorted.into_iter().filter(|f| f.size_bytes > 0) {
+        let hash = to.file_hash.as_str();
+        if let Some(from) = deleted.get_mut(hash).and_then(|fs| fs.pop()) {
+            renames.push(FilePair {
+                from: from.clone(),
+                to: to.clone(),
+            });
+        } else if let Some(from) = sources.get(hash) {
+            copies.push(FilePair {
+                from: (*from).clone(),
+                to: to.clone(),
+            });
+        }
     }
+    (renames, copies)
 }
 
 pub fn diff_parent(
@@ -162,10 +190,10 @@ pub fn diff_parent(
     // If there is no parent hash then the tree is all there is
     if let Some(parent_hash) = &commit.parent_hash {
         let parent_tree = db::tree::get(&connection, &parent_hash)?;
-        Ok(TreeDiff::new(&tree, &parent_tree))
+        Ok(TreeDiff::new(&parent_tree, &tree))
     } else {
         let parent_tree: Vec<TreeFile> = Vec::new();
-        Ok(TreeDiff::new(&tree, &parent_tree))
+        Ok(TreeDiff::new(&parent_tree, &tree))
     }
 }
 
@@ -208,6 +236,40 @@ pub fn diff_list(
     Ok(TreeDiff::new(&all_files, &head_tree))
 }
 
+/* Every object in a tree of a commit reachable from one of the branches of
+ * the database, mapped to one of the paths it's found at. Files stored in
+ * chunks contribute their chunks.
+ */
+pub fn reachable_objects(
+    connection: &Connection,
+) -> Result<BTreeMap<String, String>> {
+    let mut commits: HashSet<String> = HashSet::new();
+    for r in
+        db::reference::get_all_by_kind(connection, reference::Kind::Branch)?
+    {
+        if commits.contains(&r.hash) {
+            continue;
+        }
+        for c in db::commit::get_children(connection, &r.hash)? {
+            commits.insert(c.hash);
+        }
+    }
+
+    let mut files: HashSet<String> = HashSet::new();
+    let mut objects: BTreeMap<String, String> = BTreeMap::new();
+    for commit_hash in &commits {
+        for tree_file in db::tree::get(connection, commit_hash)? {
+            if !files.insert(tree_file.file_hash.clone()) {
+                continue;
+            }
+            for hash in chunk::file_objects(connection, &tree_file.file_hash)? {
+                objects.entry(hash).or_insert(tree_file.path.clone());
+            }
+        }
+    }
+    Ok(objects)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -218,15 +280,10 @@ mod tests {
         let older = Vec::new();
 
         let result = TreeDiff::new(&newer, &older);
-        let check = TreeDiff {
-            additions: Vec::new(),
-            deletions: Vec::new(),
-            changes: Vec::new(),
-        };
 
-        assert_eq!(result.additions, check.additions);
-        assert_eq!(result.deletions, check.deletions);
-        assert_eq!(result.changes, check.changes);
+        assert!(result.additions.is_empty());
+        assert!(result.deletions.is_empty());
+        assert!(result.changes.is_empty());
         Ok(())
     }
 
@@ -294,6 +351,8 @@ mod tests {
             changes: Vec::from([file_a_prime.clone()]),
             additions: Vec::from([file_c.clone()]),
             deletions: Vec::from([file_b.clone()]),
+            renames: Vec::new(),
+            copies: Vec::new(),
         };
 
         assert_eq!(result.changes, check.changes, "assert changes are found");
@@ -307,4 +366,123 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_diff_renames_and_copies() -> Result<()> {
+        let file = |path: &str, file_hash: &str, size_bytes: i64| TreeFile {
+            path: String::from(path),
+            file_hash: String::from(file_hash),
+            size_bytes,
+            commit_hash: String::from("commit"),
+        };
+
+        let older = Vec::from([
+            file("a", "hash-a", 10),
+            file("b", "hash-b", 10),
+            file("empty", "hash-empty", 0),
+        ]);
+        let newer = Vec::from([
+            file("a", "hash-a", 10),
+            file("moved/b", "hash-b", 10),
+            file("moved/b-again", "hash-b", 10),
+            file("a-copy", "hash-a", 10),
+            file("empty-too", "hash-empty", 0),
+        ]);
+
+        let result = TreeDiff::new(&older, &newer);
+        assert_eq!(
+            result.renames,
+            Vec::from([FilePair {
+                from: file("b", "hash-b", 10),
+                to: file("moved/b", "hash-b", 10),
+            }])
+        );
+        assert_eq!(
+            result.copies,
+            Vec::from([
+                FilePair {
+                    from: file("a", "hash-a", 10),
+                    to: file("a-copy", "hash-a", 10),
+                },
+                FilePair {
+                    from: file("b", "hash-b", 10),
+                    to: file("moved/b-again", "hash-b", 10),
+                },
+            ])
+        );
+        assert_eq!(result.new_files(), Vec::from([&newer[4]]));
+        assert_eq!(result.removed_files(), Vec::from([&older[2]]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_parent_replays_commits_for_rebase() -> Result<()> {
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, Default::default())?;
+        let file = |path: &str, file_hash: &str, commit_hash: &str| TreeFile {
+            path: String::from(path),
+            file_hash: String::from(file_hash),
+            size_bytes: 10,
+            commit_hash: String::from(commit_hash),
+        };
+        let commit =
+            |hash: &str, parent_hash: Option<&str>| -> Result<Commit> {
+                let commit = Commit::new(
+                    hash,
+                    hash,
+                    "author",
+                    parent_hash.map(String::from),
+                )?;
+                db::commit::insert(&connection, &commit)?;
+                Ok(commit)
+            };
+
+        let first = commit("first", None)?;
+        db::tree::insert_batch(
+            &connection,
+            vec![file("a", "hash-a", "first")],
+        )?;
+        let second = commit("second", Some("first"))?;
+        db::tree::insert_batch(
+            &connection,
+            vec![
+                file("a", "hash-a-prime", "second"),
+                file("b", "hash-b", "second"),
+            ],
+        )?;
+        let third = commit("third", Some("second"))?;
+        db::tree::insert_batch(
+            &connection,
+            vec![
+                file("a", "hash-a-prime", "third"),
+                file("b", "hash-b", "third"),
+                file("c", "hash-c", "third"),
+            ],
+        )?;
+
+        let diff = diff_parent(&connection, &first)?;
+        assert_eq!(diff.additions, vec![file("a", "hash-a", "first")]);
+
+        // Rebase replays the changes each commit made on top of its parent
+        // onto the tree of the new parent
+        let mut tree =
+            vec![file("a", "hash-a", "base"), file("z", "hash-z", "base")];
+        for commit in [&second, &third] {
+            tree = diff_parent(&connection, commit)?.apply(&tree);
+        }
+        let files: Vec<(&str, &str)> = tree
+            .iter()
+            .map(|f| (f.path.as_str(), f.file_hash.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("a", "hash-a-prime"),
+                ("b", "hash-b"),
+                ("c", "hash-c"),
+                ("z", "hash-z"),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,47 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+/* How files are written from the store into the working directory
+ *
+ * Copy gives every file its own bytes on disk. The others let the working
+ * directory share them with the store: reflink clones the object on
+ * filesystems that support it (btrfs, xfs) and falls back to a copy
+ * elsewhere, hardlink and symlink point the file at the object itself,
+ * which is made read only so the store can't be changed through it.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum CheckoutStrategy {
+    #[default]
+    Copy,
+    Hardlink,
+    Reflink,
+    Symlink,
+}
+
+impl CheckoutStrategy {
+    pub fn parse(s: &str) -> Result<CheckoutStrategy> {
+        match s {
+            "copy" => Ok(CheckoutStrategy::Copy),
+            "hardlink" => Ok(CheckoutStrategy::Hardlink),
+            "reflink" => Ok(CheckoutStrategy::Reflink),
+            "symlink" => Ok(CheckoutStrategy::Symlink),
+            _ => Err(anyhow!(
+                "invalid checkout strategy: {}, expected one of copy, \
+                 hardlink, reflink or symlink",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CheckoutStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheckoutStrategy::Copy => write!(f, "copy"),
+            CheckoutStrategy::Hardlink => write!(f, "hardlink"),
+            CheckoutStrategy::Reflink => write!(f, "reflink"),
+            CheckoutStrategy::Symlink => write!(f, "symlink"),
+        }
+    }
+}
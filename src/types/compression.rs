@@ -0,0 +1,60 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+/* How the bytes of an object are stored, in the store and in remotes
+ *
+ * Objects are always named by the hash of their uncompressed contents, so
+ * compressing them changes no file or commit hash. A compressed object
+ * carries the extension of its compression after its name, which is how
+ * readers know to decompress it.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+// Level passed to zstd, its default, which compresses about as fast as
+// objects are read from disk.
+pub const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    pub fn parse(s: &str) -> Result<Compression> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(anyhow!(
+                "invalid compression: {}, expected none or zstd",
+                s
+            )),
+        }
+    }
+
+    pub fn extension(&self) -> Option<&'static str> {
+        match *self {
+            Compression::None => None,
+            Compression::Zstd => Some("zst"),
+        }
+    }
+
+    /* Splits the name of a stored object into the hash and the compression
+     * it was stored with, "abcd.zst" -> ("abcd", Zstd)
+     */
+    pub fn from_name(name: &str) -> (&str, Compression) {
+        match name.strip_suffix(".zst") {
+            Some(hash) => (hash, Compression::Zstd),
+            None => (name, Compression::None),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+
+/* The hash function a repository names its objects and commits with
+ *
+ * xxh3 is fast but not cryptographic, anyone can craft two files with the
+ * same hash. blake3 and sha256 are collision resistant, blake3 at close to
+ * xxh3's speed on large files. The algorithm is picked at `sssync init` and
+ * can't be changed afterward, every object and commit is named by it.
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default, ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn parse(s: &str) -> Result<HashAlgorithm> {
+        match s {
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(anyhow!(
+                "invalid hash algorithm: {}, expected one of xxh3, blake3 or \
+                 sha256",
+                s
+            )),
+        }
+    }
+}
+
+impl FromSql for HashAlgorithm {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str().and_then(|s| match HashAlgorithm::parse(s) {
+            Ok(algorithm) => Ok(algorithm),
+            Err(_) => Err(FromSqlError::InvalidType),
+        })
+    }
+}
+
+impl ToSql for HashAlgorithm {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashAlgorithm::Xxh3 => write!(f, "xxh3"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
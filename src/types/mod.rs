@@ -1 +1,4 @@
+pub mod checkout_strategy;
+pub mod compression;
+pub mod hash_algorithm;
 pub mod remote_kind;
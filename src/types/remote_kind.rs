@@ -18,6 +18,7 @@ impl RemoteKind {
             "S3" => Ok(RemoteKind::S3),
             "s3" => Ok(RemoteKind::S3),
             "local" => Ok(RemoteKind::Local),
+            "file" => Ok(RemoteKind::Local),
             _ => Err(anyhow!("invalid Remote kind: {}", s)),
         }
     }
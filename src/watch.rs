@@ -0,0 +1,231 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+
+use crate::db;
+use crate::ignores;
+use crate::models::file;
+use crate::models::watcher::Watcher;
+use crate::store;
+
+const LOCK_FILE: &str = "watch.lock";
+
+/* While `sssync watch` runs it holds a lock on .sssync/watch.lock, which
+ * the kernel lets go of however the process ends. The watcher table can't
+ * say on its own whether the watcher is still running, its pid may have
+ * been reused.
+ */
+fn lock_file(root_path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(store::store_path(root_path).join(LOCK_FILE))
+}
+
+// Takes the watcher lock, held until the returned file is dropped
+pub fn lock(root_path: &Path) -> Result<File> {
+    let file = lock_file(root_path)?;
+    let result =
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if result == -1 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Err(anyhow!(
+                "{} is already being watched",
+                root_path.display()
+            ));
+        }
+        return Err(error.into());
+    }
+    Ok(file)
+}
+
+fn is_locked(root_path: &Path) -> Result<bool> {
+    let file = lock_file(root_path)?;
+    let result =
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) };
+    if result == -1 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(true);
+        }
+        return Err(error.into());
+    }
+    Ok(false)
+}
+
+// The watcher of the working directory, if one is running
+pub fn running(
+    connection: &Connection,
+    root_path: &Path,
+) -> Result<Option<Watcher>> {
+    if !is_locked(root_path)? {
+        return Ok(None);
+    }
+    db::watcher::get(connection)
+}
+
+/* Every file in the working directory that isn't ignored, like
+ * file::get_all. With a watcher running only the paths it saw change are
+ * looked at, the rest comes from the snapshot taken the first time.
+ */
+pub fn disk_files(
+    connection: &Connection,
+    root_path: &Path,
+) -> Result<HashSet<PathBuf>> {
+    match running(connection, root_path)? {
+        Some(watcher) if watcher.has_snapshot() => {
+            update_snapshot(connection, root_path)
+        }
+        Some(watcher) => take_snapshot(connection, root_path, &watcher),
+        None => Ok(HashSet::from_iter(file::get_all(root_path)?)),
+    }
+}
+
+fn path_strings<'a>(paths: impl Iterator<Item = &'a PathBuf>) -> Vec<String> {
+    paths.map(|p| p.to_string_lossy().to_string()).collect()
+}
+
+fn take_snapshot(
+    connection: &Connection,
+    root_path: &Path,
+    watcher: &Watcher,
+) -> Result<HashSet<PathBuf>> {
+    // Anything changing from here on is applied to the snapshot next time
+    db::dirty_path::take_all(connection)?;
+    let files = file::get_all(root_path)?;
+
+    let transaction = connection.unchecked_transaction()?;
+    db::watched_file::delete_all(&transaction)?;
+    db::watched_file::insert_batch(&transaction, &path_strings(files.iter()))?;
+    // If the watcher lost track of changes meanwhile this snapshot is never
+    // used and the next status takes another.
+    db::watcher::set_snapshot_generation(
+        &transaction,
+        watcher.pid,
+        watcher.generation,
+    )?;
+    transaction.commit()?;
+
+    Ok(HashSet::from_iter(files))
+}
+
+fn update_snapshot(
+    connection: &Connection,
+    root_path: &Path,
+) -> Result<HashSet<PathBuf>> {
+    let dirty = db::dirty_path::take_all(connection)?;
+    let mut files: BTreeSet<PathBuf> = db::watched_file::get_all(connection)?
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+
+    let mut changed = BTreeSet::new();
+    for path in dirty.into_iter().map(PathBuf::from) {
+        // Whatever was at the path, and below it if it was a directory,
+        // may have gone.
+        let below: Vec<PathBuf> = files
+            .range(path.clone()..)
+            .take_while(|p| p.starts_with(&path))
+            .cloned()
+            .collect();
+        for p in below {
+            files.remove(&p);
+            changed.insert(p);
+        }
+
+        let full_path = root_path.join(&path);
+        if fs::symlink_metadata(&full_path).is_err() {
+            continue;
+        }
+        if ignores::check(root_path, &path)?.is_some_and(|r| !r.negated) {
+            continue;
+        }
+        let found = if full_path.is_dir() {
+            file::get_all_in(root_path, &path)?
+        } else {
+            vec![path]
+        };
+        for p in found {
+            files.insert(p.clone());
+            changed.insert(p);
+        }
+    }
+
+    let (inserted, deleted): (Vec<&PathBuf>, Vec<&PathBuf>) =
+        changed.iter().partition(|p| files.contains(*p));
+    let transaction = connection.unchecked_transaction()?;
+    db::watched_file::delete_batch(
+        &transaction,
+        &path_strings(deleted.into_iter()),
+    )?;
+    db::watched_file::insert_batch(
+        &transaction,
+        &path_strings(inserted.into_iter()),
+    )?;
+    transaction.commit()?;
+
+    Ok(HashSet::from_iter(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::types::hash_algorithm::HashAlgorithm;
+
+    fn strings(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn sorted(paths: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+        BTreeSet::from_iter(paths).into_iter().collect()
+    }
+
+    #[test]
+    fn test_update_snapshot_applies_only_dirty_paths() -> Result<()> {
+        let dir = TempDir::new()?;
+        let root_path = dir.path();
+        store::init(root_path)?;
+        let connection = Connection::open_in_memory()?;
+        db::init(&connection, HashAlgorithm::default())?;
+
+        for path in ["a.txt", "b.txt", "renders/c.exr", "renders/d.exr"] {
+            let full_path = root_path.join(path);
+            fs::create_dir_all(full_path.parent().unwrap())?;
+            fs::write(full_path, path)?;
+        }
+        db::watched_file::insert_batch(
+            &connection,
+            &strings(&["a.txt", "b.txt", "renders/c.exr", "renders/d.exr"]),
+        )?;
+
+        // Seen by the watcher: a new file, a removed one and a removed
+        // directory
+        fs::write(root_path.join("new.txt"), "new")?;
+        fs::remove_file(root_path.join("b.txt"))?;
+        fs::remove_dir_all(root_path.join("renders"))?;
+        db::dirty_path::insert_batch(
+            &connection,
+            &strings(&["new.txt", "b.txt", "renders"]),
+        )?;
+        // Not seen by the watcher, so not looked at
+        fs::write(root_path.join("unseen.txt"), "unseen")?;
+        fs::remove_file(root_path.join("a.txt"))?;
+
+        let files = update_snapshot(&connection, root_path)?;
+
+        let expected = vec![PathBuf::from("a.txt"), PathBuf::from("new.txt")];
+        assert_eq!(sorted(files), expected);
+        let watched = db::watched_file::get_all(&connection)?;
+        assert_eq!(sorted(watched.into_iter().map(PathBuf::from)), expected);
+        assert!(db::dirty_path::take_all(&connection)?.is_empty());
+        Ok(())
+    }
+}